@@ -17,7 +17,7 @@ qemu-system-x86_64 \
 	-m 1G \
 	-drive if=pflash,format=raw,readonly=on,file=$DEVENV_DIR/OVMF_CODE.fd \
 	-drive if=pflash,format=raw,file=$DEVENV_DIR/OVMF_VARS.fd \
-	-drive if=ide,index=0,media=disk,format=raw,file=$DISK_IMG \
+	-drive if=virtio,index=0,media=disk,format=raw,file=$DISK_IMG \
 	-device nec-usb-xhci,id=xhci \
 	-device usb-mouse -device usb-kbd \
 	-monitor stdio \
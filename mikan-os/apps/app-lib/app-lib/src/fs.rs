@@ -15,6 +15,97 @@ use crate::{
 type Result<T> = core::result::Result<T, ErrNo>;
 
 pub fn open(path: impl Display, flags: FileFlags) -> Result<File> {
+    let res = with_cstr_path(path, |path| unsafe {
+        syscall::__open_file(path as _, flags.0 as _)
+    })?;
+
+    if res.error != 0 {
+        Err(res.error.into())
+    } else {
+        Ok(File::new(res.value as _))
+    }
+}
+
+/// `path` が指すファイルのメタデータを返す。
+pub fn metadata(path: impl Display) -> Result<Metadata> {
+    let mut stat = Stat::default();
+    let res = with_cstr_path(path, |path| unsafe {
+        syscall::__stat(path as _, &mut stat as *mut _ as _)
+    })?;
+
+    if res.error != 0 {
+        Err(res.error.into())
+    } else {
+        Ok(Metadata(stat))
+    }
+}
+
+/// `path` が指すディレクトリ内のエントリを順に返すイテレータを作る。`.` と `..` は返さない。
+pub fn read_dir(path: impl Display) -> Result<ReadDir> {
+    let res = with_cstr_path(path, |path| unsafe { syscall::__open_dir(path as _) })?;
+
+    if res.error != 0 {
+        Err(res.error.into())
+    } else {
+        Ok(ReadDir(File::new(res.value as _)))
+    }
+}
+
+/// `path` が指すファイルを削除する。ディレクトリの場合は空のときのみ削除できる。
+pub fn remove_file(path: impl Display) -> Result<()> {
+    let res = with_cstr_path(path, |path| unsafe { syscall::__unlink(path as _) })?;
+
+    if res.error != 0 {
+        Err(res.error.into())
+    } else {
+        Ok(())
+    }
+}
+
+/// `path` に新しいディレクトリを作る。
+pub fn create_dir(path: impl Display) -> Result<()> {
+    let res = with_cstr_path(path, |path| unsafe { syscall::__mkdir(path as _) })?;
+
+    if res.error != 0 {
+        Err(res.error.into())
+    } else {
+        Ok(())
+    }
+}
+
+/// `path` が指す空のディレクトリを削除する。
+pub fn remove_dir(path: impl Display) -> Result<()> {
+    let res = with_cstr_path(path, |path| unsafe { syscall::__rmdir(path as _) })?;
+
+    if res.error != 0 {
+        Err(res.error.into())
+    } else {
+        Ok(())
+    }
+}
+
+/// `from` が指すファイルもしくはディレクトリを `to` に移動する。
+/// `to` が既に存在する場合は失敗する。
+pub fn rename(from: impl Display, to: impl Display) -> Result<()> {
+    let res = with_cstr_path(from, |from| {
+        match with_cstr_path(to, |to| unsafe { syscall::__rename(from as _, to as _) }) {
+            Ok(res) => res,
+            Err(e) => SysResult {
+                value: 0,
+                error: e.into(),
+            },
+        }
+    })?;
+
+    if res.error != 0 {
+        Err(res.error.into())
+    } else {
+        Ok(())
+    }
+}
+
+/// `path` をヌル終端された文字列に変換し、その先頭ポインタを `f` に渡して呼び出す。
+fn with_cstr_path(path: impl Display, f: impl FnOnce(*const u8) -> SysResult) -> Result<SysResult> {
     #[cfg(not(feature = "alloc"))]
     let res = {
         use crate::buf::CStrBuf;
@@ -23,7 +114,7 @@ pub fn open(path: impl Display, flags: FileFlags) -> Result<File> {
         let mut buf = [0; 1024];
         let mut buf = CStrBuf::new_unchecked(&mut buf);
         write!(buf, "{}", path).unwrap();
-        unsafe { syscall::__open_file(buf.to_cstr().as_ptr() as _, flags.0 as _) }
+        f(buf.to_cstr().as_ptr() as _)
     };
 
     #[cfg(feature = "alloc")]
@@ -36,14 +127,10 @@ pub fn open(path: impl Display, flags: FileFlags) -> Result<File> {
             Ok(s) => s,
             Err(_) => return Err(ErrNo::EINVAL),
         };
-        unsafe { syscall::__open_file(path.as_ptr() as _, flags.0 as _) }
+        f(path.as_ptr() as _)
     };
 
-    if res.error != 0 {
-        Err(res.error.into())
-    } else {
-        Ok(File(res.value as _))
-    }
+    Ok(res)
 }
 
 #[cfg(feature = "alloc")]
@@ -81,14 +168,61 @@ pub trait Write {
     fn flush(&mut self) -> Result<()>;
 }
 
-pub struct File(pub(crate) i32);
+/// シークの基準位置とそこからのオフセット。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    /// ファイル先頭からのオフセット
+    Start(u64),
+    /// ファイル末尾からのオフセット
+    End(i64),
+    /// 現在の読み書きする位置からのオフセット
+    Current(i64),
+}
+
+pub trait Seek {
+    /// 読み書き位置を移動し、移動後のファイル先頭からのオフセットを返す。
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64>;
+
+    /// ファイル先頭に戻る。
+    fn rewind(&mut self) -> Result<()> {
+        self.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+
+    /// 現在の読み書きする位置を返す。
+    fn stream_position(&mut self) -> Result<u64> {
+        self.seek(SeekFrom::Current(0))
+    }
+}
+
+pub struct File {
+    fd: i32,
+    /// drop 時にファイルディスクリプタを閉じるかどうか。
+    /// 標準入出力は閉じてはいけないので `false` になる。
+    close_on_drop: bool,
+}
 
 impl File {
+    pub(crate) const fn new(fd: i32) -> Self {
+        Self {
+            fd,
+            close_on_drop: true,
+        }
+    }
+
+    /// drop しても閉じられない `File` を作る。
+    pub(crate) const fn new_unowned(fd: i32) -> Self {
+        Self {
+            fd,
+            close_on_drop: false,
+        }
+    }
+
     /// 現在開いているファイルをメモリにマップし、そのメモリスライスへの参照を返す。
     pub fn memmap(&mut self) -> Result<&mut [u8]> {
         let mut file_size = 0;
         unsafe {
-            let res = syscall::__map_file(self.0 as _, (&mut file_size) as *mut _ as _);
+            let res = syscall::__map_file(self.fd as _, (&mut file_size) as *mut _ as _);
             let ptr = match res {
                 SysResult { value, error: 0 } => value as *mut u8,
                 SysResult { error, .. } => return Err(error.into()),
@@ -98,9 +232,30 @@ impl File {
     }
 }
 
+impl File {
+    /// 開いているファイルのメタデータを返す。
+    pub fn metadata(&self) -> Result<Metadata> {
+        let mut stat = Stat::default();
+        let res = unsafe { syscall::__fstat(self.fd as _, &mut stat as *mut _ as _) };
+        if res.error != 0 {
+            Err(res.error.into())
+        } else {
+            Ok(Metadata(stat))
+        }
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        if self.close_on_drop {
+            unsafe { syscall::__close_file(self.fd as _) };
+        }
+    }
+}
+
 impl Read for File {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let res = unsafe { syscall::__read_file(self.0 as _, buf.as_ptr() as _, buf.len() as _) };
+        let res = unsafe { syscall::__read_file(self.fd as _, buf.as_ptr() as _, buf.len() as _) };
         if res.error != 0 {
             Err(res.error.into())
         } else {
@@ -111,11 +266,11 @@ impl Read for File {
 
 impl Write for File {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let res = unsafe { syscall::__put_string(self.0 as _, buf.as_ptr() as _, buf.len() as _) };
+        let res = unsafe { syscall::__write_file(self.fd as _, buf.as_ptr() as _, buf.len() as _) };
         if res.error != 0 {
             Err(res.error.into())
         } else {
-            Ok(buf.len())
+            Ok(res.value as _)
         }
     }
 
@@ -124,6 +279,167 @@ impl Write for File {
     }
 }
 
+impl Seek for File {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let (offset, whence) = match pos {
+            SeekFrom::Start(off) => (off as i64, 0),
+            SeekFrom::Current(off) => (off, 1),
+            SeekFrom::End(off) => (off, 2),
+        };
+        let res = unsafe { syscall::__seek_file(self.fd as _, offset as _, whence) };
+        if res.error != 0 {
+            Err(res.error.into())
+        } else {
+            Ok(res.value)
+        }
+    }
+}
+
+/// カーネルが `stat`、`fstat` システムコールで返すメタデータ。
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Stat {
+    size: u64,
+    first_cluster: u32,
+    attr: u8,
+    crt_time_tenth: u8,
+    crt_time: u16,
+    crt_date: u16,
+    wrt_time: u16,
+    wrt_date: u16,
+    lst_acc_date: u16,
+}
+
+/// ファイルのメタデータ。
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata(Stat);
+
+impl Metadata {
+    const ATTR_READ_ONLY: u8 = 0x01;
+    const ATTR_DIRECTORY: u8 = 0x10;
+
+    /// ファイルサイズを返す。
+    pub fn len(&self) -> u64 {
+        self.0.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.size == 0
+    }
+
+    /// FAT のディレクトリエントリの属性値を返す。
+    pub fn attributes(&self) -> u8 {
+        self.0.attr
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.0.attr & Self::ATTR_DIRECTORY != 0
+    }
+
+    pub fn is_file(&self) -> bool {
+        !self.is_dir()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.0.attr & Self::ATTR_READ_ONLY != 0
+    }
+
+    /// ファイルの先頭クラスタ番号を返す。
+    pub fn first_cluster(&self) -> u32 {
+        self.0.first_cluster
+    }
+
+    /// 作成日時を返す。
+    pub fn created(&self) -> DateTime {
+        let mut time = DateTime::from_fat(self.0.crt_date, self.0.crt_time);
+        // 10ms 単位で 0 ～ 199 なので、1秒以上の分を秒に足す
+        time.second += self.0.crt_time_tenth / 100;
+        time
+    }
+
+    /// 最終更新日時を返す。
+    pub fn modified(&self) -> DateTime {
+        DateTime::from_fat(self.0.wrt_date, self.0.wrt_time)
+    }
+
+    /// 最終アクセス日を返す。時刻は記録されていないので 0 時 0 分 0 秒となる。
+    pub fn accessed(&self) -> DateTime {
+        DateTime::from_fat(self.0.lst_acc_date, 0)
+    }
+}
+
+/// カーネルが `read_dir` システムコールで返すディレクトリエントリ。
+#[repr(C)]
+struct DirEnt {
+    stat: Stat,
+    name: [u8; 256],
+}
+
+/// ディレクトリ内のエントリを順に返すイテレータ。
+pub struct ReadDir(File);
+
+impl Iterator for ReadDir {
+    type Item = Result<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut ent = DirEnt {
+            stat: Stat::default(),
+            name: [0; 256],
+        };
+        let res = unsafe { syscall::__read_dir(self.0.fd as _, &mut ent as *mut _ as _) };
+        match res {
+            SysResult { error: 0, value: 0 } => None,
+            SysResult { error: 0, .. } => Some(Ok(DirEntry(ent))),
+            SysResult { error, .. } => Some(Err(error.into())),
+        }
+    }
+}
+
+/// ディレクトリ内のエントリ。
+pub struct DirEntry(DirEnt);
+
+impl DirEntry {
+    /// エントリの名前を返す。
+    pub fn file_name(&self) -> &str {
+        let len = self
+            .0
+            .name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(self.0.name.len());
+        core::str::from_utf8(&self.0.name[..len]).unwrap_or("")
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        Metadata(self.0.stat)
+    }
+}
+
+/// ファイルの日時。
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// FAT の日付、時刻の形式から変換する。
+    fn from_fat(date: u16, time: u16) -> Self {
+        Self {
+            year: 1980 + (date >> 9),
+            month: (date >> 5 & 0xf) as _,
+            day: (date & 0x1f) as _,
+            hour: (time >> 11) as _,
+            minute: (time >> 5 & 0x3f) as _,
+            second: ((time & 0x1f) * 2) as _,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct FileFlags(i32);
 
@@ -137,6 +453,9 @@ impl FileFlags {
     pub const WRONLY: Self = Self(1);
     pub const RDWR: Self = Self(2);
     pub const CREAT: Self = Self(0o100);
+    pub const EXCL: Self = Self(0o200);
+    pub const TRUNC: Self = Self(0o1000);
+    pub const APPEND: Self = Self(0o2000);
 }
 
 impl From<FileFlags> for i32 {
@@ -1,13 +1,13 @@
 use crate::fs::File;
 
 pub fn stdin() -> File {
-    File(0)
+    File::new_unowned(0)
 }
 
 pub fn stdout() -> File {
-    File(1)
+    File::new_unowned(1)
 }
 
 pub fn stderr() -> File {
-    File(2)
+    File::new_unowned(2)
 }
@@ -76,6 +76,17 @@ syscall!(open_file, 0x8000_000c, path, flags);
 syscall!(read_file, 0x8000_000d, fd, buf, count);
 syscall!(demand_pages, 0x8000_000e, nam_pages);
 syscall!(map_file, 0x8000_000f, fd, pfile_size);
+syscall!(close_file, 0x8000_0010, fd);
+syscall!(write_file, 0x8000_0011, fd, buf, count);
+syscall!(seek_file, 0x8000_0012, fd, offset, whence);
+syscall!(stat, 0x8000_0013, path, buf);
+syscall!(fstat, 0x8000_0014, fd, buf);
+syscall!(open_dir, 0x8000_0015, path);
+syscall!(read_dir, 0x8000_0016, fd, buf);
+syscall!(unlink, 0x8000_0017, path);
+syscall!(mkdir, 0x8000_0018, path);
+syscall!(rmdir, 0x8000_0019, path);
+syscall!(rename, 0x8000_001a, old_path, new_path);
 
 #[repr(C)]
 #[derive(Debug, Default, Clone, Copy)]
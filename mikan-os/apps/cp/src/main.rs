@@ -25,7 +25,10 @@ fn main(args: app_lib::args::Args) -> i32 {
         }
     };
 
-    let mut dest_file = match fs::open(dest, FileFlags::WRONLY | FileFlags::CREAT) {
+    let mut dest_file = match fs::open(
+        dest,
+        FileFlags::WRONLY | FileFlags::CREAT | FileFlags::TRUNC,
+    ) {
         Ok(f) => f,
         Err(_) => {
             println!("failed to open for write: {}", dest);
@@ -0,0 +1,232 @@
+use core::ptr;
+
+use crate::{dir::DIR_ENTRY_SIZE, Error, Result};
+
+/// クラスタ数から決まる FAT の種類。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// データ領域のクラスタ数 `cluster_count` から FAT の種類を決める。
+    pub fn from_cluster_count(cluster_count: u64) -> Self {
+        if cluster_count < 4085 {
+            Self::Fat12
+        } else if cluster_count < 65525 {
+            Self::Fat16
+        } else {
+            Self::Fat32
+        }
+    }
+
+    /// FAT エントリ 1 つのビット数を返す。
+    pub fn entry_bits(self) -> usize {
+        match self {
+            Self::Fat12 => 12,
+            Self::Fat16 => 16,
+            Self::Fat32 => 32,
+        }
+    }
+}
+
+/// ボリュームの先頭セクタにある BIOS Parameter Block。
+///
+/// `fat_sz32` 以降のフィールドは FAT32 のレイアウトで、FAT12/16 のボリュームでは意味を持たない。
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct BPB {
+    jmp_bot: [u8; 3],
+    oemname: [u8; 8],
+    byts_per_sec: u16,
+    sec_per_clus: u8,
+    rsvd_sec_cnt: u16,
+    num_fats: u8,
+    root_ent_cnd: u16,
+    tot_sec16: u16,
+    media: u8,
+    fat_sz16: u16,
+    sec_per_trk: u16,
+    num_heads: u16,
+    hidd_sec: u32,
+    tot_sec32: u32,
+    fat_sz32: u32,
+    ext_flags: u16,
+    fsver: u16,
+    root_clus: u32,
+    fsinfo: u16,
+    bk_boot_sec: u16,
+    reserved: [u8; 12],
+    drv_num: u8,
+    reserved1: u8,
+    boot_sig: u8,
+    vol_id: u32,
+    vol_lab: [u8; 11],
+    fil_sys_type: u64,
+}
+
+impl BPB {
+    /// 先頭セクタの内容 `sector` から読み込み、FAT12/16/32 の BPB として正しいかを確かめる。
+    pub fn parse(sector: &[u8]) -> Result<Self> {
+        if sector.len() < 512 {
+            return Err(Error::InvalidFormat);
+        }
+        // Safety: 長さは確認済みで、BPB はどのバイト列でも値として正しい
+        let bpb = unsafe { ptr::read_unaligned(sector.as_ptr() as *const Self) };
+
+        let valid = matches!(bpb.byts_per_sec(), 512 | 1024 | 2048 | 4096)
+            && bpb.sec_per_clus().is_power_of_two()
+            && bpb.rsvd_sec_cnt() > 0
+            && bpb.num_fats() > 0
+            && bpb.fat_size() > 0
+            && bpb.data_start() < bpb.total_sectors();
+        if !valid {
+            return Err(Error::InvalidFormat);
+        }
+
+        let cluster_count = bpb.cluster_count();
+        let valid = match bpb.fat_type() {
+            FatType::Fat32 => {
+                bpb.fat_sz16() == 0
+                    && bpb.root_ent_cnd() == 0
+                    && (2..cluster_count + 2).contains(&(bpb.root_clus() as u64))
+            }
+            FatType::Fat12 | FatType::Fat16 => bpb.root_ent_cnd() > 0,
+        };
+        // FAT に全てのクラスタのエントリが収まっていなければならない
+        let fat_bits = bpb.fat_size() * bpb.byts_per_sec() as u64 * 8;
+        let required_bits = (cluster_count + 2) * bpb.fat_type().entry_bits() as u64;
+        if !valid || fat_bits < required_bits {
+            return Err(Error::InvalidFormat);
+        }
+        Ok(bpb)
+    }
+
+    pub fn jmp_bot(&self) -> [u8; 3] {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.jmp_bot)) }
+    }
+    pub fn oemname(&self) -> [u8; 8] {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.oemname)) }
+    }
+    pub fn byts_per_sec(&self) -> u16 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.byts_per_sec)) }
+    }
+    pub fn sec_per_clus(&self) -> u8 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.sec_per_clus)) }
+    }
+    pub fn rsvd_sec_cnt(&self) -> u16 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.rsvd_sec_cnt)) }
+    }
+    pub fn num_fats(&self) -> u8 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.num_fats)) }
+    }
+    pub fn root_ent_cnd(&self) -> u16 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.root_ent_cnd)) }
+    }
+    pub fn tot_sec16(&self) -> u16 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.tot_sec16)) }
+    }
+    pub fn media(&self) -> u8 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.media)) }
+    }
+    pub fn fat_sz16(&self) -> u16 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.fat_sz16)) }
+    }
+    pub fn sec_per_trk(&self) -> u16 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.sec_per_trk)) }
+    }
+    pub fn num_heads(&self) -> u16 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.num_heads)) }
+    }
+    pub fn hidd_sec(&self) -> u32 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.hidd_sec)) }
+    }
+    pub fn tot_sec32(&self) -> u32 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.tot_sec32)) }
+    }
+    pub fn fat_sz32(&self) -> u32 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.fat_sz32)) }
+    }
+    pub fn ext_flags(&self) -> u16 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.ext_flags)) }
+    }
+    pub fn fsver(&self) -> u16 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.fsver)) }
+    }
+    pub fn root_clus(&self) -> u32 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.root_clus)) }
+    }
+    pub fn fsinfo(&self) -> u16 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.fsinfo)) }
+    }
+    pub fn bk_boot_sec(&self) -> u16 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.bk_boot_sec)) }
+    }
+    pub fn reserved(&self) -> [u8; 12] {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.reserved)) }
+    }
+    pub fn drv_num(&self) -> u8 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.drv_num)) }
+    }
+    pub fn reserved1(&self) -> u8 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.reserved1)) }
+    }
+    pub fn boot_sig(&self) -> u8 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.boot_sig)) }
+    }
+    pub fn vol_id(&self) -> u32 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.vol_id)) }
+    }
+    pub fn vol_lab(&self) -> [u8; 11] {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.vol_lab)) }
+    }
+    pub fn fil_sys_type(&self) -> u64 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.fil_sys_type)) }
+    }
+
+    /// 1 つの FAT のセクタ数を返す。
+    pub fn fat_size(&self) -> u64 {
+        if self.fat_sz16() != 0 {
+            self.fat_sz16() as u64
+        } else {
+            self.fat_sz32() as u64
+        }
+    }
+
+    /// FAT12/16 の固定サイズのルートディレクトリ領域のセクタ数を返す。FAT32 では 0。
+    pub fn root_dir_sectors(&self) -> u64 {
+        let bytes = self.root_ent_cnd() as u64 * DIR_ENTRY_SIZE as u64;
+        bytes.div_ceil(self.byts_per_sec() as u64)
+    }
+
+    /// FAT12/16 のルートディレクトリ領域の先頭セクタを返す。
+    pub fn root_dir_start(&self) -> u64 {
+        self.rsvd_sec_cnt() as u64 + self.num_fats() as u64 * self.fat_size()
+    }
+
+    /// データ領域の先頭セクタを返す。
+    pub fn data_start(&self) -> u64 {
+        self.root_dir_start() + self.root_dir_sectors()
+    }
+
+    /// データ領域にあるクラスタの数を返す。クラスタ番号は 2 から始まる。
+    pub fn cluster_count(&self) -> u64 {
+        (self.total_sectors() - self.data_start()) / self.sec_per_clus() as u64
+    }
+
+    /// クラスタ数から FAT の種類を返す。
+    pub fn fat_type(&self) -> FatType {
+        FatType::from_cluster_count(self.cluster_count())
+    }
+
+    /// ボリューム全体のセクタ数を返す。
+    pub fn total_sectors(&self) -> u64 {
+        if self.tot_sec16() != 0 {
+            self.tot_sec16() as u64
+        } else {
+            self.tot_sec32() as u64
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//! ボリュームの整合性の検査と修復。
+//!
+//! 全てのディレクトリをたどり、各ファイルのクラスタチェーンを FAT と照らし合わせる。
+//! どのファイルからも使われていないクラスタは、最後に FAT 全体を見て探す。
+
+use core::fmt;
+
+use alloc::{
+    format,
+    string::{String, ToString as _},
+    vec,
+    vec::Vec,
+};
+
+use crate::{dir, DirectoryEntry, FatType, Result, Volume, BAD_CLUSTER, END_OF_CLUSTER_CHAIN};
+
+/// [Volume::check] で見つかった問題。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// クラスタチェーンがデータ領域の外や未使用のクラスタを指している
+    OutOfRange { path: String, cluster: u64 },
+    /// クラスタが別のファイル、もしくは同じチェーンの前の部分でも使われている
+    CrossLinked { path: String, cluster: u64 },
+    /// ファイルサイズとクラスタチェーンの長さが合わない
+    SizeMismatch {
+        path: String,
+        size: u32,
+        clusters: usize,
+    },
+    /// 短い名前が 8.3 形式として正しくない
+    BadName { path: String, name: [u8; 11] },
+    /// どのファイルからも使われていないクラスタチェーン
+    LostChain { first_cluster: u64, clusters: usize },
+    /// FSInfo の空きクラスタ数が実際の数と合わない
+    FreeCount { recorded: u64, actual: u64 },
+}
+
+impl Problem {
+    /// 修復できる問題かどうかを返す。名前は重複しない名前を決められないので修復しない。
+    pub fn repairable(&self) -> bool {
+        !matches!(self, Self::BadName { .. })
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfRange { path, cluster } => {
+                write!(
+                    f,
+                    "{}: cluster chain points to invalid cluster {}",
+                    path, cluster
+                )
+            }
+            Self::CrossLinked { path, cluster } => {
+                write!(f, "{}: cluster {} is cross-linked", path, cluster)
+            }
+            Self::SizeMismatch {
+                path,
+                size,
+                clusters,
+            } => write!(
+                f,
+                "{}: size {} does not match the chain of {} clusters",
+                path, size, clusters
+            ),
+            Self::BadName { path, name } => write!(
+                f,
+                "{}: invalid short name \"{}\"",
+                path,
+                String::from_utf8_lossy(name)
+            ),
+            Self::LostChain {
+                first_cluster,
+                clusters,
+            } => write!(
+                f,
+                "lost cluster chain at {} ({} clusters)",
+                first_cluster, clusters
+            ),
+            Self::FreeCount { recorded, actual } => write!(
+                f,
+                "free cluster count is {} but {} clusters are free",
+                recorded, actual
+            ),
+        }
+    }
+}
+
+impl Volume {
+    /// ボリューム全体を検査し、見つかった問題を返す。
+    ///
+    /// `repair` が true の場合は見つけた問題を修復する。
+    /// 壊れたクラスタチェーンは問題のあるクラスタの手前で切り、ファイルサイズはチェーンの長さに合わせる。
+    /// チェーンが長すぎる場合は余分なクラスタを解放し、使われていないチェーンも解放する。
+    /// FSInfo の空きクラスタ数が合わない場合は数え直す。
+    /// 修復した内容は [Volume::sync] で書き戻す。
+    pub fn check(&mut self, repair: bool) -> Result<Vec<Problem>> {
+        let mut checker = Checker {
+            used: vec![false; self.cluster_count() as usize + 2],
+            problems: Vec::new(),
+            repair,
+        };
+
+        // FAT12/16 のルートディレクトリは固定の領域にあり、クラスタチェーンを持たない
+        let root_chain = match self.fat_type() {
+            FatType::Fat32 => checker.check_chain(self, self.root_cluster(), "/")?,
+            FatType::Fat12 | FatType::Fat16 => vec![self.root_cluster()],
+        };
+        let mut dirs = vec![(root_chain, String::new())];
+        while let Some((chain, dir_path)) = dirs.pop() {
+            for entry in self.read_dir_clusters(&chain)? {
+                if entry.name == "." || entry.name == ".." || entry.dir_entry.is_volume_label() {
+                    continue;
+                }
+                let path = format!("{}/{}", dir_path, entry.name);
+
+                if !dir::is_valid_short_name(&entry.dir_entry.name) {
+                    checker.problems.push(Problem::BadName {
+                        path: path.clone(),
+                        name: entry.dir_entry.name,
+                    });
+                }
+
+                let first_cluster = entry.first_cluster();
+                let mut chain = if first_cluster == 0 {
+                    Vec::new()
+                } else {
+                    checker.check_chain(self, first_cluster, &path)?
+                };
+                let mut dir_entry = entry.dir_entry;
+                if first_cluster != 0 && chain.is_empty() {
+                    dir_entry.set_first_cluster(0);
+                }
+
+                if entry.is_dir() {
+                    if !chain.is_empty() {
+                        dirs.push((chain, path));
+                    }
+                } else {
+                    checker.check_size(self, &mut dir_entry, &mut chain, path)?;
+                }
+
+                if repair && dir_entry != entry.dir_entry {
+                    self.set_dir_entry(entry.location, &dir_entry)?;
+                }
+            }
+        }
+
+        checker.check_lost_chains(self)?;
+        checker.check_free_count(self)?;
+        Ok(checker.problems)
+    }
+}
+
+struct Checker {
+    /// ファイルやディレクトリで使われているクラスタ
+    used: Vec<bool>,
+    problems: Vec<Problem>,
+    repair: bool,
+}
+
+impl Checker {
+    /// `first_cluster` から始まる `path` のクラスタチェーンをたどり、問題のないクラスタを返す。
+    /// 修復する場合は、問題のあるクラスタの手前でチェーンを終わらせる。
+    fn check_chain(
+        &mut self,
+        volume: &mut Volume,
+        first_cluster: u64,
+        path: &str,
+    ) -> Result<Vec<u64>> {
+        let mut chain = Vec::new();
+        let mut cluster = first_cluster;
+        loop {
+            let problem = if !(2..self.used.len() as u64).contains(&cluster) {
+                Problem::OutOfRange {
+                    path: path.to_string(),
+                    cluster,
+                }
+            } else if self.used[cluster as usize] {
+                Problem::CrossLinked {
+                    path: path.to_string(),
+                    cluster,
+                }
+            } else {
+                self.used[cluster as usize] = true;
+                chain.push(cluster);
+
+                let next = volume.fat_entry(cluster)?;
+                if next >= 0x0fff_fff8 {
+                    break;
+                }
+                cluster = next as u64;
+                continue;
+            };
+
+            self.problems.push(problem);
+            if self.repair {
+                if let Some(&last) = chain.last() {
+                    volume.set_fat_entry(last, END_OF_CLUSTER_CHAIN as _)?;
+                }
+            }
+            break;
+        }
+        Ok(chain)
+    }
+
+    /// ファイルのサイズが `chain` の長さと合っているかを確かめる。
+    /// 修復する場合は、余分なクラスタを解放するか、サイズをチェーンの長さまで切り詰める。
+    fn check_size(
+        &mut self,
+        volume: &mut Volume,
+        dir_entry: &mut DirectoryEntry,
+        chain: &mut Vec<u64>,
+        path: String,
+    ) -> Result<()> {
+        let bytes_per_cluster = volume.bytes_per_cluster();
+        let expected = (dir_entry.file_size as usize).div_ceil(bytes_per_cluster);
+        if chain.len() == expected {
+            return Ok(());
+        }
+
+        self.problems.push(Problem::SizeMismatch {
+            path,
+            size: dir_entry.file_size,
+            clusters: chain.len(),
+        });
+        if !self.repair {
+            return Ok(());
+        }
+
+        if chain.len() > expected {
+            for cluster in chain.split_off(expected) {
+                volume.set_fat_entry(cluster, 0)?;
+                self.used[cluster as usize] = false;
+            }
+            match chain.last() {
+                Some(&last) => volume.set_fat_entry(last, END_OF_CLUSTER_CHAIN as _)?,
+                None => dir_entry.set_first_cluster(0),
+            }
+        } else {
+            dir_entry.file_size = (chain.len() * bytes_per_cluster) as _;
+        }
+        Ok(())
+    }
+
+    /// 記録している空きクラスタ数が実際の数と合っているかを確かめる。
+    /// 修復する場合は、実際の数を記録する。
+    fn check_free_count(&mut self, volume: &mut Volume) -> Result<()> {
+        let mut actual = 0;
+        for cluster in 2..self.used.len() as u64 {
+            if volume.fat_entry(cluster)? == 0 {
+                actual += 1;
+            }
+        }
+
+        match volume.recorded_free_clusters() {
+            Some(recorded) if recorded != actual => {
+                self.problems.push(Problem::FreeCount { recorded, actual });
+                if self.repair {
+                    volume.set_free_clusters(actual);
+                }
+            }
+            Some(_) => {}
+            // まだ数えていなければ、問題ではないので数えた値を使う
+            None => volume.set_free_clusters(actual),
+        }
+        Ok(())
+    }
+
+    /// 使用中になっているが、どのファイルからも使われていないクラスタをチェーンごとに探す。
+    /// 修復する場合は、それらを未使用にする。
+    fn check_lost_chains(&mut self, volume: &mut Volume) -> Result<()> {
+        let num_clusters = self.used.len();
+        let mut lost = vec![false; num_clusters];
+        for (cluster, is_lost) in lost.iter_mut().enumerate().skip(2) {
+            let value = volume.fat_entry(cluster as _)?;
+            *is_lost = !self.used[cluster] && value != 0 && value != BAD_CLUSTER;
+        }
+
+        // 他の失われたクラスタから指されていないものがチェーンの先頭
+        let mut pointed = vec![false; num_clusters];
+        for cluster in 2..num_clusters {
+            if lost[cluster] {
+                let next = volume.fat_entry(cluster as _)? as usize;
+                if next < num_clusters && lost[next] {
+                    pointed[next] = true;
+                }
+            }
+        }
+        // 先頭がないものはループしているので、適当なところから始める
+        let heads: Vec<usize> = (2..num_clusters)
+            .filter(|&c| lost[c] && !pointed[c])
+            .chain((2..num_clusters).filter(|&c| lost[c] && pointed[c]))
+            .collect();
+
+        for first_cluster in heads {
+            if !lost[first_cluster] {
+                continue;
+            }
+
+            let mut clusters = 0;
+            let mut cluster = first_cluster;
+            while cluster < num_clusters && lost[cluster] {
+                lost[cluster] = false;
+                clusters += 1;
+                let next = volume.fat_entry(cluster as _)? as usize;
+                if self.repair {
+                    volume.set_fat_entry(cluster as _, 0)?;
+                }
+                cluster = next;
+            }
+            self.problems.push(Problem::LostChain {
+                first_cluster: first_cluster as _,
+                clusters,
+            });
+        }
+        Ok(())
+    }
+}
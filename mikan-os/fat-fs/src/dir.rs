@@ -0,0 +1,287 @@
+use core::{cmp, mem, ptr};
+
+use alloc::{
+    format,
+    string::{String, ToString as _},
+    vec::Vec,
+};
+
+/// LFN で表せる最大文字数（UCS-2 単位）。
+pub(crate) const LFN_MAX_CHARS: usize = 255;
+/// LFN エントリ1つあたりの文字数（UCS-2 単位）。
+pub(crate) const LFN_CHARS_PER_ENTRY: usize = 13;
+/// LFN エントリの順番のうち、最後のエントリであることを示すビット。
+pub(crate) const LFN_LAST_ENTRY: u8 = 0x40;
+
+/// ディレクトリエントリ 1 つのバイト数
+pub(crate) const DIR_ENTRY_SIZE: usize = mem::size_of::<DirectoryEntry>();
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DirectoryEntry {
+    pub name: [u8; 11],
+    pub attr: u8,
+    pub nt_res: u8,
+    pub crt_time_tenth: u8,
+    pub crt_time: u16,
+    pub crt_date: u16,
+    pub lst_acc_date: u16,
+    pub fst_clus_hl: u16,
+    pub wrt_time: u16,
+    pub wrt_date: u16,
+    pub fst_clus_lo: u16,
+    pub file_size: u32,
+}
+
+impl DirectoryEntry {
+    /// ボリューム上の 32 バイトから読み込む。
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        assert!(bytes.len() >= DIR_ENTRY_SIZE);
+        // Safety: 長さは確認済みで、どのバイト列でも値として正しい
+        unsafe { ptr::read_unaligned(bytes.as_ptr() as *const Self) }
+    }
+
+    /// ボリューム上の形式で `bytes` に書き込む。
+    pub fn write_to(&self, bytes: &mut [u8]) {
+        assert!(bytes.len() >= DIR_ENTRY_SIZE);
+        // Safety: 長さは確認済み
+        unsafe { ptr::write_unaligned(bytes.as_mut_ptr() as *mut Self, *self) }
+    }
+
+    pub fn first_cluster(&self) -> u32 {
+        (self.fst_clus_hl as u32) << 16 | self.fst_clus_lo as u32
+    }
+
+    pub fn set_first_cluster(&mut self, clus: u32) {
+        self.fst_clus_lo = clus as _;
+        self.fst_clus_hl = (clus >> 16) as _;
+    }
+
+    /// ディレクトリかどうかを返す。隠し属性などが一緒に立っていてもディレクトリとみなす。
+    pub fn is_dir(&self) -> bool {
+        self.attr != Attribute::LongName as u8 && self.attr & Attribute::Directory as u8 != 0
+    }
+
+    /// ボリュームラベルかどうかを返す。
+    pub fn is_volume_label(&self) -> bool {
+        self.attr != Attribute::LongName as u8 && self.attr & Attribute::VolumeID as u8 != 0
+    }
+
+    pub(crate) fn name_is_equal(&self, name: &str) -> bool {
+        // `name` を名前と拡張子に分割
+        let (base, ext) = match name.rsplit_once('.') {
+            // `.` と `..` はピリオドが名前の一部
+            _ if name == "." || name == ".." => (name, ""),
+            Some(res) => (res.0, res.1),
+            None => (name, ""),
+        };
+        if base.len() > 8 || ext.len() > 3 {
+            return false;
+        }
+
+        let base = base
+            .as_bytes()
+            .iter()
+            .map(|c| c.to_ascii_uppercase())
+            .chain((base.len()..8).map(|_| 0x20));
+        let ext = ext
+            .as_bytes()
+            .iter()
+            .map(|c| c.to_ascii_uppercase())
+            .chain((ext.len()..3).map(|_| 0x20));
+        let name = base.chain(ext);
+
+        self.name.into_iter().eq(name)
+    }
+
+    /// 短い名前のチェックサムを計算する。
+    pub(crate) fn checksum(&self) -> u8 {
+        checksum(&self.name)
+    }
+}
+
+/// 短い名前 `name` のチェックサムを計算する。
+pub(crate) fn checksum(name: &[u8; 11]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// LFN を格納するディレクトリエントリ。
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub(crate) struct LongNameEntry {
+    /// このエントリの順番。最後のエントリは 0x40 ビットが立つ。
+    pub ord: u8,
+    pub name1: [u16; 5],
+    /// 常に [Attribute::LongName]。
+    pub attr: u8,
+    pub ty: u8,
+    /// 対応する短い名前のチェックサム。
+    pub checksum: u8,
+    pub name2: [u16; 6],
+    pub fst_clus_lo: u16,
+    pub name3: [u16; 2],
+}
+
+impl LongNameEntry {
+    /// ボリューム上の 32 バイトから読み込む。
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        assert!(bytes.len() >= DIR_ENTRY_SIZE);
+        // Safety: DirectoryEntry::from_bytes と同じ
+        unsafe { ptr::read_unaligned(bytes.as_ptr() as *const Self) }
+    }
+
+    /// ボリューム上の形式で `bytes` に書き込む。
+    pub fn write_to(&self, bytes: &mut [u8]) {
+        assert!(bytes.len() >= DIR_ENTRY_SIZE);
+        // Safety: 長さは確認済み
+        unsafe { ptr::write_unaligned(bytes.as_mut_ptr() as *mut Self, *self) }
+    }
+
+    /// LFN エントリとして決められた値を持っているかを返す。
+    pub fn is_valid(&self) -> bool {
+        self.attr == Attribute::LongName as u8 && self.ty == 0 && self.fst_clus_lo == 0
+    }
+
+    /// このエントリが持つ文字を順に返す。
+    pub fn chars(&self) -> impl Iterator<Item = u16> {
+        let (name1, name2, name3) = (self.name1, self.name2, self.name3);
+        name1.into_iter().chain(name2).chain(name3)
+    }
+}
+
+#[repr(u8)]
+pub enum Attribute {
+    ReadOnly = 0x01,
+    Hidden = 0x02,
+    System = 0x04,
+    VolumeID = 0x08,
+    Directory = 0x10,
+    Archive = 0x20,
+    LongName = 0x0f,
+}
+
+pub fn read_name(entry: &DirectoryEntry) -> (&str, &str) {
+    let base_len = entry.name[..8]
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(i, &b)| if b != 0x20 { Some(i + 1) } else { None })
+        .unwrap_or(0);
+
+    let ext_len = entry.name[8..]
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(i, &b)| if b != 0x20 { Some(i + 1) } else { None })
+        .unwrap_or(0);
+
+    // 壊れたボリュームでは ASCII 以外が入っていることもある
+    (
+        core::str::from_utf8(&entry.name[..base_len]).unwrap_or("?"),
+        core::str::from_utf8(&entry.name[8..8 + ext_len]).unwrap_or("?"),
+    )
+}
+
+/// `entry` の短い名前を `BASE.EXT` の形式で返す。
+pub fn short_name(entry: &DirectoryEntry) -> String {
+    let (base, ext) = read_name(entry);
+    if ext.is_empty() {
+        base.to_string()
+    } else {
+        format!("{}.{}", base, ext)
+    }
+}
+
+/// `name` を短い名前に変換するときの、名前部分と拡張子部分を返す。
+/// 使えない文字を置き換えたり、切り詰めたりして情報が失われた場合は、それも返す。
+pub(crate) fn short_name_basis(name: &str) -> (Vec<u8>, Vec<u8>, bool) {
+    // 先頭のピリオドは拡張子の区切りではない
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) if !base.trim_start_matches('.').is_empty() => (base, ext),
+        _ => (name, ""),
+    };
+
+    let mut lossy = false;
+    let mut convert = |s: &str, max_len: usize| {
+        let mut res = Vec::with_capacity(max_len);
+        for c in s.chars() {
+            let c = match c {
+                ' ' | '.' => {
+                    lossy = true;
+                    continue;
+                }
+                c if c.is_ascii_alphanumeric() || "$%'-_@~`!(){}^#&".contains(c) => {
+                    c.to_ascii_uppercase() as u8
+                }
+                _ => {
+                    lossy = true;
+                    b'_'
+                }
+            };
+            if res.len() == max_len {
+                lossy = true;
+                break;
+            }
+            res.push(c);
+        }
+        res
+    };
+    let base = convert(base, 8);
+    let ext = convert(ext, 3);
+    (base, ext, lossy)
+}
+
+/// `path` の最後の要素が `.` か `..` かを返す。
+pub(crate) fn ends_with_dot_entry(path: &str) -> bool {
+    let name = path
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default();
+    name == "." || name == ".."
+}
+
+/// `name` がそのまま 8.3 形式で表せる場合は、その短い名前を返す。
+/// 小文字を含む場合は、大文字小文字を保存するために LFN が必要なので `None` を返す。
+pub(crate) fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext, lossy) = short_name_basis(name);
+    if lossy || base.is_empty() || name.bytes().any(|c| c.is_ascii_lowercase()) {
+        return None;
+    }
+    Some(to_short_name(&base, &ext))
+}
+
+/// `base` に `~N` を付けた短い名前の候補を、`N` の小さい順に返す。
+pub(crate) fn numbered_short_names<'a>(
+    base: &'a [u8],
+    ext: &'a [u8],
+) -> impl Iterator<Item = [u8; 11]> + 'a {
+    (1..1_000_000).map(move |n| {
+        let tail = format!("~{}", n);
+        let base_len = cmp::min(base.len(), 8 - tail.len());
+        let mut candidate_base = base[..base_len].to_vec();
+        candidate_base.extend_from_slice(tail.as_bytes());
+        to_short_name(&candidate_base, ext)
+    })
+}
+
+/// ディレクトリエントリの短い名前 `name` が、8.3 形式として正しいかを返す。
+/// 小文字は LFN を持たないエントリでは使われないので、正しくないものとして扱う。
+pub(crate) fn is_valid_short_name(name: &[u8; 11]) -> bool {
+    // 先頭の 0x05 は 0xe5 で始まる名前を表す
+    name[0] != b' '
+        && name.iter().enumerate().all(|(i, &c)| {
+            (c >= 0x20 || (i == 0 && c == 0x05))
+                && !b"\"*+,./:;<=>?[\\]|".contains(&c)
+                && !c.is_ascii_lowercase()
+        })
+}
+
+/// 名前部分と拡張子部分を空白で埋めて、ディレクトリエントリの形式にする。
+pub(crate) fn to_short_name(base: &[u8], ext: &[u8]) -> [u8; 11] {
+    let mut name = [b' '; 11];
+    name[..base.len()].copy_from_slice(base);
+    name[8..8 + ext.len()].copy_from_slice(ext);
+    name
+}
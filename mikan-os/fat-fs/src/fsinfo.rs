@@ -0,0 +1,64 @@
+//! FAT32 の FSInfo セクタ。
+//!
+//! 空きクラスタ数と、次に空きクラスタを探し始める位置のヒントを持つ。
+//! どちらもヒントなので、値がおかしい場合は使わずに FAT から求める。
+
+const LEAD_SIG: u32 = 0x4161_5252;
+const STRUC_SIG: u32 = 0x6141_7272;
+const TRAIL_SIG: u32 = 0xaa55_0000;
+
+const LEAD_SIG_OFFSET: usize = 0;
+const STRUC_SIG_OFFSET: usize = 484;
+const FREE_COUNT_OFFSET: usize = 488;
+const NXT_FREE_OFFSET: usize = 492;
+const TRAIL_SIG_OFFSET: usize = 508;
+
+/// 値がわからないことを表す
+const UNKNOWN: u32 = 0xffff_ffff;
+
+/// FSInfo セクタの内容。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FsInfo {
+    /// 空きクラスタ数
+    pub free_count: Option<u32>,
+    /// 空きクラスタを探し始めるクラスタ
+    pub next_free: Option<u32>,
+}
+
+impl FsInfo {
+    /// セクタの内容 `sector` から読み込む。シグネチャが正しくない場合は `None` を返す。
+    pub fn parse(sector: &[u8]) -> Option<Self> {
+        let valid = sector.len() >= 512
+            && read_u32(sector, LEAD_SIG_OFFSET) == LEAD_SIG
+            && read_u32(sector, STRUC_SIG_OFFSET) == STRUC_SIG
+            && read_u32(sector, TRAIL_SIG_OFFSET) == TRAIL_SIG;
+        if !valid {
+            return None;
+        }
+
+        let value = |offset| Some(read_u32(sector, offset)).filter(|&v| v != UNKNOWN);
+        Some(Self {
+            free_count: value(FREE_COUNT_OFFSET),
+            next_free: value(NXT_FREE_OFFSET),
+        })
+    }
+
+    /// シグネチャが正しいセクタ `sector` に、空きクラスタ数とヒントを書き込む。
+    /// 内容が変わった場合は true を返す。
+    pub fn write_to(&self, sector: &mut [u8]) -> bool {
+        let mut fields = [0; 8];
+        fields[..4].copy_from_slice(&self.free_count.unwrap_or(UNKNOWN).to_le_bytes());
+        fields[4..].copy_from_slice(&self.next_free.unwrap_or(UNKNOWN).to_le_bytes());
+
+        let dest = &mut sector[FREE_COUNT_OFFSET..NXT_FREE_OFFSET + 4];
+        if *dest == fields {
+            return false;
+        }
+        dest.copy_from_slice(&fields);
+        true
+    }
+}
+
+fn read_u32(sector: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap())
+}
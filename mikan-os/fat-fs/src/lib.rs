@@ -0,0 +1,75 @@
+//! FAT12/16/32 のボリュームを扱うライブラリ。
+//!
+//! ボリュームは [BlockDevice] を通して読み書きし、FAT とディレクトリのセクタは [Volume] が持つキャッシュに
+//! 読み込んで保持する。変更したセクタは [Volume::sync] で書き戻す。
+//! ファイルの内容はキャッシュせずに、デバイスから直接読み書きする。
+
+#![no_std]
+
+extern crate alloc;
+
+mod bpb;
+mod check;
+mod dir;
+mod fsinfo;
+mod volume;
+
+use core::fmt;
+
+pub use bpb::{FatType, BPB};
+pub use check::Problem;
+pub use dir::{read_name, short_name, Attribute, DirectoryEntry};
+pub use volume::{DirCursor, Entry, Location, Volume};
+
+/// クラスタチェーンの終わりを表すクラスタ番号。
+/// FAT12/16 のボリュームでも、FAT エントリの値はこの値にそろえて扱う。
+pub const END_OF_CLUSTER_CHAIN: u64 = 0x0fff_ffff;
+
+/// 不良クラスタを表す FAT エントリの値
+pub(crate) const BAD_CLUSTER: u32 = 0x0fff_fff7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// ファイルもしくはディレクトリが見つからない
+    NoSuchEntry,
+    /// ディレクトリであるべきものがファイルだった
+    NotDirectory,
+    /// ファイルであるべきものがディレクトリだった
+    IsDirectory,
+    FileExists,
+    DirectoryNotEmpty,
+    NameTooLong,
+    /// 空きクラスタがない
+    NoSpace,
+    /// ディレクトリを自身の中に移動しようとしたか、`.` か `..` を削除しようとした
+    InvalidMove,
+    /// ファイルの終わりより後ろを指定した
+    OutOfRange,
+    /// ボリュームが FAT として解釈できない
+    InvalidFormat,
+    /// デバイスの読み書きに失敗した
+    Device,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// セクタ単位で読み書きできるデバイス。
+/// セクタ番号はボリュームの先頭を 0 とする。
+pub trait BlockDevice: Send {
+    /// 1 セクタのバイト数を返す。
+    fn sector_size(&self) -> usize;
+
+    /// `sector` から `buf` の長さ分のセクタを読み込む。
+    /// `buf` の長さはセクタサイズの倍数でなければならない。
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// `sector` から `buf` の長さ分のセクタに書き込む。
+    /// `buf` の長さはセクタサイズの倍数でなければならない。
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<()>;
+}
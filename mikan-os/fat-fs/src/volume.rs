@@ -0,0 +1,1377 @@
+use core::{cmp, mem};
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    vec,
+    vec::Vec,
+};
+
+use crate::{
+    bpb::{FatType, BPB},
+    dir::{
+        self, Attribute, DirectoryEntry, LongNameEntry, DIR_ENTRY_SIZE, LFN_CHARS_PER_ENTRY,
+        LFN_LAST_ENTRY, LFN_MAX_CHARS,
+    },
+    fsinfo::FsInfo,
+    BlockDevice, Error, Result, BAD_CLUSTER, END_OF_CLUSTER_CHAIN,
+};
+
+/// キャッシュに置いておくセクタ数の目安。これを超えたら変更されていないセクタを捨てる
+const MAX_CACHED_SECTORS: usize = 4096;
+
+/// ディレクトリエントリがあるボリューム上の位置。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Location {
+    /// エントリを含むセクタ
+    pub sector: u64,
+    /// セクタ先頭からのバイト数
+    pub offset: usize,
+}
+
+/// ディレクトリ内のファイルもしくはディレクトリ。
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// 読み込んだ時点のディレクトリエントリの内容
+    pub dir_entry: DirectoryEntry,
+    /// LFN があればそれを、なければ短い名前を `BASE.EXT` の形式にしたもの
+    pub name: String,
+    /// ディレクトリエントリの位置
+    pub location: Location,
+}
+
+impl Entry {
+    pub fn is_dir(&self) -> bool {
+        self.dir_entry.is_dir()
+    }
+
+    pub fn first_cluster(&self) -> u64 {
+        self.dir_entry.first_cluster() as _
+    }
+}
+
+/// ディレクトリ内のエントリを順に読むための位置と、読み途中の LFN。
+/// [Volume::next_entry] に渡して使う。
+#[derive(Debug, Clone)]
+pub struct DirCursor {
+    /// 次に読むエントリがあるクラスタ。
+    cluster: u64,
+    /// 次に読むエントリのクラスタ内でのインデックス。
+    index: usize,
+    /// 読み途中の LFN。
+    lfn: [u16; LFN_MAX_CHARS],
+    /// 直前に読んだ LFN エントリの順番。LFN を読んでいないときは 0。
+    lfn_ord: u8,
+    /// 読み途中の LFN エントリが持つチェックサム。
+    lfn_checksum: u8,
+}
+
+impl DirCursor {
+    /// `dir_cluster` から始まるディレクトリの先頭を指す。
+    pub fn new(dir_cluster: u64) -> Self {
+        Self {
+            cluster: dir_cluster,
+            index: 0,
+            lfn: [0; LFN_MAX_CHARS],
+            lfn_ord: 0,
+            lfn_checksum: 0,
+        }
+    }
+
+    /// LFN エントリを読み、読み途中の LFN に追加する。
+    /// 順番やチェックサムが合わない場合は、読み途中の LFN を破棄する。
+    fn push_lfn_entry(&mut self, entry: &LongNameEntry) {
+        let ord = entry.ord & !LFN_LAST_ENTRY;
+        let valid_ord = (1..=(LFN_MAX_CHARS / LFN_CHARS_PER_ENTRY + 1) as u8).contains(&ord)
+            && entry.is_valid();
+
+        if entry.ord & LFN_LAST_ENTRY != 0 && valid_ord {
+            // 最後の LFN エントリから逆順に並んでいる
+            self.lfn = [0; LFN_MAX_CHARS];
+            self.lfn_checksum = entry.checksum;
+        } else if !valid_ord || ord + 1 != self.lfn_ord || entry.checksum != self.lfn_checksum {
+            self.lfn_ord = 0;
+            return;
+        }
+        self.lfn_ord = ord;
+
+        let start = (ord as usize - 1) * LFN_CHARS_PER_ENTRY;
+        for (i, c) in entry.chars().enumerate() {
+            if let Some(dest) = self.lfn.get_mut(start + i) {
+                *dest = c;
+            }
+        }
+    }
+
+    /// 読み終わった LFN が `entry` に対応していればそれを返す。
+    fn take_lfn(&mut self, entry: &DirectoryEntry) -> Option<String> {
+        let ord = mem::replace(&mut self.lfn_ord, 0);
+        if ord != 1 || self.lfn_checksum != entry.checksum() {
+            return None;
+        }
+
+        // 0x0000 で終端され、残りは 0xffff で埋められている
+        let len = self
+            .lfn
+            .iter()
+            .position(|&c| c == 0x0000 || c == 0xffff)
+            .unwrap_or(LFN_MAX_CHARS);
+        char::decode_utf16(self.lfn[..len].iter().copied())
+            .collect::<core::result::Result<String, _>>()
+            .ok()
+    }
+}
+
+/// FAT12/16/32 のボリューム。
+///
+/// FAT12/16 のルートディレクトリはデータ領域の手前にある固定サイズの領域で、クラスタ番号 0 で指す。
+pub struct Volume {
+    device: Box<dyn BlockDevice>,
+    /// ボリュームの 1 セクタに対応するデバイスのセクタ数
+    device_sectors_per_sector: u64,
+    bpb: BPB,
+    fat_type: FatType,
+    /// 読み込んだ FAT とディレクトリのセクタ
+    sectors: BTreeMap<u64, Box<[u8]>>,
+    /// 書き戻していないセクタ
+    dirty: BTreeSet<u64>,
+    /// FSInfo セクタの番号。FAT32 で FSInfo セクタが正しい場合だけ持つ
+    fsinfo_sector: Option<u64>,
+    /// 空きクラスタ数。まだ数えていない場合は `None`
+    free_count: Option<u64>,
+    /// 空きクラスタを探し始めるクラスタ。これより前のクラスタは使用中であることが多い
+    next_free: u64,
+}
+
+impl Volume {
+    /// `device` の先頭にある FAT のボリュームを開く。FAT の種類はクラスタ数から決める。
+    pub fn new(mut device: Box<dyn BlockDevice>) -> Result<Self> {
+        let device_sector_size = device.sector_size();
+        let mut boot_sector = vec![0; 512.max(device_sector_size)];
+        device.read(0, &mut boot_sector)?;
+        let bpb = BPB::parse(&boot_sector)?;
+
+        let bytes_per_sector = bpb.byts_per_sec() as usize;
+        if !bytes_per_sector.is_multiple_of(device_sector_size) {
+            return Err(Error::InvalidFormat);
+        }
+
+        let mut volume = Self {
+            device,
+            device_sectors_per_sector: (bytes_per_sector / device_sector_size) as u64,
+            fat_type: bpb.fat_type(),
+            bpb,
+            sectors: BTreeMap::new(),
+            dirty: BTreeSet::new(),
+            fsinfo_sector: None,
+            free_count: None,
+            next_free: 2,
+        };
+        volume.load_fsinfo()?;
+        Ok(volume)
+    }
+
+    /// FAT32 の FSInfo セクタから空きクラスタ数とヒントを読み込む。
+    /// 範囲外の値は使わない。
+    fn load_fsinfo(&mut self) -> Result<()> {
+        let sector = self.bpb.fsinfo() as u64;
+        if self.fat_type != FatType::Fat32
+            || sector == 0
+            || sector >= self.bpb.rsvd_sec_cnt() as u64
+        {
+            return Ok(());
+        }
+        let Some(fsinfo) = FsInfo::parse(self.cached_sector(sector)?) else {
+            return Ok(());
+        };
+
+        let cluster_count = self.cluster_count();
+        self.fsinfo_sector = Some(sector);
+        self.free_count = fsinfo
+            .free_count
+            .map(|n| n as u64)
+            .filter(|&n| n <= cluster_count);
+        if let Some(next_free) = fsinfo.next_free {
+            if (2..cluster_count + 2).contains(&(next_free as u64)) {
+                self.next_free = next_free as _;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn bpb(&self) -> &BPB {
+        &self.bpb
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    pub fn bytes_per_sector(&self) -> usize {
+        self.bpb.byts_per_sec() as _
+    }
+
+    pub fn bytes_per_cluster(&self) -> usize {
+        self.bytes_per_sector() * self.bpb.sec_per_clus() as usize
+    }
+
+    /// ルートディレクトリの先頭クラスタを返す。
+    /// FAT12/16 ではルートディレクトリの領域を指す 0 を返す。
+    pub fn root_cluster(&self) -> u64 {
+        match self.fat_type {
+            FatType::Fat32 => self.bpb.root_clus() as _,
+            FatType::Fat12 | FatType::Fat16 => 0,
+        }
+    }
+
+    /// データ領域にあるクラスタの数を返す。クラスタ番号は 2 から始まる。
+    pub fn cluster_count(&self) -> u64 {
+        self.bpb.cluster_count()
+    }
+
+    /// 空きクラスタの数を返す。
+    /// FSInfo から読めなかった場合は、最初に呼んだときに FAT を全て読んで数える。
+    pub fn free_clusters(&mut self) -> Result<u64> {
+        if let Some(n) = self.free_count {
+            return Ok(n);
+        }
+        let mut n = 0;
+        for cluster in 2..self.cluster_count() + 2 {
+            if self.fat_entry(cluster)? == 0 {
+                n += 1;
+            }
+        }
+        self.free_count = Some(n);
+        Ok(n)
+    }
+
+    /// 空きクラスタ数を `n` として記録する。[Volume::check] で数え直したときに使う。
+    pub(crate) fn set_free_clusters(&mut self, n: u64) {
+        self.free_count = Some(n);
+    }
+
+    /// 記録している空きクラスタ数を返す。まだ数えていない場合は `None`。
+    pub(crate) fn recorded_free_clusters(&self) -> Option<u64> {
+        self.free_count
+    }
+
+    /// 変更済みのセクタをデバイスに書き戻す。
+    /// FAT の複製には 1 つ目の FAT と同じ内容を書き込み、FSInfo には空きクラスタ数とヒントを書き込む。
+    pub fn sync(&mut self) -> Result<()> {
+        let fat_start = self.bpb.rsvd_sec_cnt() as u64;
+        let fat_size = self.bpb.fat_size();
+
+        if let Some(sector) = self.fsinfo_sector {
+            let fsinfo = FsInfo {
+                free_count: self.free_count.map(|n| n as _),
+                next_free: Some(self.next_free as _),
+            };
+            if fsinfo.write_to(self.cached_sector(sector)?) {
+                self.dirty.insert(sector);
+            }
+        }
+
+        while let Some(&sector) = self.dirty.first() {
+            let data = self.sectors[&sector].clone();
+            self.write_device(sector, &data)?;
+            if (fat_start..fat_start + fat_size).contains(&sector) {
+                for i in 1..self.bpb.num_fats() as u64 {
+                    self.write_device(sector + i * fat_size, &data)?;
+                }
+            }
+            self.dirty.remove(&sector);
+        }
+        Ok(())
+    }
+
+    /// `path` が絶対パスのときはルートディレクトリ、
+    /// 相対パスの場合は `directory_cluster` を基準としてファイル、ディレクトリを検索する。
+    /// `directory_cluster` が `0` のときはルートディレクトリを基準として探索する。
+    ///
+    /// 見つかったファイルもしくはディレクトリと、`/` がその直後にあるかどうかを返す。
+    pub fn find_file(
+        &mut self,
+        path: &str,
+        directory_cluster: u64,
+    ) -> Result<(Option<Entry>, bool)> {
+        let (mut rel_path, mut directory_cluster) = match path.strip_prefix('/') {
+            Some(path) => (path, self.root_cluster()),
+            None if directory_cluster == 0 => (path, self.root_cluster()),
+            None => (path, directory_cluster),
+        };
+
+        loop {
+            let (path_elem, next_path, post_slash) = rel_path
+                .split_once('/')
+                // Some になるのは `/` が含まれていた場合なので、`path_elem` のあとは `/`
+                .map(|x| (x.0, x.1, true))
+                // None になるのは `/` がない場合で、`path_elem` のあとはなにもない
+                .unwrap_or((rel_path, "", false));
+
+            let found = self.read_dir(directory_cluster)?.into_iter().find(|entry| {
+                entry.name.eq_ignore_ascii_case(path_elem)
+                    || entry.dir_entry.name_is_equal(path_elem)
+            });
+            let Some(entry) = found else {
+                return Ok((None, post_slash));
+            };
+
+            if entry.is_dir() && !next_path.is_empty() {
+                rel_path = next_path;
+                // `..` がルートディレクトリを指す場合は 0 になっている
+                directory_cluster = match entry.first_cluster() {
+                    0 => self.root_cluster(),
+                    cluster => cluster,
+                };
+            } else {
+                return Ok((Some(entry), post_slash));
+            }
+        }
+    }
+
+    /// `dir_cluster` から始まるディレクトリ内のエントリを全て返す。
+    pub fn read_dir(&mut self, dir_cluster: u64) -> Result<Vec<Entry>> {
+        let mut cursor = DirCursor::new(dir_cluster);
+        let mut entries = Vec::new();
+        while let Some(entry) = self.next_entry(&mut cursor)? {
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// `clusters` を順にたどってディレクトリ内のエントリを全て返す。
+    /// FAT を信用せずに、確認済みのクラスタチェーンだけを読むときに使う。
+    pub(crate) fn read_dir_clusters(&mut self, clusters: &[u64]) -> Result<Vec<Entry>> {
+        let mut cursor = DirCursor::new(0);
+        let mut entries = Vec::new();
+        for &cluster in clusters {
+            cursor.cluster = cluster;
+            cursor.index = 0;
+            while let Some(entry) = self.next_entry_in_cluster(&mut cursor)? {
+                entries.push(entry);
+            }
+            if cursor.cluster == END_OF_CLUSTER_CHAIN {
+                break;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// `cursor` が指すディレクトリ内の次のエントリを返し、`cursor` を進める。
+    /// 名前は LFN エントリがあればそれを、なければ短い名前を使う。
+    /// 削除済みのエントリと LFN エントリ自体は飛ばす。
+    pub fn next_entry(&mut self, cursor: &mut DirCursor) -> Result<Option<Entry>> {
+        while cursor.cluster != END_OF_CLUSTER_CHAIN
+            && (cursor.cluster != 0 || self.is_root_region(cursor.cluster))
+        {
+            if let Some(entry) = self.next_entry_in_cluster(cursor)? {
+                return Ok(Some(entry));
+            }
+            if cursor.cluster != END_OF_CLUSTER_CHAIN {
+                cursor.cluster = self.next_dir_cluster(cursor.cluster)?;
+                cursor.index = 0;
+            }
+        }
+        Ok(None)
+    }
+
+    /// `cursor` が指すクラスタ内の次のエントリを返し、`cursor` を進める。
+    /// クラスタの終わりに達した場合は `None` を返す。
+    /// ディレクトリの終わりに達した場合は、`cursor` のクラスタを [END_OF_CLUSTER_CHAIN] にして `None` を返す。
+    fn next_entry_in_cluster(&mut self, cursor: &mut DirCursor) -> Result<Option<Entry>> {
+        while cursor.index < self.dir_entries_in(cursor.cluster) {
+            let location = self.entry_location(cursor.cluster, cursor.index);
+            cursor.index += 1;
+            let bytes = self.entry_bytes(location)?;
+
+            // ディレクトリ内の要素が終わったことを示す
+            if bytes[0] == 0x00 {
+                cursor.cluster = END_OF_CLUSTER_CHAIN;
+                return Ok(None);
+            } else if bytes[0] == 0xe5 {
+                cursor.lfn_ord = 0;
+                continue;
+            } else if bytes[11] == Attribute::LongName as u8 {
+                cursor.push_lfn_entry(&LongNameEntry::from_bytes(bytes));
+                continue;
+            }
+
+            let dir_entry = DirectoryEntry::from_bytes(bytes);
+            let name = cursor
+                .take_lfn(&dir_entry)
+                .unwrap_or_else(|| dir::short_name(&dir_entry));
+            return Ok(Some(Entry {
+                dir_entry,
+                name,
+                location,
+            }));
+        }
+        Ok(None)
+    }
+
+    /// `location` にあるディレクトリエントリを読み込む。
+    pub fn dir_entry(&mut self, location: Location) -> Result<DirectoryEntry> {
+        Ok(DirectoryEntry::from_bytes(self.entry_bytes(location)?))
+    }
+
+    /// `location` にあるディレクトリエントリを読み込む。削除済みや未使用のエントリの場合はエラーを返す。
+    fn file_entry(&mut self, location: Location) -> Result<DirectoryEntry> {
+        let entry = self.dir_entry(location)?;
+        if entry.name[0] == 0x00 || entry.name[0] == 0xe5 {
+            return Err(Error::NoSuchEntry);
+        }
+        Ok(entry)
+    }
+
+    /// `location` にあるディレクトリエントリを `entry` で上書きする。
+    pub fn set_dir_entry(&mut self, location: Location, entry: &DirectoryEntry) -> Result<()> {
+        entry.write_to(self.entry_bytes_mut(location)?);
+        Ok(())
+    }
+
+    /// `path` に空のファイルを作る。
+    pub fn create_file(&mut self, path: &str) -> Result<Entry> {
+        let (location, _) = self.create_entry(path)?;
+        self.entry_at(location, path)
+    }
+
+    /// `path` にディレクトリを作り、`.` と `..` のエントリを書き込む。
+    pub fn create_dir(&mut self, path: &str) -> Result<Entry> {
+        let path = path.trim_end_matches('/');
+        if path.is_empty() {
+            return Err(Error::FileExists);
+        }
+
+        let (location, parent_dir_cluster) = self.create_entry(path)?;
+        let cluster = match self.allocate_cluster_chain(1) {
+            Ok(cluster) => cluster,
+            Err(e) => {
+                self.remove_entry(parent_dir_cluster, location)?;
+                return Err(e);
+            }
+        };
+        self.clear_cluster(cluster)?;
+
+        let mut entry = self.dir_entry(location)?;
+        entry.attr = Attribute::Directory as _;
+        entry.set_first_cluster(cluster as _);
+        self.set_dir_entry(location, &entry)?;
+
+        let mut dot = DirectoryEntry {
+            name: *b".          ",
+            attr: Attribute::Directory as _,
+            ..Default::default()
+        };
+        dot.set_first_cluster(cluster as _);
+        self.set_dir_entry(self.entry_location(cluster, 0), &dot)?;
+
+        let mut dotdot = DirectoryEntry {
+            name: *b"..         ",
+            attr: Attribute::Directory as _,
+            ..Default::default()
+        };
+        dotdot.set_first_cluster(self.parent_cluster_value(parent_dir_cluster) as _);
+        self.set_dir_entry(self.entry_location(cluster, 1), &dotdot)?;
+
+        self.entry_at(location, path)
+    }
+
+    /// `path` が指すファイルもしくは空のディレクトリを削除し、そのクラスタチェーンを解放する。
+    /// 削除したエントリを返す。`.` と `..` は削除できない。
+    pub fn remove_file(&mut self, path: &str) -> Result<Entry> {
+        if dir::ends_with_dot_entry(path) {
+            return Err(Error::InvalidMove);
+        }
+        let entry = match self.find_file(path, 0)? {
+            (Some(entry), true) if !entry.is_dir() => return Err(Error::NoSuchEntry),
+            (Some(entry), _) => entry,
+            (None, _) => return Err(Error::NoSuchEntry),
+        };
+
+        if entry.is_dir() && !self.is_empty_dir(entry.first_cluster())? {
+            return Err(Error::DirectoryNotEmpty);
+        }
+
+        let (parent_dir_cluster, _) = self.split_path(path.trim_end_matches('/'))?;
+        self.free_cluster_chain(entry.first_cluster())?;
+        self.remove_entry(parent_dir_cluster, entry.location)?;
+        Ok(entry)
+    }
+
+    /// `path` が指す空のディレクトリを削除する。
+    pub fn remove_dir(&mut self, path: &str) -> Result<Entry> {
+        if dir::ends_with_dot_entry(path) {
+            return Err(Error::InvalidMove);
+        }
+        match self.find_file(path, 0)? {
+            (Some(entry), _) if entry.is_dir() => self.remove_file(path),
+            (Some(_), _) => Err(Error::NotDirectory),
+            (None, _) => Err(Error::NoSuchEntry),
+        }
+    }
+
+    /// `old_path` が指すファイルもしくはディレクトリを `new_path` に移動する。
+    /// `new_path` が既に存在する場合は何も変更せずにエラーを返す。
+    /// ただし `old_path` と大文字小文字だけが違う場合は、名前を付け直す。
+    pub fn rename(&mut self, old_path: &str, new_path: &str) -> Result<()> {
+        let old_path = old_path.trim_end_matches('/');
+        let new_path = new_path.trim_end_matches('/');
+
+        if dir::ends_with_dot_entry(old_path) || dir::ends_with_dot_entry(new_path) {
+            return Err(Error::InvalidMove);
+        }
+
+        let (Some(entry), _) = self.find_file(old_path, 0)? else {
+            return Err(Error::NoSuchEntry);
+        };
+        match self.find_file(new_path, 0)?.0 {
+            // 大文字小文字だけが違う名前は、移動元と同じエントリに見つかる
+            Some(found) if found.location == entry.location => {
+                let (dir_cluster, new_name) = self.split_path(new_path)?;
+                return self.rename_in_place(dir_cluster, &entry, new_name);
+            }
+            Some(_) => return Err(Error::FileExists),
+            None => {}
+        }
+        let (old_parent_cluster, _) = self.split_path(old_path)?;
+        let (new_parent_cluster, new_name) = self.split_path(new_path)?;
+
+        // 同じディレクトリ内で 8.3 形式で表せる名前なら、短い名前を書き換えるだけで良い
+        if old_parent_cluster == new_parent_cluster {
+            if let Some(short_name) = dir::exact_short_name(new_name) {
+                self.remove_long_name(old_parent_cluster, entry.location)?;
+                let mut dir_entry = entry.dir_entry;
+                dir_entry.name = short_name;
+                return self.set_dir_entry(entry.location, &dir_entry);
+            }
+        }
+
+        // ディレクトリを自身の中に移動することはできない
+        if entry.is_dir() && self.is_ancestor(entry.first_cluster(), new_parent_cluster)? {
+            return Err(Error::InvalidMove);
+        }
+
+        let new_location = self.create_entry_in(new_parent_cluster, new_name)?;
+        let mut new_entry = entry.dir_entry;
+        new_entry.name = self.dir_entry(new_location)?.name;
+        self.set_dir_entry(new_location, &new_entry)?;
+        self.remove_entry(old_parent_cluster, entry.location)?;
+
+        if entry.is_dir() {
+            let dotdot_location = self.entry_location(entry.first_cluster(), 1);
+            let mut dotdot = self.dir_entry(dotdot_location)?;
+            dotdot.set_first_cluster(self.parent_cluster_value(new_parent_cluster) as _);
+            self.set_dir_entry(dotdot_location, &dotdot)?;
+        }
+        Ok(())
+    }
+
+    /// `dir_cluster` のディレクトリ内の `entry` の名前を、大文字小文字だけが違う `new_name` に変える。
+    /// 短い名前はそのままにして、名前の変わる LFN エントリを書き換える。
+    fn rename_in_place(&mut self, dir_cluster: u64, entry: &Entry, new_name: &str) -> Result<()> {
+        if entry.name == new_name {
+            return Ok(());
+        }
+        if let Some(short_name) = dir::exact_short_name(new_name) {
+            self.remove_long_name(dir_cluster, entry.location)?;
+            let mut dir_entry = entry.dir_entry;
+            dir_entry.name = short_name;
+            return self.set_dir_entry(entry.location, &dir_entry);
+        }
+
+        let name16: Vec<u16> = new_name.encode_utf16().collect();
+        let num_lfn_entries = name16.len().div_ceil(LFN_CHARS_PER_ENTRY);
+        let checksum = entry.dir_entry.checksum();
+        let lfn_locations = self.long_name_locations(dir_cluster, entry.location)?;
+        if lfn_locations.len() == num_lfn_entries {
+            return self.write_long_name(&lfn_locations, &name16, checksum);
+        }
+
+        // LFN エントリの数が変わる場合は、同じ短い名前のエントリを別の場所に作り直す
+        let mut locations = self.allocate_entries(dir_cluster, num_lfn_entries + 1)?;
+        let location = locations.pop().unwrap();
+        self.set_dir_entry(location, &entry.dir_entry)?;
+        self.write_long_name(&locations, &name16, checksum)?;
+        self.remove_entry(dir_cluster, entry.location)
+    }
+
+    /// `first_cluster` から始まるクラスタチェーンの `offset` バイト目から `buf` の長さ分を読み込み、
+    /// 読み込んだバイト数を返す。クラスタチェーンの終わりに達した場合はそこまでを読む。
+    /// `offset` と `buf` の長さはセクタサイズの倍数でなければならない。
+    pub fn read_cluster_chain(
+        &mut self,
+        first_cluster: u64,
+        mut offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let bytes_per_cluster = self.bytes_per_cluster();
+        let bytes_per_sector = self.bytes_per_sector();
+
+        let mut cluster = first_cluster;
+        while offset >= bytes_per_cluster && cluster != 0 && cluster != END_OF_CLUSTER_CHAIN {
+            cluster = self.next_cluster(cluster)?;
+            offset -= bytes_per_cluster;
+        }
+
+        let mut total = 0;
+        while total < buf.len() && cluster != 0 && cluster != END_OF_CLUSTER_CHAIN {
+            let n = cmp::min(buf.len() - total, bytes_per_cluster - offset);
+            let sector = self.cluster_sector(cluster) + (offset / bytes_per_sector) as u64;
+            self.read_sectors(sector, &mut buf[total..total + n])?;
+
+            total += n;
+            offset = 0;
+            cluster = self.next_cluster(cluster)?;
+        }
+        Ok(total)
+    }
+
+    /// `location` にあるファイルの `offset` バイト目から `data` を書き込み、書き込んだバイト数を返す。
+    /// 必要に応じてクラスタを確保し、ファイルサイズを更新する。
+    /// 空きクラスタが足りなくなった場合は、書き込めたところまでのバイト数を返す。
+    pub fn write_file(&mut self, location: Location, offset: usize, data: &[u8]) -> Result<usize> {
+        let mut entry = self.file_entry(location)?;
+        if offset > entry.file_size as usize {
+            return Err(Error::OutOfRange);
+        }
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let (total, err) = self.write_clusters(&mut entry, offset, data);
+        // 途中を上書きした場合はファイルサイズは変わらない
+        entry.file_size = cmp::max(entry.file_size, (offset + total) as _);
+        self.set_dir_entry(location, &entry)?;
+        match err {
+            Some(e) if total == 0 => Err(e),
+            _ => Ok(total),
+        }
+    }
+
+    /// `location` にあるファイルを `size` バイトに切り詰め、要らなくなったクラスタを解放する。
+    /// `size` は今のファイルサイズ以下でなければならない。
+    pub fn truncate_file(&mut self, location: Location, size: usize) -> Result<()> {
+        let mut entry = self.file_entry(location)?;
+        if entry.is_dir() {
+            return Err(Error::IsDirectory);
+        }
+        if size > entry.file_size as usize {
+            return Err(Error::OutOfRange);
+        }
+
+        let first_cluster = entry.first_cluster() as u64;
+        let keep = size.div_ceil(self.bytes_per_cluster());
+        let mut last = first_cluster;
+        if first_cluster != 0 && keep > 0 {
+            for _ in 1..keep {
+                match self.next_cluster(last)? {
+                    END_OF_CLUSTER_CHAIN => break,
+                    next => last = next,
+                }
+            }
+        }
+
+        entry.file_size = size as _;
+        if keep == 0 {
+            entry.set_first_cluster(0);
+        }
+        self.set_dir_entry(location, &entry)?;
+
+        if first_cluster == 0 {
+            return Ok(());
+        }
+        if keep == 0 {
+            return self.free_cluster_chain(first_cluster);
+        }
+        match self.next_cluster(last)? {
+            END_OF_CLUSTER_CHAIN => Ok(()),
+            rest => {
+                self.set_fat_entry(last, END_OF_CLUSTER_CHAIN as _)?;
+                self.free_cluster_chain(rest)
+            }
+        }
+    }
+
+    /// `entry` のクラスタチェーンの `offset` バイト目から `data` を書き込む。
+    /// 書き込んだバイト数と、途中で失敗した場合はそのエラーを返す。
+    fn write_clusters(
+        &mut self,
+        entry: &mut DirectoryEntry,
+        offset: usize,
+        data: &[u8],
+    ) -> (usize, Option<Error>) {
+        let bytes_per_cluster = self.bytes_per_cluster();
+
+        let mut cluster = entry.first_cluster() as u64;
+        if cluster == 0 {
+            cluster = match self.allocate_cluster_chain(1) {
+                Ok(cluster) => cluster,
+                Err(e) => return (0, Some(e)),
+            };
+            entry.set_first_cluster(cluster as _);
+        }
+
+        let mut cluster_off = offset;
+        let mut total = 0;
+        while total < data.len() {
+            if cluster_off >= bytes_per_cluster {
+                cluster = match self.next_or_extend(cluster) {
+                    Ok(cluster) => cluster,
+                    Err(e) => return (total, Some(e)),
+                };
+                cluster_off -= bytes_per_cluster;
+                continue;
+            }
+
+            let n = cmp::min(data.len() - total, bytes_per_cluster - cluster_off);
+            if let Err(e) = self.write_in_cluster(cluster, cluster_off, &data[total..total + n]) {
+                return (total, Some(e));
+            }
+            total += n;
+            cluster_off += n;
+        }
+        (total, None)
+    }
+
+    /// `cluster` の `offset` バイト目から `data` を書き込む。
+    /// セクタの一部だけを書き込む場合は、そのセクタを読み込んでから書き戻す。
+    fn write_in_cluster(&mut self, cluster: u64, offset: usize, data: &[u8]) -> Result<()> {
+        let bytes_per_sector = self.bytes_per_sector();
+        let first_sector = self.cluster_sector(cluster);
+
+        let mut pos = 0;
+        while pos < data.len() {
+            let sector = first_sector + ((offset + pos) / bytes_per_sector) as u64;
+            let sector_off = (offset + pos) % bytes_per_sector;
+            let rest = data.len() - pos;
+
+            if sector_off == 0 && rest >= bytes_per_sector {
+                let n = rest / bytes_per_sector * bytes_per_sector;
+                self.write_sectors(sector, &data[pos..pos + n])?;
+                pos += n;
+            } else {
+                let n = cmp::min(rest, bytes_per_sector - sector_off);
+                let mut buf = vec![0; bytes_per_sector];
+                self.read_sectors(sector, &mut buf)?;
+                buf[sector_off..sector_off + n].copy_from_slice(&data[pos..pos + n]);
+                self.write_sectors(sector, &buf)?;
+                pos += n;
+            }
+        }
+        Ok(())
+    }
+
+    /// `cluster` の次のクラスタを返す。チェーンの終わりの場合は新しいクラスタをつなげて返す。
+    fn next_or_extend(&mut self, cluster: u64) -> Result<u64> {
+        match self.next_cluster(cluster)? {
+            END_OF_CLUSTER_CHAIN => self.extend_cluster(cluster, 1),
+            next => Ok(next),
+        }
+    }
+
+    /// `cluster` の次のクラスタを返す。チェーンの終わりの場合は [END_OF_CLUSTER_CHAIN] を返す。
+    pub fn next_cluster(&mut self, cluster: u64) -> Result<u64> {
+        let next = self.fat_entry(cluster)? as u64;
+        // 範囲外を指している壊れたチェーンも、そこで終わりとして扱う
+        if next < 2 || next >= self.cluster_count() + 2 {
+            Ok(END_OF_CLUSTER_CHAIN)
+        } else {
+            Ok(next)
+        }
+    }
+
+    /// `n` 個のクラスタからなるクラスタチェーンを作り、その先頭クラスタを返す。
+    pub fn allocate_cluster_chain(&mut self, n: usize) -> Result<u64> {
+        let first_cluster = self.find_free_cluster()?;
+        self.set_fat_entry(first_cluster, END_OF_CLUSTER_CHAIN as _)?;
+        if n > 1 {
+            if let Err(e) = self.extend_cluster(first_cluster, n - 1) {
+                self.free_cluster_chain(first_cluster)?;
+                return Err(e);
+            }
+        }
+        Ok(first_cluster)
+    }
+
+    /// `eoc_cluster` を含むクラスタチェーンの終わりに `n` 個のクラスタをつなげ、最後のクラスタを返す。
+    /// 空きクラスタが足りない場合は、つなげられるだけつなげてエラーを返す。
+    pub fn extend_cluster(&mut self, eoc_cluster: u64, n: usize) -> Result<u64> {
+        let mut current = eoc_cluster;
+        loop {
+            match self.next_cluster(current)? {
+                END_OF_CLUSTER_CHAIN => break,
+                next => current = next,
+            }
+        }
+
+        for _ in 0..n {
+            let candidate = self.find_free_cluster()?;
+            self.set_fat_entry(candidate, END_OF_CLUSTER_CHAIN as _)?;
+            self.set_fat_entry(current, candidate as _)?;
+            current = candidate;
+        }
+        Ok(current)
+    }
+
+    /// `first_cluster` から始まるクラスタチェーンを全て未使用にする。
+    pub fn free_cluster_chain(&mut self, first_cluster: u64) -> Result<()> {
+        let mut cluster = first_cluster;
+        while cluster != 0 && cluster != END_OF_CLUSTER_CHAIN {
+            let next = self.next_cluster(cluster)?;
+            self.set_fat_entry(cluster, 0)?;
+            self.forget_cluster(cluster);
+            cluster = next;
+        }
+        Ok(())
+    }
+
+    /// 未使用のクラスタを、ヒントの位置から探す。
+    fn find_free_cluster(&mut self) -> Result<u64> {
+        let end = self.cluster_count() + 2;
+        for cluster in (self.next_free..end).chain(2..self.next_free) {
+            if self.fat_entry(cluster)? == 0 {
+                // 使われるとヒントはこの次に進む
+                self.next_free = cluster;
+                return Ok(cluster);
+            }
+        }
+        self.free_count = Some(0);
+        Err(Error::NoSpace)
+    }
+
+    /// `cluster` の FAT エントリの値を返す。
+    /// FAT12/16 のチェーンの終わりと不良クラスタの値は、FAT32 の値にそろえて返す。
+    pub fn fat_entry(&mut self, cluster: u64) -> Result<u32> {
+        let (offset, shift, mask) = self.fat_entry_position(cluster);
+        let mut bytes = [0; 4];
+        self.read_fat_bytes(offset, &mut bytes[..self.fat_entry_bytes()])?;
+        let value = (u32::from_le_bytes(bytes) >> shift) & mask;
+        Ok(if value >= mask - 7 {
+            END_OF_CLUSTER_CHAIN as _
+        } else if value == mask - 8 {
+            BAD_CLUSTER
+        } else {
+            value
+        })
+    }
+
+    /// `cluster` の FAT エントリを `value` にする。エントリに含まれないビットは保存する。
+    /// FAT32 の上位 4 ビットは予約されていて、FAT12 では 1 つのバイトを 2 つのエントリで共有している。
+    ///
+    /// 空きクラスタ数と空きクラスタを探すヒントもここで更新する。
+    /// 解放したクラスタがヒントより前にあればヒントを戻すので、空きクラスタは先頭から順に使われる。
+    pub(crate) fn set_fat_entry(&mut self, cluster: u64, value: u32) -> Result<()> {
+        let (offset, shift, mask) = self.fat_entry_position(cluster);
+        let value = if value >= END_OF_CLUSTER_CHAIN as u32 - 7 {
+            mask
+        } else if value == BAD_CLUSTER {
+            mask - 8
+        } else {
+            value & mask
+        };
+
+        let width = self.fat_entry_bytes();
+        let mut bytes = [0; 4];
+        self.read_fat_bytes(offset, &mut bytes[..width])?;
+        let old = u32::from_le_bytes(bytes);
+        let new = (old & !(mask << shift)) | (value << shift);
+        self.write_fat_bytes(offset, &new.to_le_bytes()[..width])?;
+
+        let was_free = (old >> shift) & mask == 0;
+        if let Some(n) = &mut self.free_count {
+            match (was_free, value == 0) {
+                (true, false) => *n = n.saturating_sub(1),
+                (false, true) => *n += 1,
+                _ => {}
+            }
+        }
+        if value == 0 {
+            self.next_free = cmp::min(self.next_free, cluster);
+        } else if cluster == self.next_free && cluster + 1 < self.cluster_count() + 2 {
+            self.next_free = cluster + 1;
+        }
+        Ok(())
+    }
+
+    /// `cluster` の FAT エントリの、1 つ目の FAT の先頭からのバイト位置と、
+    /// そこから読んだ値の中でのエントリのビット位置とマスクを返す。
+    fn fat_entry_position(&self, cluster: u64) -> (usize, u32, u32) {
+        let cluster = cluster as usize;
+        match self.fat_type {
+            // 12 ビットのエントリが 3 バイトに 2 つずつ詰められている
+            FatType::Fat12 => (cluster * 3 / 2, (cluster as u32 & 1) * 4, 0xfff),
+            FatType::Fat16 => (cluster * 2, 0, 0xffff),
+            FatType::Fat32 => (cluster * 4, 0, 0x0fff_ffff),
+        }
+    }
+
+    /// FAT エントリを読み書きするときのバイト数を返す。
+    fn fat_entry_bytes(&self) -> usize {
+        match self.fat_type {
+            FatType::Fat12 | FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        }
+    }
+
+    /// 1 つ目の FAT の `offset` バイト目から `buf` の長さ分を読む。
+    fn read_fat_bytes(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        let mut pos = 0;
+        // FAT12 のエントリはセクタの境界をまたぐことがある
+        while pos < buf.len() {
+            let (sector, sector_off) = self.fat_byte_position(offset + pos);
+            let n = cmp::min(buf.len() - pos, self.bytes_per_sector() - sector_off);
+            let data = self.cached_sector(sector)?;
+            buf[pos..pos + n].copy_from_slice(&data[sector_off..sector_off + n]);
+            pos += n;
+        }
+        Ok(())
+    }
+
+    /// 1 つ目の FAT の `offset` バイト目から `data` を書き込む。
+    fn write_fat_bytes(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        let mut pos = 0;
+        while pos < data.len() {
+            let (sector, sector_off) = self.fat_byte_position(offset + pos);
+            let n = cmp::min(data.len() - pos, self.bytes_per_sector() - sector_off);
+            let buf = self.cached_sector(sector)?;
+            buf[sector_off..sector_off + n].copy_from_slice(&data[pos..pos + n]);
+            self.dirty.insert(sector);
+            pos += n;
+        }
+        Ok(())
+    }
+
+    /// 1 つ目の FAT の `offset` バイト目があるセクタと、そのセクタ内でのオフセットを返す。
+    fn fat_byte_position(&self, offset: usize) -> (u64, usize) {
+        let sector = self.bpb.rsvd_sec_cnt() as u64 + (offset / self.bytes_per_sector()) as u64;
+        (sector, offset % self.bytes_per_sector())
+    }
+
+    /// `cluster` の先頭セクタの番号を返す。
+    pub fn cluster_sector(&self, cluster: u64) -> u64 {
+        self.bpb.data_start() + (cluster - 2) * self.bpb.sec_per_clus() as u64
+    }
+
+    fn entries_per_cluster(&self) -> usize {
+        self.bytes_per_cluster() / DIR_ENTRY_SIZE
+    }
+
+    /// `dir_cluster` が FAT12/16 のルートディレクトリの領域を指すかを返す。
+    fn is_root_region(&self, dir_cluster: u64) -> bool {
+        dir_cluster == 0 && self.fat_type != FatType::Fat32
+    }
+
+    /// ディレクトリの `dir_cluster` に入るエントリの数を返す。
+    fn dir_entries_in(&self, dir_cluster: u64) -> usize {
+        if self.is_root_region(dir_cluster) {
+            self.bpb.root_ent_cnd() as _
+        } else {
+            self.entries_per_cluster()
+        }
+    }
+
+    /// ディレクトリの `dir_cluster` の次のクラスタを返す。
+    /// FAT12/16 のルートディレクトリの領域は 1 つのクラスタのように扱い、次はない。
+    fn next_dir_cluster(&mut self, dir_cluster: u64) -> Result<u64> {
+        if self.is_root_region(dir_cluster) {
+            Ok(END_OF_CLUSTER_CHAIN)
+        } else {
+            self.next_cluster(dir_cluster)
+        }
+    }
+
+    /// ディレクトリの `cluster` の `index` 番目のエントリの位置を返す。
+    fn entry_location(&self, cluster: u64, index: usize) -> Location {
+        let offset = index * DIR_ENTRY_SIZE;
+        let first_sector = if self.is_root_region(cluster) {
+            self.bpb.root_dir_start()
+        } else {
+            self.cluster_sector(cluster)
+        };
+        Location {
+            sector: first_sector + (offset / self.bytes_per_sector()) as u64,
+            offset: offset % self.bytes_per_sector(),
+        }
+    }
+
+    fn entry_bytes(&mut self, location: Location) -> Result<&[u8]> {
+        let sector = self.cached_sector(location.sector)?;
+        Ok(&sector[location.offset..location.offset + DIR_ENTRY_SIZE])
+    }
+
+    fn entry_bytes_mut(&mut self, location: Location) -> Result<&mut [u8]> {
+        self.dirty.insert(location.sector);
+        let sector = self.cached_sector(location.sector)?;
+        Ok(&mut sector[location.offset..location.offset + DIR_ENTRY_SIZE])
+    }
+
+    /// `location` にあるエントリを、`path` の最後の要素を名前として返す。
+    fn entry_at(&mut self, location: Location, path: &str) -> Result<Entry> {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        Ok(Entry {
+            dir_entry: self.dir_entry(location)?,
+            name: String::from(name),
+            location,
+        })
+    }
+
+    /// `..` エントリに入れる、親ディレクトリのクラスタ番号を返す。
+    fn parent_cluster_value(&self, parent_dir_cluster: u64) -> u64 {
+        // 親がルートディレクトリの場合は 0 を入れる決まり
+        if parent_dir_cluster == self.root_cluster() {
+            0
+        } else {
+            parent_dir_cluster
+        }
+    }
+
+    /// `dir_cluster` のディレクトリが `cluster` のディレクトリ自身か、その祖先であるかを返す。
+    fn is_ancestor(&mut self, dir_cluster: u64, mut cluster: u64) -> Result<bool> {
+        let root_cluster = self.root_cluster();
+        while cluster != 0 && cluster != root_cluster {
+            if cluster == dir_cluster {
+                return Ok(true);
+            }
+            // `..` は各ディレクトリの 2 番目のエントリ
+            cluster = self
+                .dir_entry(self.entry_location(cluster, 1))?
+                .first_cluster() as _;
+        }
+        Ok(false)
+    }
+
+    /// `dir_cluster` から始まるディレクトリが `.` と `..` 以外のエントリを持たないかを返す。
+    fn is_empty_dir(&mut self, dir_cluster: u64) -> Result<bool> {
+        Ok(self
+            .read_dir(dir_cluster)?
+            .iter()
+            .all(|entry| entry.name == "." || entry.name == ".."))
+    }
+
+    /// `path` を親ディレクトリの先頭クラスタと、最後の要素の名前に分ける。
+    fn split_path<'a>(&mut self, path: &'a str) -> Result<(u64, &'a str)> {
+        let Some((parent_dir_name, filename)) = path.rsplit_once('/') else {
+            return Ok((self.root_cluster(), path));
+        };
+        if filename.is_empty() {
+            return Err(Error::IsDirectory);
+        }
+        if parent_dir_name.is_empty() {
+            return Ok((self.root_cluster(), filename));
+        }
+
+        let (Some(parent_dir), _) = self.find_file(parent_dir_name, 0)? else {
+            return Err(Error::NoSuchEntry);
+        };
+        if !parent_dir.is_dir() {
+            return Err(Error::NotDirectory);
+        }
+        Ok((parent_dir.first_cluster(), filename))
+    }
+
+    /// `path` の親ディレクトリに新しい空のエントリを確保し、名前を設定する。
+    /// 確保したエントリの位置と親ディレクトリの先頭クラスタを返す。
+    fn create_entry(&mut self, path: &str) -> Result<(Location, u64)> {
+        let (parent_dir_cluster, filename) = self.split_path(path)?;
+        if self.find_file(path, 0)?.0.is_some() {
+            return Err(Error::FileExists);
+        }
+        let location = self.create_entry_in(parent_dir_cluster, filename)?;
+        Ok((location, parent_dir_cluster))
+    }
+
+    /// `dir_cluster` のディレクトリに `name` という名前の空のエントリを作る。
+    /// `name` が 8.3 形式で表せない場合は、LFN エントリと `~N` の付いた短い名前を作る。
+    fn create_entry_in(&mut self, dir_cluster: u64, name: &str) -> Result<Location> {
+        let name16: Vec<u16> = name.encode_utf16().collect();
+        if name16.len() > LFN_MAX_CHARS {
+            return Err(Error::NameTooLong);
+        }
+
+        let (short_name, num_lfn_entries) = match dir::exact_short_name(name) {
+            Some(short_name) if !self.short_name_exists(dir_cluster, &short_name)? => {
+                (short_name, 0)
+            }
+            _ => (
+                self.generate_short_name(dir_cluster, name)?,
+                name16.len().div_ceil(LFN_CHARS_PER_ENTRY),
+            ),
+        };
+
+        let mut locations = self.allocate_entries(dir_cluster, num_lfn_entries + 1)?;
+        let location = locations.pop().unwrap();
+        // 削除済みのエントリを再利用する場合もあるので、全て初期化しておく
+        let entry = DirectoryEntry {
+            name: short_name,
+            ..Default::default()
+        };
+        self.set_dir_entry(location, &entry)?;
+        self.write_long_name(&locations, &name16, entry.checksum())?;
+
+        Ok(location)
+    }
+
+    /// `locations` に `name16` の LFN エントリを書き込む。
+    /// `locations` は短い名前のエントリの直前に並んでいる、名前に必要な数のエントリの位置。
+    fn write_long_name(
+        &mut self,
+        locations: &[Location],
+        name16: &[u16],
+        checksum: u8,
+    ) -> Result<()> {
+        let num_lfn_entries = locations.len();
+        // LFN エントリは最後の部分から逆順に並べる
+        for (i, &lfn_location) in locations.iter().enumerate() {
+            let ord = (num_lfn_entries - i) as u8;
+            // 名前の後は 0x0000 で終端し、残りは 0xffff で埋める
+            let mut chars = [0xffff; LFN_CHARS_PER_ENTRY];
+            for (j, c) in chars.iter_mut().enumerate() {
+                let index = (ord as usize - 1) * LFN_CHARS_PER_ENTRY + j;
+                match index.cmp(&name16.len()) {
+                    cmp::Ordering::Less => *c = name16[index],
+                    cmp::Ordering::Equal => *c = 0x0000,
+                    cmp::Ordering::Greater => {}
+                }
+            }
+
+            let lfn_entry = LongNameEntry {
+                ord: if i == 0 { ord | LFN_LAST_ENTRY } else { ord },
+                name1: chars[..5].try_into().unwrap(),
+                attr: Attribute::LongName as _,
+                ty: 0,
+                checksum,
+                name2: chars[5..11].try_into().unwrap(),
+                fst_clus_lo: 0,
+                name3: chars[11..].try_into().unwrap(),
+            };
+            lfn_entry.write_to(self.entry_bytes_mut(lfn_location)?);
+        }
+        Ok(())
+    }
+
+    /// `dir_cluster` のディレクトリ内で連続した `n` 個の空きエントリを確保する。
+    /// 足りない場合はディレクトリのクラスタチェーンを伸ばす。
+    fn allocate_entries(&mut self, dir_cluster: u64, n: usize) -> Result<Vec<Location>> {
+        let entries_per_cluster = self.entries_per_cluster();
+
+        let mut run = Vec::with_capacity(n);
+        let mut cluster = dir_cluster;
+        loop {
+            for index in 0..self.dir_entries_in(cluster) {
+                let location = self.entry_location(cluster, index);
+                let first = self.entry_bytes(location)?[0];
+                if first == 0 || first == 0xe5 {
+                    run.push(location);
+                    if run.len() == n {
+                        return Ok(run);
+                    }
+                } else {
+                    run.clear();
+                }
+            }
+
+            cluster = match self.next_dir_cluster(cluster)? {
+                END_OF_CLUSTER_CHAIN => break,
+                clus => clus,
+            };
+        }
+        // FAT12/16 のルートディレクトリは広げられない
+        if self.is_root_region(cluster) {
+            return Err(Error::NoSpace);
+        }
+
+        // 新しいクラスタは全て空きエントリなので、続けて使える
+        while run.len() < n {
+            cluster = self.extend_cluster(cluster, 1)?;
+            self.clear_cluster(cluster)?;
+            for index in 0..cmp::min(entries_per_cluster, n - run.len()) {
+                run.push(self.entry_location(cluster, index));
+            }
+        }
+        Ok(run)
+    }
+
+    /// `dir_cluster` のディレクトリ内の `location` にあるエントリを、LFN エントリと共に削除済みにする。
+    fn remove_entry(&mut self, dir_cluster: u64, location: Location) -> Result<()> {
+        self.remove_long_name(dir_cluster, location)?;
+        self.entry_bytes_mut(location)?[0] = 0xe5;
+        Ok(())
+    }
+
+    /// `dir_cluster` のディレクトリ内の `location` にあるエントリの、LFN エントリを削除済みにする。
+    fn remove_long_name(&mut self, dir_cluster: u64, location: Location) -> Result<()> {
+        for lfn_location in self.long_name_locations(dir_cluster, location)? {
+            self.entry_bytes_mut(lfn_location)?[0] = 0xe5;
+        }
+        Ok(())
+    }
+
+    /// `dir_cluster` のディレクトリ内の `location` にあるエントリの、LFN エントリの位置を並んでいる順に返す。
+    fn long_name_locations(
+        &mut self,
+        dir_cluster: u64,
+        location: Location,
+    ) -> Result<Vec<Location>> {
+        let checksum = self.dir_entry(location)?.checksum();
+
+        // `location` の直前に連続している LFN エントリ
+        let mut lfn_locations = Vec::new();
+        let mut cluster = dir_cluster;
+        while cluster != END_OF_CLUSTER_CHAIN {
+            for index in 0..self.dir_entries_in(cluster) {
+                let l = self.entry_location(cluster, index);
+                if l == location {
+                    return Ok(lfn_locations);
+                }
+
+                let bytes = self.entry_bytes(l)?;
+                if bytes[0] == 0 {
+                    return Ok(Vec::new());
+                } else if bytes[0] != 0xe5
+                    && bytes[11] == Attribute::LongName as u8
+                    && LongNameEntry::from_bytes(bytes).checksum == checksum
+                {
+                    lfn_locations.push(l);
+                } else {
+                    lfn_locations.clear();
+                }
+            }
+            cluster = self.next_dir_cluster(cluster)?;
+        }
+        Ok(Vec::new())
+    }
+
+    /// `name` から、`dir_cluster` のディレクトリ内で重複しない短い名前を作る。
+    /// 変換で情報が失われた場合や重複する場合は `~N` を付ける。
+    fn generate_short_name(&mut self, dir_cluster: u64, name: &str) -> Result<[u8; 11]> {
+        let (mut base, ext, lossy) = dir::short_name_basis(name);
+        if base.is_empty() {
+            base.push(b'_');
+        }
+
+        let basis = dir::to_short_name(&base, &ext);
+        if !lossy && !self.short_name_exists(dir_cluster, &basis)? {
+            return Ok(basis);
+        }
+
+        let existing: BTreeSet<[u8; 11]> = self
+            .read_dir(dir_cluster)?
+            .iter()
+            .map(|entry| entry.dir_entry.name)
+            .collect();
+        let candidate =
+            dir::numbered_short_names(&base, &ext).find(|candidate| !existing.contains(candidate));
+        candidate.ok_or(Error::FileExists)
+    }
+
+    /// `dir_cluster` のディレクトリ内に短い名前が `name` のエントリがあるかを返す。
+    fn short_name_exists(&mut self, dir_cluster: u64, name: &[u8; 11]) -> Result<bool> {
+        Ok(self
+            .read_dir(dir_cluster)?
+            .iter()
+            .any(|entry| entry.dir_entry.name == *name))
+    }
+
+    /// `cluster` を 0 で埋める。
+    /// ディレクトリ用のクラスタなので、デバイスから読まずにキャッシュ上で埋める。
+    fn clear_cluster(&mut self, cluster: u64) -> Result<()> {
+        let first_sector = self.cluster_sector(cluster);
+        for sector in first_sector..first_sector + self.bpb.sec_per_clus() as u64 {
+            self.evict_if_full();
+            self.sectors
+                .insert(sector, vec![0; self.bytes_per_sector()].into_boxed_slice());
+            self.dirty.insert(sector);
+        }
+        Ok(())
+    }
+
+    /// 解放した `cluster` のセクタをキャッシュから捨てる。
+    /// 後でファイルの内容として直接書き込まれたときに、古い内容で上書きしないようにする。
+    fn forget_cluster(&mut self, cluster: u64) {
+        let first_sector = self.cluster_sector(cluster);
+        for sector in first_sector..first_sector + self.bpb.sec_per_clus() as u64 {
+            self.sectors.remove(&sector);
+            self.dirty.remove(&sector);
+        }
+    }
+
+    /// `sector` をキャッシュから返す。キャッシュにない場合はデバイスから読み込む。
+    fn cached_sector(&mut self, sector: u64) -> Result<&mut [u8]> {
+        if !self.sectors.contains_key(&sector) {
+            self.evict_if_full();
+            let mut buf = vec![0; self.bytes_per_sector()].into_boxed_slice();
+            self.read_device(sector, &mut buf)?;
+            self.sectors.insert(sector, buf);
+        }
+        Ok(self.sectors.get_mut(&sector).unwrap())
+    }
+
+    /// キャッシュしているセクタが多すぎる場合は、変更されていないセクタを捨てる。
+    fn evict_if_full(&mut self) {
+        if self.sectors.len() < MAX_CACHED_SECTORS {
+            return;
+        }
+        let clean: Vec<u64> = self
+            .sectors
+            .keys()
+            .filter(|sector| !self.dirty.contains(sector))
+            .take(MAX_CACHED_SECTORS / 4)
+            .copied()
+            .collect();
+        for sector in clean {
+            self.sectors.remove(&sector);
+        }
+    }
+
+    /// `sector` から `buf` の長さ分のセクタを読む。
+    /// キャッシュにあるセクタはその内容を使い、ないセクタはキャッシュに載せずにデバイスから読む。
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        let bytes_per_sector = self.bytes_per_sector();
+        let num_sectors = (buf.len() / bytes_per_sector) as u64;
+
+        let mut i = 0;
+        while i < num_sectors {
+            let off = i as usize * bytes_per_sector;
+            if let Some(cached) = self.sectors.get(&(sector + i)) {
+                buf[off..off + bytes_per_sector].copy_from_slice(cached);
+                i += 1;
+                continue;
+            }
+
+            // 次にキャッシュされているセクタの手前までをまとめて読む
+            let next = self
+                .sectors
+                .range(sector + i..sector + num_sectors)
+                .next()
+                .map_or(num_sectors, |(&s, _)| s - sector);
+            let end = next as usize * bytes_per_sector;
+            self.read_device(sector + i, &mut buf[off..end])?;
+            i = next;
+        }
+        Ok(())
+    }
+
+    /// `sector` から `buf` の長さ分のセクタをデバイスに書き込む。
+    /// キャッシュにあるセクタは、その内容も更新する。
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<()> {
+        let bytes_per_sector = self.bytes_per_sector();
+        self.write_device(sector, buf)?;
+        for (i, data) in buf.chunks(bytes_per_sector).enumerate() {
+            if let Some(cached) = self.sectors.get_mut(&(sector + i as u64)) {
+                cached.copy_from_slice(data);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_device(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        self.device
+            .read(sector * self.device_sectors_per_sector, buf)
+    }
+
+    fn write_device(&mut self, sector: u64, buf: &[u8]) -> Result<()> {
+        self.device
+            .write(sector * self.device_sectors_per_sector, buf)
+    }
+}
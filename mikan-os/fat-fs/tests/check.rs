@@ -0,0 +1,238 @@
+mod common;
+
+use common::*;
+use fat_fs::{Problem, Volume, END_OF_CLUSTER_CHAIN};
+
+/// ファイルをいくつか作って書き戻したボリュームを用意する。
+fn populated_volume() -> (RamDisk, Layout) {
+    let (disk, layout, mut volume) = new_volume();
+    volume.create_dir("/dir").unwrap();
+    write_file(&mut volume, "/dir/a.bin", &[1; 1500]);
+    write_file(&mut volume, "/b.bin", &[2; 600]);
+    write_file(&mut volume, "/long file name.txt", b"c");
+    volume.sync().unwrap();
+    (disk, layout)
+}
+
+fn first_cluster(volume: &mut Volume, path: &str) -> u64 {
+    volume
+        .find_file(path, 0)
+        .unwrap()
+        .0
+        .unwrap()
+        .first_cluster()
+}
+
+/// 両方の FAT の `cluster` のエントリを書き換える。
+fn write_fat(disk: &RamDisk, layout: &Layout, cluster: u64, value: u32) {
+    for n in 0..layout.num_fats {
+        disk.write_u32(layout.fat_offset(n, cluster as _), value);
+    }
+}
+
+/// 検査で `expected` が見つかり、修復して書き戻した後は問題がなくなることを確かめる。
+fn check_and_repair(disk: &RamDisk, expected: &[Problem]) -> Volume {
+    let mut volume = Volume::new(Box::new(disk.clone())).unwrap();
+    assert_eq!(volume.check(false).unwrap(), expected);
+    assert_eq!(volume.check(true).unwrap(), expected);
+    assert_eq!(volume.check(false).unwrap(), []);
+    volume.sync().unwrap();
+
+    let mut volume = Volume::new(Box::new(disk.clone())).unwrap();
+    assert_eq!(volume.check(false).unwrap(), []);
+    volume
+}
+
+#[test]
+fn test_check_clean() {
+    let (disk, _) = populated_volume();
+    let mut volume = Volume::new(Box::new(disk)).unwrap();
+
+    assert_eq!(volume.check(false).unwrap(), []);
+    assert_eq!(volume.check(true).unwrap(), []);
+    assert_eq!(read_file(&mut volume, "/dir/a.bin"), [1; 1500]);
+}
+
+#[test]
+fn test_cross_linked() {
+    let (disk, layout) = populated_volume();
+    let mut volume = Volume::new(Box::new(disk.clone())).unwrap();
+    let b = first_cluster(&mut volume, "/b.bin");
+    let b2 = volume.next_cluster(b).unwrap();
+    let c = first_cluster(&mut volume, "/long file name.txt");
+
+    // 後ろのファイルのチェーンが b.bin の 2 つ目のクラスタにつながっている
+    write_fat(&disk, &layout, c, b2 as _);
+
+    let mut volume = check_and_repair(
+        &disk,
+        &[Problem::CrossLinked {
+            path: "/long file name.txt".into(),
+            cluster: b2,
+        }],
+    );
+    assert_eq!(volume.next_cluster(c).unwrap(), END_OF_CLUSTER_CHAIN);
+    assert_eq!(read_file(&mut volume, "/b.bin"), [2; 600]);
+    assert_eq!(read_file(&mut volume, "/long file name.txt"), b"c");
+}
+
+#[test]
+fn test_lost_chain() {
+    let (disk, layout) = populated_volume();
+    let mut volume = Volume::new(Box::new(disk.clone())).unwrap();
+    let lost = volume.allocate_cluster_chain(3).unwrap();
+    let looped = volume.allocate_cluster_chain(2).unwrap();
+    let looped2 = volume.next_cluster(looped).unwrap();
+    volume.sync().unwrap();
+
+    // 先頭のないループしたチェーンも見つける
+    write_fat(&disk, &layout, looped2, looped as _);
+
+    let mut volume = check_and_repair(
+        &disk,
+        &[
+            Problem::LostChain {
+                first_cluster: lost,
+                clusters: 3,
+            },
+            Problem::LostChain {
+                first_cluster: looped,
+                clusters: 2,
+            },
+        ],
+    );
+    assert_eq!(volume.fat_entry(lost).unwrap(), 0);
+    assert_eq!(volume.fat_entry(looped2).unwrap(), 0);
+    assert_eq!(volume.allocate_cluster_chain(1).unwrap(), lost);
+}
+
+#[test]
+fn test_out_of_range() {
+    let (disk, layout) = populated_volume();
+    let mut volume = Volume::new(Box::new(disk.clone())).unwrap();
+    let a = first_cluster(&mut volume, "/dir/a.bin");
+    let a2 = volume.next_cluster(a).unwrap();
+    let a3 = volume.next_cluster(a2).unwrap();
+    let b = first_cluster(&mut volume, "/b.bin");
+
+    // b.bin の先頭クラスタが予約された値で、a.bin のチェーンはデータ領域の外を指している
+    let entry = volume.find_file("/b.bin", 0).unwrap().0.unwrap();
+    let mut dir_entry = entry.dir_entry;
+    dir_entry.set_first_cluster(1);
+    volume.set_dir_entry(entry.location, &dir_entry).unwrap();
+    volume.sync().unwrap();
+    let invalid = layout.cluster_count as u64 + 2;
+    write_fat(&disk, &layout, a2, invalid as _);
+
+    let mut volume = check_and_repair(
+        &disk,
+        &[
+            Problem::OutOfRange {
+                path: "/b.bin".into(),
+                cluster: 1,
+            },
+            Problem::SizeMismatch {
+                path: "/b.bin".into(),
+                size: 600,
+                clusters: 0,
+            },
+            Problem::OutOfRange {
+                path: "/dir/a.bin".into(),
+                cluster: invalid,
+            },
+            Problem::SizeMismatch {
+                path: "/dir/a.bin".into(),
+                size: 1500,
+                clusters: 2,
+            },
+            Problem::LostChain {
+                first_cluster: a3,
+                clusters: 1,
+            },
+            Problem::LostChain {
+                first_cluster: b,
+                clusters: 2,
+            },
+        ],
+    );
+    let entry = volume.find_file("/b.bin", 0).unwrap().0.unwrap();
+    assert_eq!(entry.first_cluster(), 0);
+    assert_eq!(entry.dir_entry.file_size, 0);
+    assert_eq!(read_file(&mut volume, "/dir/a.bin"), [1; 1024]);
+}
+
+#[test]
+fn test_size_mismatch() {
+    let (disk, _) = populated_volume();
+    let mut volume = Volume::new(Box::new(disk.clone())).unwrap();
+    let a = first_cluster(&mut volume, "/dir/a.bin");
+    let a2 = volume.next_cluster(a).unwrap();
+
+    // a.bin はクラスタが余っていて、b.bin はクラスタが足りない
+    for (path, size) in [("/dir/a.bin", 100), ("/b.bin", 5000)] {
+        let entry = volume.find_file(path, 0).unwrap().0.unwrap();
+        let mut dir_entry = entry.dir_entry;
+        dir_entry.file_size = size;
+        volume.set_dir_entry(entry.location, &dir_entry).unwrap();
+    }
+    volume.sync().unwrap();
+
+    let mut volume = check_and_repair(
+        &disk,
+        &[
+            Problem::SizeMismatch {
+                path: "/b.bin".into(),
+                size: 5000,
+                clusters: 2,
+            },
+            Problem::SizeMismatch {
+                path: "/dir/a.bin".into(),
+                size: 100,
+                clusters: 3,
+            },
+        ],
+    );
+    assert_eq!(read_file(&mut volume, "/dir/a.bin"), [1; 100]);
+    assert_eq!(volume.next_cluster(a).unwrap(), END_OF_CLUSTER_CHAIN);
+    assert_eq!(volume.fat_entry(a2).unwrap(), 0);
+    let entry = volume.find_file("/b.bin", 0).unwrap().0.unwrap();
+    assert_eq!(entry.dir_entry.file_size, 1024);
+}
+
+#[test]
+fn test_bad_name() {
+    let (disk, _) = populated_volume();
+    let mut volume = Volume::new(Box::new(disk)).unwrap();
+
+    let entry = volume.find_file("/b.bin", 0).unwrap().0.unwrap();
+    let mut dir_entry = entry.dir_entry;
+    dir_entry.name = *b"B?N     BIN";
+    volume.set_dir_entry(entry.location, &dir_entry).unwrap();
+
+    let expected = [Problem::BadName {
+        path: "/B?N.BIN".into(),
+        name: *b"B?N     BIN",
+    }];
+    assert_eq!(volume.check(false).unwrap(), expected);
+    // 名前は修復しない
+    assert!(!expected[0].repairable());
+    assert_eq!(volume.check(true).unwrap(), expected);
+}
+
+#[test]
+fn test_hidden_dir() {
+    let (disk, _) = populated_volume();
+    let mut volume = Volume::new(Box::new(disk.clone())).unwrap();
+
+    // "System Volume Information" のように隠し属性とシステム属性も立っているディレクトリ
+    let location = volume.find_file("/dir", 0).unwrap().0.unwrap().location;
+    let mut entry = volume.dir_entry(location).unwrap();
+    entry.attr = 0x16;
+    volume.set_dir_entry(location, &entry).unwrap();
+    volume.sync().unwrap();
+
+    let mut volume = check_and_repair(&disk, &[]);
+    assert!(volume.find_file("/dir", 0).unwrap().0.unwrap().is_dir());
+    assert_eq!(list_dir(&mut volume, "/dir"), ["a.bin"]);
+    assert_eq!(read_file(&mut volume, "/dir/a.bin"), [1; 1500]);
+}
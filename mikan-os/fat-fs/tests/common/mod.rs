@@ -0,0 +1,339 @@
+#![allow(dead_code)]
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use fat_fs::{BlockDevice, FatType, Result, Volume};
+
+/// メモリ上のブロックデバイス。書き込まれていないセクタは 0 として読める。
+/// クローンしたものは同じ内容を共有する。
+#[derive(Clone)]
+pub struct RamDisk {
+    sector_size: usize,
+    sectors: Arc<Mutex<BTreeMap<u64, Vec<u8>>>>,
+}
+
+impl RamDisk {
+    pub fn new(sector_size: usize) -> Self {
+        Self {
+            sector_size,
+            sectors: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// `offset` バイト目から `buf` の長さ分を読む。
+    pub fn read_bytes(&self, offset: usize, buf: &mut [u8]) {
+        let sectors = self.sectors.lock().unwrap();
+        for (i, b) in buf.iter_mut().enumerate() {
+            let pos = offset + i;
+            *b = sectors
+                .get(&((pos / self.sector_size) as u64))
+                .map_or(0, |s| s[pos % self.sector_size]);
+        }
+    }
+
+    /// `offset` バイト目から `data` を書き込む。
+    pub fn write_bytes(&self, offset: usize, data: &[u8]) {
+        let mut sectors = self.sectors.lock().unwrap();
+        for (i, &b) in data.iter().enumerate() {
+            let pos = offset + i;
+            sectors
+                .entry((pos / self.sector_size) as u64)
+                .or_insert_with(|| vec![0; self.sector_size])[pos % self.sector_size] = b;
+        }
+    }
+
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        let mut buf = [0; 4];
+        self.read_bytes(offset, &mut buf);
+        u32::from_le_bytes(buf)
+    }
+
+    pub fn write_u32(&self, offset: usize, value: u32) {
+        self.write_bytes(offset, &value.to_le_bytes());
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        let mut buf = [0; 2];
+        self.read_bytes(offset, &mut buf);
+        u16::from_le_bytes(buf)
+    }
+
+    pub fn write_u16(&self, offset: usize, value: u16) {
+        self.write_bytes(offset, &value.to_le_bytes());
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        assert_eq!(buf.len() % self.sector_size, 0);
+        self.read_bytes(sector as usize * self.sector_size, buf);
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<()> {
+        assert_eq!(buf.len() % self.sector_size, 0);
+        self.write_bytes(sector as usize * self.sector_size, buf);
+        Ok(())
+    }
+}
+
+/// フォーマットしたボリュームの配置。
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    pub fat_type: FatType,
+    pub bytes_per_sector: usize,
+    pub sectors_per_cluster: usize,
+    pub reserved_sectors: usize,
+    pub num_fats: usize,
+    pub fat_sectors: usize,
+    /// FAT12/16 のルートディレクトリ領域のセクタ数
+    pub root_dir_sectors: usize,
+    pub cluster_count: usize,
+}
+
+impl Layout {
+    pub fn bytes_per_cluster(&self) -> usize {
+        self.bytes_per_sector * self.sectors_per_cluster
+    }
+
+    /// `n` 番目の FAT で `cluster` のエントリがあるバイト位置を返す。
+    pub fn fat_offset(&self, n: usize, cluster: u32) -> usize {
+        let cluster = cluster as usize;
+        (self.reserved_sectors + n * self.fat_sectors) * self.bytes_per_sector
+            + match self.fat_type {
+                FatType::Fat12 => cluster * 3 / 2,
+                FatType::Fat16 => cluster * 2,
+                FatType::Fat32 => cluster * 4,
+            }
+    }
+
+    /// `n` 番目の FAT から `cluster` のエントリの値をそのまま読む。
+    pub fn read_fat(&self, disk: &RamDisk, n: usize, cluster: u32) -> u32 {
+        let offset = self.fat_offset(n, cluster);
+        match self.fat_type {
+            FatType::Fat12 if cluster.is_multiple_of(2) => (disk.read_u16(offset) & 0xfff) as u32,
+            FatType::Fat12 => (disk.read_u16(offset) >> 4) as u32,
+            FatType::Fat16 => disk.read_u16(offset) as u32,
+            FatType::Fat32 => disk.read_u32(offset),
+        }
+    }
+
+    /// `n` 番目の FAT の `cluster` のエントリに `value` をそのまま書き込む。
+    pub fn write_fat(&self, disk: &RamDisk, n: usize, cluster: u32, value: u32) {
+        let offset = self.fat_offset(n, cluster);
+        match self.fat_type {
+            FatType::Fat12 => {
+                let old = disk.read_u16(offset);
+                let new = if cluster.is_multiple_of(2) {
+                    (old & 0xf000) | value as u16
+                } else {
+                    (old & 0x000f) | (value as u16) << 4
+                };
+                disk.write_u16(offset, new);
+            }
+            FatType::Fat16 => disk.write_u16(offset, value as _),
+            FatType::Fat32 => disk.write_u32(offset, value),
+        }
+    }
+
+    /// FAT32 の FSInfo セクタの先頭のバイト位置を返す。
+    pub fn fsinfo_offset(&self) -> usize {
+        self.bytes_per_sector
+    }
+
+    /// FAT12/16 のルートディレクトリ領域の先頭のバイト位置を返す。
+    pub fn root_dir_offset(&self) -> usize {
+        (self.reserved_sectors + self.num_fats * self.fat_sectors) * self.bytes_per_sector
+    }
+
+    /// `cluster` の先頭のバイト位置を返す。
+    pub fn cluster_offset(&self, cluster: u32) -> usize {
+        let data_start =
+            self.reserved_sectors + self.num_fats * self.fat_sectors + self.root_dir_sectors;
+        (data_start + (cluster as usize - 2) * self.sectors_per_cluster) * self.bytes_per_sector
+    }
+}
+
+/// FAT32 として有効な最小のクラスタ数
+pub const MIN_FAT32_CLUSTERS: usize = 65525;
+
+/// FSInfo セクタ内の空きクラスタ数の位置
+pub const FSINFO_FREE_COUNT: usize = 488;
+/// FSInfo セクタ内の空きクラスタを探し始める位置のヒントの位置
+pub const FSINFO_NEXT_FREE: usize = 492;
+
+/// FAT12/16 でフォーマットするときのルートディレクトリのエントリ数
+pub const ROOT_ENTRIES: usize = 512;
+
+/// `disk` を `cluster_count` 個のクラスタを持つ FAT32 でフォーマットする。
+/// ルートディレクトリはクラスタ 2 に置く。
+pub fn format(
+    disk: &RamDisk,
+    bytes_per_sector: usize,
+    sectors_per_cluster: usize,
+    cluster_count: usize,
+) -> Layout {
+    format_fat(
+        disk,
+        FatType::Fat32,
+        bytes_per_sector,
+        sectors_per_cluster,
+        cluster_count,
+    )
+}
+
+/// `disk` を `cluster_count` 個のクラスタを持つ `fat_type` の FAT でフォーマットする。
+/// `fat_type` は `cluster_count` から決まる種類と合わせること。
+/// FAT12/16 のルートディレクトリには [ROOT_ENTRIES] 個のエントリを置く。
+pub fn format_fat(
+    disk: &RamDisk,
+    fat_type: FatType,
+    bytes_per_sector: usize,
+    sectors_per_cluster: usize,
+    cluster_count: usize,
+) -> Layout {
+    let (reserved_sectors, root_entries, entry_bits, eoc) = match fat_type {
+        FatType::Fat12 => (1, ROOT_ENTRIES, 12, 0xfff),
+        FatType::Fat16 => (1, ROOT_ENTRIES, 16, 0xffff),
+        FatType::Fat32 => (32, 0, 32, 0x0fff_ffff),
+    };
+    let num_fats = 2;
+    let fat_sectors = ((cluster_count + 2) * entry_bits)
+        .div_ceil(8)
+        .div_ceil(bytes_per_sector);
+    let root_dir_sectors = (root_entries * 32).div_ceil(bytes_per_sector);
+    let layout = Layout {
+        fat_type,
+        bytes_per_sector,
+        sectors_per_cluster,
+        reserved_sectors,
+        num_fats,
+        fat_sectors,
+        root_dir_sectors,
+        cluster_count,
+    };
+    let total_sectors = reserved_sectors
+        + num_fats * fat_sectors
+        + root_dir_sectors
+        + cluster_count * sectors_per_cluster;
+
+    let mut boot = vec![0u8; 512];
+    boot[..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"MIKANFAT");
+    boot[11..13].copy_from_slice(&(bytes_per_sector as u16).to_le_bytes());
+    boot[13] = sectors_per_cluster as u8;
+    boot[14..16].copy_from_slice(&(reserved_sectors as u16).to_le_bytes());
+    boot[16] = num_fats as u8;
+    boot[17..19].copy_from_slice(&(root_entries as u16).to_le_bytes());
+    boot[21] = 0xf8;
+    match fat_type {
+        FatType::Fat32 => {
+            boot[32..36].copy_from_slice(&(total_sectors as u32).to_le_bytes());
+            boot[36..40].copy_from_slice(&(fat_sectors as u32).to_le_bytes());
+            boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+            boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+            boot[50..52].copy_from_slice(&6u16.to_le_bytes());
+            boot[66] = 0x29;
+            boot[71..82].copy_from_slice(b"NO NAME    ");
+            boot[82..90].copy_from_slice(b"FAT32   ");
+        }
+        FatType::Fat12 | FatType::Fat16 => {
+            match u16::try_from(total_sectors) {
+                Ok(total_sectors) => boot[19..21].copy_from_slice(&total_sectors.to_le_bytes()),
+                Err(_) => boot[32..36].copy_from_slice(&(total_sectors as u32).to_le_bytes()),
+            }
+            boot[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes());
+            boot[36] = 0x80;
+            boot[38] = 0x29;
+            boot[43..54].copy_from_slice(b"NO NAME    ");
+            boot[54..62].copy_from_slice(if fat_type == FatType::Fat12 {
+                b"FAT12   "
+            } else {
+                b"FAT16   "
+            });
+        }
+    }
+    boot[510] = 0x55;
+    boot[511] = 0xaa;
+    disk.write_bytes(0, &boot);
+
+    if fat_type == FatType::Fat32 {
+        let fsinfo = layout.fsinfo_offset();
+        disk.write_u32(fsinfo, 0x4161_5252);
+        disk.write_u32(fsinfo + 484, 0x6141_7272);
+        disk.write_u32(fsinfo + FSINFO_FREE_COUNT, cluster_count as u32 - 1);
+        disk.write_u32(fsinfo + FSINFO_NEXT_FREE, 2);
+        disk.write_u32(fsinfo + 508, 0xaa55_0000);
+    }
+
+    // 0 番目のエントリにはメディアの種類、1 番目にはチェーンの終わりを入れておく
+    for n in 0..num_fats {
+        layout.write_fat(disk, n, 0, eoc & !0x7);
+        layout.write_fat(disk, n, 1, eoc);
+        if fat_type == FatType::Fat32 {
+            layout.write_fat(disk, n, 2, eoc);
+        }
+    }
+    layout
+}
+
+/// 512 バイトのセクタ、1 クラスタ 1 セクタでフォーマットしたボリュームを開く。
+pub fn new_volume() -> (RamDisk, Layout, Volume) {
+    let disk = RamDisk::new(512);
+    let layout = format(&disk, 512, 1, MIN_FAT32_CLUSTERS);
+    let volume = Volume::new(Box::new(disk.clone())).unwrap();
+    (disk, layout, volume)
+}
+
+/// `volume` の `path` にあるファイルの内容を全て読む。
+pub fn read_file(volume: &mut Volume, path: &str) -> Vec<u8> {
+    let (entry, _) = volume.find_file(path, 0).unwrap();
+    let entry = entry.unwrap();
+    let bytes_per_cluster = volume.bytes_per_cluster();
+    let size = entry.dir_entry.file_size as usize;
+
+    let mut buf = vec![0; size.div_ceil(bytes_per_cluster) * bytes_per_cluster];
+    volume
+        .read_cluster_chain(entry.first_cluster(), 0, &mut buf)
+        .unwrap();
+    buf.truncate(size);
+    buf
+}
+
+/// `volume` の `path` にファイルを作り、`data` を書き込む。
+pub fn write_file(volume: &mut Volume, path: &str, data: &[u8]) {
+    let entry = volume.create_file(path).unwrap();
+    assert_eq!(
+        volume.write_file(entry.location, 0, data).unwrap(),
+        data.len()
+    );
+}
+
+/// `volume` の `path` にあるディレクトリ内の名前を、`.` と `..` とボリュームラベルを除いて返す。
+pub fn list_dir(volume: &mut Volume, path: &str) -> Vec<String> {
+    let cluster = if path == "/" {
+        volume.root_cluster()
+    } else {
+        volume
+            .find_file(path, 0)
+            .unwrap()
+            .0
+            .unwrap()
+            .first_cluster()
+    };
+    volume
+        .read_dir(cluster)
+        .unwrap()
+        .into_iter()
+        .filter(|entry| !entry.dir_entry.is_volume_label())
+        .map(|entry| entry.name)
+        .filter(|name| name != "." && name != "..")
+        .collect()
+}
@@ -0,0 +1,377 @@
+mod common;
+
+use common::*;
+use fat_fs::{Error, Volume};
+
+/// `name` から LFN エントリを 1 つ作る。`name` は 13 文字以下。
+fn lfn_entry(name: &str, checksum: u8) -> [u8; 32] {
+    let mut chars = [0xffffu16; 13];
+    let name16: Vec<u16> = name.encode_utf16().collect();
+    chars[..name16.len()].copy_from_slice(&name16);
+    if name16.len() < 13 {
+        chars[name16.len()] = 0;
+    }
+
+    let mut entry = [0u8; 32];
+    entry[0] = 0x41;
+    entry[11] = 0x0f;
+    entry[13] = checksum;
+    let offsets = (1..11)
+        .step_by(2)
+        .chain((14..26).step_by(2))
+        .chain((28..32).step_by(2));
+    for (offset, c) in offsets.zip(chars) {
+        entry[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+    }
+    entry
+}
+
+fn short_entry(name: &[u8; 11]) -> [u8; 32] {
+    let mut entry = [0u8; 32];
+    entry[..11].copy_from_slice(name);
+    entry[11] = 0x20;
+    entry
+}
+
+fn checksum(name: &[u8; 11]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+#[test]
+fn test_read_long_name() {
+    let disk = RamDisk::new(512);
+    let layout = format(&disk, 512, 1, MIN_FAT32_CLUSTERS);
+    let root = layout.cluster_offset(2);
+
+    disk.write_bytes(root, &lfn_entry("abc.txt", checksum(b"ABC     TXT")));
+    disk.write_bytes(root + 32, &short_entry(b"ABC     TXT"));
+    // チェックサムが合わない LFN は無視する
+    disk.write_bytes(root + 64, &lfn_entry("wrong", 0));
+    disk.write_bytes(root + 96, &short_entry(b"XYZ        "));
+
+    let mut volume = Volume::new(Box::new(disk)).unwrap();
+    assert_eq!(list_dir(&mut volume, "/"), ["abc.txt", "XYZ"]);
+    assert!(volume.find_file("/ABC.TXT", 0).unwrap().0.is_some());
+    assert!(volume.find_file("/abc.txt", 0).unwrap().0.is_some());
+    assert!(volume.find_file("/wrong", 0).unwrap().0.is_none());
+}
+
+#[test]
+fn test_volume_label() {
+    let disk = RamDisk::new(512);
+    let layout = format(&disk, 512, 1, MIN_FAT32_CLUSTERS);
+    let root = layout.cluster_offset(2);
+
+    let mut label = short_entry(b"MIKAN OS   ");
+    label[11] = 0x08;
+    disk.write_bytes(root, &label);
+    disk.write_bytes(root + 32, &short_entry(b"ABC     TXT"));
+
+    let mut volume = Volume::new(Box::new(disk)).unwrap();
+    let entries = volume.read_dir(volume.root_cluster()).unwrap();
+    assert!(entries[0].dir_entry.is_volume_label());
+    assert!(!entries[1].dir_entry.is_volume_label());
+    assert_eq!(list_dir(&mut volume, "/"), ["ABC.TXT"]);
+}
+
+#[test]
+fn test_long_name() {
+    let (disk, layout, mut volume) = new_volume();
+
+    let long_name = "A file with a rather long name.text";
+    write_file(&mut volume, &format!("/{}", long_name), b"long");
+    write_file(&mut volume, "/lower.txt", b"lower");
+    write_file(&mut volume, "/UPPER.TXT", b"upper");
+    assert_eq!(
+        list_dir(&mut volume, "/"),
+        [long_name, "lower.txt", "UPPER.TXT"]
+    );
+
+    // 大文字小文字を区別せずに、LFN でも短い名前でも見つかる
+    assert_eq!(
+        read_file(&mut volume, "/a file with a rather long name.TEXT"),
+        b"long"
+    );
+    assert_eq!(read_file(&mut volume, "/AFILEW~1.TEX"), b"long");
+    assert_eq!(read_file(&mut volume, "/LOWER.TXT"), b"lower");
+
+    // 8.3 形式で表せる名前には LFN エントリを作らない
+    volume.sync().unwrap();
+    let root = layout.cluster_offset(2);
+    let mut entries = vec![0; 32 * 8];
+    disk.read_bytes(root, &mut entries);
+    let short_names: Vec<&[u8]> = entries
+        .chunks(32)
+        .filter(|e| e[0] != 0 && e[11] != 0x0f)
+        .map(|e| &e[..11])
+        .collect();
+    assert_eq!(
+        short_names,
+        [&b"AFILEW~1TEX"[..], b"LOWER   TXT", b"UPPER   TXT"]
+    );
+    let num_lfn = entries.chunks(32).filter(|e| e[11] == 0x0f).count();
+    assert_eq!(num_lfn, 3 + 1);
+
+    let mut volume = Volume::new(Box::new(disk)).unwrap();
+    assert_eq!(
+        list_dir(&mut volume, "/"),
+        [long_name, "lower.txt", "UPPER.TXT"]
+    );
+}
+
+#[test]
+fn test_short_name_collision() {
+    let (_, _, mut volume) = new_volume();
+
+    for i in 0..3 {
+        write_file(&mut volume, &format!("/long name {}.txt", i), &[i]);
+    }
+    write_file(&mut volume, "/日本語.txt", b"ja");
+
+    let short_names: Vec<String> = volume
+        .read_dir(volume.root_cluster())
+        .unwrap()
+        .iter()
+        .map(|entry| fat_fs::short_name(&entry.dir_entry))
+        .collect();
+    assert_eq!(
+        short_names,
+        ["LONGNA~1.TXT", "LONGNA~2.TXT", "LONGNA~3.TXT", "___~1.TXT"]
+    );
+    for i in 0..3 {
+        assert_eq!(
+            read_file(&mut volume, &format!("/long name {}.txt", i)),
+            [i]
+        );
+    }
+    assert_eq!(read_file(&mut volume, "/日本語.txt"), b"ja");
+}
+
+#[test]
+fn test_name_too_long() {
+    let (_, _, mut volume) = new_volume();
+
+    let name = "a".repeat(255);
+    write_file(&mut volume, &format!("/{}", name), b"a");
+    assert_eq!(list_dir(&mut volume, "/"), [name]);
+
+    let name = "b".repeat(256);
+    assert_eq!(
+        volume.create_file(&format!("/{}", name)).err(),
+        Some(Error::NameTooLong)
+    );
+}
+
+#[test]
+fn test_directory_grows() {
+    let (_, _, mut volume) = new_volume();
+
+    volume.create_dir("/dir").unwrap();
+    let names: Vec<String> = (0..40).map(|i| format!("file number {}", i)).collect();
+    for name in &names {
+        write_file(&mut volume, &format!("/dir/{}", name), name.as_bytes());
+    }
+    assert_eq!(list_dir(&mut volume, "/dir"), names);
+    for name in &names {
+        assert_eq!(
+            read_file(&mut volume, &format!("/dir/{}", name)),
+            name.as_bytes()
+        );
+    }
+
+    // 削除したエントリの場所は再利用される
+    let location = volume
+        .find_file("/dir/file number 3", 0)
+        .unwrap()
+        .0
+        .unwrap()
+        .location;
+    volume.remove_file("/dir/file number 3").unwrap();
+    write_file(&mut volume, "/dir/file number 3", b"again");
+    let entry = volume
+        .find_file("/dir/file number 3", 0)
+        .unwrap()
+        .0
+        .unwrap();
+    assert_eq!(entry.location, location);
+}
+
+#[test]
+fn test_create_remove_dir() {
+    let (_, _, mut volume) = new_volume();
+
+    let a = volume.create_dir("/a").unwrap();
+    let b = volume.create_dir("/a/b/").unwrap();
+    assert!(a.is_dir() && b.is_dir());
+    write_file(&mut volume, "/a/b/f", b"f");
+
+    let dot = volume.find_file("/a/b/.", 0).unwrap().0.unwrap();
+    assert_eq!(dot.first_cluster(), b.first_cluster());
+    let dotdot = volume.find_file("/a/b/..", 0).unwrap().0.unwrap();
+    assert_eq!(dotdot.first_cluster(), a.first_cluster());
+    // 親がルートディレクトリの場合は 0
+    let dotdot = volume.find_file("/a/..", 0).unwrap().0.unwrap();
+    assert_eq!(dotdot.first_cluster(), 0);
+    assert_eq!(read_file(&mut volume, "/a/b/../b/f"), b"f");
+    assert_eq!(
+        volume
+            .find_file("b/f", a.first_cluster())
+            .unwrap()
+            .0
+            .unwrap()
+            .name,
+        "f"
+    );
+
+    assert_eq!(volume.create_dir("/a").err(), Some(Error::FileExists));
+    assert_eq!(
+        volume.remove_dir("/a").err(),
+        Some(Error::DirectoryNotEmpty)
+    );
+    assert_eq!(volume.remove_dir("/a/b/f").err(), Some(Error::NotDirectory));
+    assert_eq!(volume.remove_dir("/c").err(), Some(Error::NoSuchEntry));
+
+    volume.remove_file("/a/b/f").unwrap();
+    volume.remove_dir("/a/b/").unwrap();
+    volume.remove_dir("/a").unwrap();
+    assert!(list_dir(&mut volume, "/").is_empty());
+    assert_eq!(volume.fat_entry(a.first_cluster()).unwrap(), 0);
+    assert_eq!(volume.fat_entry(b.first_cluster()).unwrap(), 0);
+}
+
+#[test]
+fn test_remove_dot_entries() {
+    let (_, _, mut volume) = new_volume();
+
+    let sub = volume.create_dir("/sub").unwrap();
+    let inner = volume.create_dir("/sub/inner").unwrap();
+
+    for path in ["/sub/..", "/sub/inner/.", "/sub/inner/./", "."] {
+        assert_eq!(volume.remove_dir(path).err(), Some(Error::InvalidMove));
+        assert_eq!(volume.remove_file(path).err(), Some(Error::InvalidMove));
+    }
+
+    let dot = volume.find_file("/sub/inner/.", 0).unwrap().0.unwrap();
+    assert_eq!(dot.first_cluster(), inner.first_cluster());
+    let dotdot = volume.find_file("/sub/..", 0).unwrap().0.unwrap();
+    assert_eq!(dotdot.first_cluster(), 0);
+    assert_eq!(list_dir(&mut volume, "/sub"), ["inner"]);
+    assert_ne!(volume.fat_entry(sub.first_cluster()).unwrap(), 0);
+    assert_ne!(volume.fat_entry(inner.first_cluster()).unwrap(), 0);
+    assert!(volume.check(false).unwrap().is_empty());
+}
+
+#[test]
+fn test_rename() {
+    let (_, _, mut volume) = new_volume();
+
+    write_file(&mut volume, "/A.TXT", b"a");
+    let location = volume.find_file("/A.TXT", 0).unwrap().0.unwrap().location;
+
+    // 同じディレクトリ内で 8.3 形式の名前に変える場合は、その場で書き換える
+    volume.rename("/A.TXT", "/B.TXT").unwrap();
+    let entry = volume.find_file("/B.TXT", 0).unwrap().0.unwrap();
+    assert_eq!(entry.location, location);
+
+    volume.rename("/B.TXT", "/long name.txt").unwrap();
+    assert_eq!(list_dir(&mut volume, "/"), ["long name.txt"]);
+    volume.rename("/long name.txt", "/C.TXT").unwrap();
+    assert_eq!(list_dir(&mut volume, "/"), ["C.TXT"]);
+    assert_eq!(read_file(&mut volume, "/C.TXT"), b"a");
+
+    write_file(&mut volume, "/D.TXT", b"d");
+    assert_eq!(
+        volume.rename("/C.TXT", "/D.TXT").err(),
+        Some(Error::FileExists)
+    );
+    assert_eq!(
+        volume.rename("/none", "/E.TXT").err(),
+        Some(Error::NoSuchEntry)
+    );
+    assert_eq!(
+        volume.rename("/C.TXT", "/none/E.TXT").err(),
+        Some(Error::NoSuchEntry)
+    );
+    volume.create_dir("/dir").unwrap();
+    assert_eq!(
+        volume.rename("/dir/.", "/moved").err(),
+        Some(Error::InvalidMove)
+    );
+    assert_eq!(
+        volume.rename("/C.TXT", "/dir/..").err(),
+        Some(Error::InvalidMove)
+    );
+}
+
+#[test]
+fn test_rename_case() {
+    let (_, _, mut volume) = new_volume();
+
+    write_file(&mut volume, "/foo.txt", b"foo");
+    let location = volume.find_file("/foo.txt", 0).unwrap().0.unwrap().location;
+    let short_name = |volume: &mut Volume| {
+        let entries = volume.read_dir(volume.root_cluster()).unwrap();
+        assert_eq!(entries.len(), 1);
+        fat_fs::short_name(&entries[0].dir_entry)
+    };
+
+    volume.rename("/foo.txt", "/foo.txt").unwrap();
+    assert_eq!(list_dir(&mut volume, "/"), ["foo.txt"]);
+
+    // LFN エントリの数が変わらなければ、その場で書き換える
+    volume.rename("/foo.txt", "/Foo.txt").unwrap();
+    assert_eq!(list_dir(&mut volume, "/"), ["Foo.txt"]);
+    let entry = volume.find_file("/Foo.txt", 0).unwrap().0.unwrap();
+    assert_eq!(entry.location, location);
+
+    volume.rename("/Foo.txt", "/FOO.TXT").unwrap();
+    assert_eq!(list_dir(&mut volume, "/"), ["FOO.TXT"]);
+    let entry = volume.find_file("/FOO.TXT", 0).unwrap().0.unwrap();
+    assert_eq!(entry.location, location);
+
+    // LFN エントリが必要になる場合も、短い名前は変わらない
+    volume.rename("/FOO.TXT", "/foo.TXT").unwrap();
+    assert_eq!(list_dir(&mut volume, "/"), ["foo.TXT"]);
+    assert_eq!(short_name(&mut volume), "FOO.TXT");
+    assert_eq!(read_file(&mut volume, "/foo.TXT"), b"foo");
+
+    write_file(&mut volume, "/long name.txt", b"long");
+    volume.rename("/long name.txt", "/Long Name.TXT").unwrap();
+    assert_eq!(list_dir(&mut volume, "/"), ["Long Name.TXT", "foo.TXT"]);
+    assert_eq!(read_file(&mut volume, "/long name.txt"), b"long");
+    assert!(volume.check(false).unwrap().is_empty());
+}
+
+#[test]
+fn test_rename_dir() {
+    let (_, _, mut volume) = new_volume();
+
+    let d1 = volume.create_dir("/d1").unwrap();
+    let d2 = volume.create_dir("/d2").unwrap();
+    volume.create_dir("/d1/sub").unwrap();
+    write_file(&mut volume, "/d1/f.txt", b"f");
+
+    assert_eq!(
+        volume.rename("/d1", "/d1/sub/d1").err(),
+        Some(Error::InvalidMove)
+    );
+    assert_eq!(
+        volume.rename("/d1", "/d1/x").err(),
+        Some(Error::InvalidMove)
+    );
+
+    volume.rename("/d1", "/d2/moved dir").unwrap();
+    assert_eq!(list_dir(&mut volume, "/"), ["d2"]);
+    assert_eq!(list_dir(&mut volume, "/d2/moved dir"), ["sub", "f.txt"]);
+    assert_eq!(read_file(&mut volume, "/d2/moved dir/f.txt"), b"f");
+
+    let dotdot = volume.find_file("/d2/moved dir/..", 0).unwrap().0.unwrap();
+    assert_eq!(dotdot.first_cluster(), d2.first_cluster());
+    let moved = volume.find_file("/d2/moved dir", 0).unwrap().0.unwrap();
+    assert_eq!(moved.first_cluster(), d1.first_cluster());
+
+    volume.rename("/d2/moved dir", "/back").unwrap();
+    let dotdot = volume.find_file("/back/..", 0).unwrap().0.unwrap();
+    assert_eq!(dotdot.first_cluster(), 0);
+}
@@ -0,0 +1,183 @@
+mod common;
+
+use common::*;
+use fat_fs::{Error, FatType, Problem, Volume, END_OF_CLUSTER_CHAIN};
+
+/// `fat_type` でフォーマットしたボリュームを開く。
+fn new_volume_of(fat_type: FatType, cluster_count: usize) -> (RamDisk, Layout, Volume) {
+    let disk = RamDisk::new(512);
+    let layout = format_fat(&disk, fat_type, 512, 1, cluster_count);
+    let volume = Volume::new(Box::new(disk.clone())).unwrap();
+    (disk, layout, volume)
+}
+
+/// ファイルとディレクトリを作って読み書きし、書き戻した後も同じ内容が読めることを確かめる。
+fn check_read_write(fat_type: FatType, cluster_count: usize) {
+    let (disk, layout, mut volume) = new_volume_of(fat_type, cluster_count);
+    assert_eq!(volume.fat_type(), fat_type);
+
+    // FAT12 ではセクタの境界をまたぐエントリも使われるだけのクラスタを使う
+    let data: Vec<u8> = (0..400 * 512).map(|i| (i % 251) as u8).collect();
+    write_file(&mut volume, "/big.bin", &data);
+    volume.create_dir("/dir").unwrap();
+    write_file(&mut volume, "/dir/a long file name.txt", b"hello");
+    let entry = volume.find_file("/big.bin", 0).unwrap().0.unwrap();
+    assert_eq!(
+        volume
+            .write_file(entry.location, 1000, b"overwrite")
+            .unwrap(),
+        9
+    );
+    volume.sync().unwrap();
+
+    let mut expected = data.clone();
+    expected[1000..1009].copy_from_slice(b"overwrite");
+    let mut volume = Volume::new(Box::new(disk.clone())).unwrap();
+    assert_eq!(list_dir(&mut volume, "/"), ["big.bin", "dir"]);
+    assert_eq!(list_dir(&mut volume, "/dir"), ["a long file name.txt"]);
+    assert_eq!(read_file(&mut volume, "/big.bin"), expected);
+    assert_eq!(read_file(&mut volume, "/dir/../big.bin"), expected);
+    assert_eq!(
+        read_file(&mut volume, "/dir/a long file name.txt"),
+        b"hello"
+    );
+
+    // デバイス上の FAT をたどっても同じチェーンになっている
+    let first_cluster = volume
+        .find_file("/big.bin", 0)
+        .unwrap()
+        .0
+        .unwrap()
+        .first_cluster();
+    let mut cluster = first_cluster;
+    let mut clusters = 0;
+    while cluster != END_OF_CLUSTER_CHAIN {
+        let next = volume.next_cluster(cluster).unwrap();
+        for n in 0..layout.num_fats {
+            let raw = layout.read_fat(&disk, n, cluster as _);
+            // チェーンの終わりは予約された 1 番目のエントリと同じ値
+            match next {
+                END_OF_CLUSTER_CHAIN => assert_eq!(raw, layout.read_fat(&disk, n, 1)),
+                next => assert_eq!(raw as u64, next),
+            }
+        }
+        clusters += 1;
+        cluster = next;
+    }
+    assert_eq!(clusters, 400);
+    assert_eq!(volume.check(false).unwrap(), []);
+
+    let lost = volume.allocate_cluster_chain(2).unwrap();
+    assert_eq!(
+        volume.check(true).unwrap(),
+        [Problem::LostChain {
+            first_cluster: lost,
+            clusters: 2,
+        }]
+    );
+    volume.remove_file("/big.bin").unwrap();
+    volume.sync().unwrap();
+
+    let mut volume = Volume::new(Box::new(disk)).unwrap();
+    assert_eq!(list_dir(&mut volume, "/"), ["dir"]);
+    assert_eq!(volume.check(false).unwrap(), []);
+    assert_eq!(volume.allocate_cluster_chain(1).unwrap(), first_cluster);
+}
+
+#[test]
+fn test_detect_fat_type() {
+    for (cluster_count, fat_type) in [
+        (4084, FatType::Fat12),
+        (4085, FatType::Fat16),
+        (65524, FatType::Fat16),
+        (65525, FatType::Fat32),
+    ] {
+        let (_, _, volume) = new_volume_of(fat_type, cluster_count);
+        assert_eq!(volume.fat_type(), fat_type);
+        assert_eq!(volume.cluster_count(), cluster_count as u64);
+    }
+
+    let (_, _, volume) = new_volume_of(FatType::Fat16, 10000);
+    assert_eq!(volume.root_cluster(), 0);
+}
+
+#[test]
+fn test_reject_invalid_fat16() {
+    let disk = RamDisk::new(512);
+    format_fat(&disk, FatType::Fat16, 512, 1, 10000);
+    assert!(Volume::new(Box::new(disk.clone())).is_ok());
+
+    // ルートディレクトリ領域がない
+    disk.write_bytes(17, &0u16.to_le_bytes());
+    assert_eq!(
+        Volume::new(Box::new(disk.clone())).err(),
+        Some(Error::InvalidFormat)
+    );
+    disk.write_bytes(17, &(ROOT_ENTRIES as u16).to_le_bytes());
+
+    // FAT が全てのクラスタのエントリを持てない
+    disk.write_bytes(22, &10u16.to_le_bytes());
+    assert_eq!(
+        Volume::new(Box::new(disk)).err(),
+        Some(Error::InvalidFormat)
+    );
+}
+
+#[test]
+fn test_fat12_read_write() {
+    check_read_write(FatType::Fat12, 4000);
+}
+
+#[test]
+fn test_fat16_read_write() {
+    check_read_write(FatType::Fat16, 20000);
+}
+
+#[test]
+fn test_fat32_read_write() {
+    check_read_write(FatType::Fat32, MIN_FAT32_CLUSTERS);
+}
+
+#[test]
+fn test_fat12_entries() {
+    let (disk, layout, mut volume) = new_volume_of(FatType::Fat12, 4000);
+
+    let first = volume.allocate_cluster_chain(2).unwrap();
+    assert_eq!(first, 2);
+    volume.sync().unwrap();
+    // クラスタ 2 と 3 のエントリは 3 バイトに詰められている
+    let mut bytes = [0; 3];
+    disk.read_bytes(layout.fat_offset(0, 2), &mut bytes);
+    assert_eq!(bytes, [0x03, 0xf0, 0xff]);
+
+    // 予約された値と不良クラスタの値も FAT32 の値にそろえて返す
+    layout.write_fat(&disk, 0, 4, 0xff7);
+    layout.write_fat(&disk, 0, 5, 0xff8);
+    let mut volume = Volume::new(Box::new(disk)).unwrap();
+    assert_eq!(volume.fat_entry(3).unwrap(), END_OF_CLUSTER_CHAIN as u32);
+    assert_eq!(volume.fat_entry(4).unwrap(), 0x0fff_fff7);
+    assert_eq!(volume.fat_entry(5).unwrap(), END_OF_CLUSTER_CHAIN as u32);
+    assert_eq!(volume.allocate_cluster_chain(1).unwrap(), 6);
+}
+
+#[test]
+fn test_fixed_root_dir_full() {
+    let (_, _, mut volume) = new_volume_of(FatType::Fat16, 10000);
+
+    for i in 0..ROOT_ENTRIES {
+        volume.create_file(&format!("/F{}", i)).unwrap();
+    }
+    // ルートディレクトリ領域は広げられない
+    assert_eq!(volume.create_file("/NEW").err(), Some(Error::NoSpace));
+    assert_eq!(volume.create_dir("/DIR").err(), Some(Error::NoSpace));
+    assert!(volume.find_file("/NEW", 0).unwrap().0.is_none());
+
+    // 削除したエントリは再利用できる
+    volume.remove_file("/F0").unwrap();
+    volume.create_dir("/DIR").unwrap();
+    // サブディレクトリはクラスタを足して広げられる
+    for i in 0..100 {
+        volume.create_file(&format!("/DIR/F{}", i)).unwrap();
+    }
+    assert_eq!(list_dir(&mut volume, "/DIR").len(), 100);
+}
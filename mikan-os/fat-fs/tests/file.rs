@@ -0,0 +1,188 @@
+mod common;
+
+use common::*;
+use fat_fs::{Error, Volume, END_OF_CLUSTER_CHAIN};
+
+#[test]
+fn test_write_read() {
+    let (_, _, mut volume) = new_volume();
+
+    let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+    write_file(&mut volume, "/data.bin", &data);
+    assert_eq!(read_file(&mut volume, "/data.bin"), data);
+
+    let entry = volume.find_file("/data.bin", 0).unwrap().0.unwrap();
+    assert_eq!(entry.dir_entry.file_size, 3000);
+
+    let mut cluster = entry.first_cluster();
+    let mut chain = vec![cluster];
+    loop {
+        cluster = volume.next_cluster(cluster).unwrap();
+        if cluster == END_OF_CLUSTER_CHAIN {
+            break;
+        }
+        chain.push(cluster);
+    }
+    assert_eq!(chain, [3, 4, 5, 6, 7, 8]);
+
+    let mut buf = vec![0; 1024];
+    assert_eq!(
+        volume
+            .read_cluster_chain(entry.first_cluster(), 2048, &mut buf)
+            .unwrap(),
+        1024
+    );
+    assert_eq!(buf[..952], data[2048..]);
+}
+
+#[test]
+fn test_partial_write() {
+    let (_, _, mut volume) = new_volume();
+
+    let mut data = vec![b'a'; 1000];
+    write_file(&mut volume, "/a.txt", &data);
+    let location = volume.find_file("/a.txt", 0).unwrap().0.unwrap().location;
+
+    // セクタをまたいで途中を上書きする
+    assert_eq!(volume.write_file(location, 500, &[b'b'; 30]).unwrap(), 30);
+    data[500..530].fill(b'b');
+    assert_eq!(read_file(&mut volume, "/a.txt"), data);
+
+    // 終わりに追記する
+    assert_eq!(
+        volume.write_file(location, 1000, &[b'c'; 100]).unwrap(),
+        100
+    );
+    data.extend_from_slice(&[b'c'; 100]);
+    assert_eq!(read_file(&mut volume, "/a.txt"), data);
+
+    assert_eq!(
+        volume.write_file(location, 1200, b"x"),
+        Err(Error::OutOfRange)
+    );
+    assert_eq!(volume.write_file(location, 1100, b"").unwrap(), 0);
+    assert_eq!(volume.dir_entry(location).unwrap().file_size, 1100);
+}
+
+#[test]
+fn test_no_space() {
+    let disk = RamDisk::new(512);
+    let layout = format(&disk, 512, 1, MIN_FAT32_CLUSTERS);
+    // 最後の 2 クラスタだけを空きにする
+    for cluster in 3..layout.cluster_count as u32 {
+        disk.write_u32(layout.fat_offset(0, cluster), 0x0fff_fff7);
+    }
+    let mut volume = Volume::new(Box::new(disk)).unwrap();
+
+    let location = volume.create_file("/a.bin").unwrap().location;
+    assert_eq!(volume.write_file(location, 0, &[1; 1536]).unwrap(), 1024);
+    assert_eq!(volume.dir_entry(location).unwrap().file_size, 1024);
+    assert_eq!(
+        volume.write_file(location, 1024, &[1; 10]),
+        Err(Error::NoSpace)
+    );
+    assert_eq!(volume.create_dir("/dir").err(), Some(Error::NoSpace));
+    assert!(volume.find_file("/dir", 0).unwrap().0.is_none());
+
+    volume.remove_file("/a.bin").unwrap();
+    volume.create_dir("/dir").unwrap();
+}
+
+#[test]
+fn test_remove_file() {
+    let (_, _, mut volume) = new_volume();
+
+    write_file(&mut volume, "/a.bin", &[1; 2000]);
+    let entry = volume.find_file("/a.bin", 0).unwrap().0.unwrap();
+    assert_eq!(entry.first_cluster(), 3);
+
+    let removed = volume.remove_file("/a.bin").unwrap();
+    assert_eq!(removed.location, entry.location);
+    for cluster in 3..7 {
+        assert_eq!(volume.fat_entry(cluster).unwrap(), 0);
+    }
+    assert!(volume.find_file("/a.bin", 0).unwrap().0.is_none());
+    assert_eq!(volume.remove_file("/a.bin").err(), Some(Error::NoSuchEntry));
+    // 削除したエントリを通して書き込んだり切り詰めたりはできない
+    assert_eq!(
+        volume.write_file(entry.location, 0, b"XX").err(),
+        Some(Error::NoSuchEntry)
+    );
+    assert_eq!(
+        volume.truncate_file(entry.location, 0).err(),
+        Some(Error::NoSuchEntry)
+    );
+
+    write_file(&mut volume, "/b.bin", &[2; 10]);
+    let entry = volume.find_file("/b.bin", 0).unwrap().0.unwrap();
+    assert_eq!(entry.first_cluster(), 3);
+    assert_eq!(
+        volume.remove_file("/b.bin/").err(),
+        Some(Error::NoSuchEntry)
+    );
+}
+
+#[test]
+fn test_create_errors() {
+    let (_, _, mut volume) = new_volume();
+
+    write_file(&mut volume, "/a.txt", b"a");
+    assert_eq!(volume.create_file("/a.txt").err(), Some(Error::FileExists));
+    assert_eq!(volume.create_file("/A.TXT").err(), Some(Error::FileExists));
+    assert_eq!(
+        volume.create_file("/a.txt/b").err(),
+        Some(Error::NotDirectory)
+    );
+    assert_eq!(
+        volume.create_file("/none/b").err(),
+        Some(Error::NoSuchEntry)
+    );
+
+    volume.create_dir("/dir").unwrap();
+    assert_eq!(volume.create_file("/dir/").err(), Some(Error::IsDirectory));
+    assert_eq!(volume.create_file("/x/").err(), Some(Error::IsDirectory));
+}
+
+#[test]
+fn test_truncate_file() {
+    let (_, layout, mut volume) = new_volume();
+    let cluster_count = layout.cluster_count as u64;
+
+    let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+    write_file(&mut volume, "/data.bin", &data);
+    let location = volume
+        .find_file("/data.bin", 0)
+        .unwrap()
+        .0
+        .unwrap()
+        .location;
+    assert_eq!(volume.free_clusters().unwrap(), cluster_count - 7);
+
+    // クラスタの途中で切り詰めると、そのクラスタまでが残る
+    volume.truncate_file(location, 1000).unwrap();
+    assert_eq!(read_file(&mut volume, "/data.bin"), data[..1000]);
+    assert_eq!(volume.next_cluster(4).unwrap(), END_OF_CLUSTER_CHAIN);
+    for cluster in 5..9 {
+        assert_eq!(volume.fat_entry(cluster).unwrap(), 0);
+    }
+    assert_eq!(volume.free_clusters().unwrap(), cluster_count - 3);
+    assert_eq!(volume.truncate_file(location, 1001), Err(Error::OutOfRange));
+
+    // 空にするとクラスタチェーンがなくなる
+    volume.truncate_file(location, 0).unwrap();
+    let entry = volume.dir_entry(location).unwrap();
+    assert_eq!((entry.file_size, entry.first_cluster()), (0, 0));
+    assert_eq!(volume.free_clusters().unwrap(), cluster_count - 1);
+    volume.truncate_file(location, 0).unwrap();
+    assert_eq!(volume.check(false).unwrap(), []);
+
+    // 切り詰めた後も書き込める
+    assert_eq!(volume.write_file(location, 0, b"abc").unwrap(), 3);
+    assert_eq!(read_file(&mut volume, "/data.bin"), b"abc");
+
+    let dir = volume.create_dir("/dir").unwrap();
+    assert_eq!(
+        volume.truncate_file(dir.location, 0),
+        Err(Error::IsDirectory)
+    );
+}
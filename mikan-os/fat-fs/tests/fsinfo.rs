@@ -0,0 +1,120 @@
+mod common;
+
+use common::*;
+use fat_fs::{FatType, Problem, Volume};
+
+fn fsinfo(disk: &RamDisk, layout: &Layout) -> (u32, u32) {
+    let offset = layout.fsinfo_offset();
+    (
+        disk.read_u32(offset + FSINFO_FREE_COUNT),
+        disk.read_u32(offset + FSINFO_NEXT_FREE),
+    )
+}
+
+#[test]
+fn test_fsinfo_updated() {
+    let (disk, layout, mut volume) = new_volume();
+    let cluster_count = layout.cluster_count as u64;
+    assert_eq!(volume.free_clusters().unwrap(), cluster_count - 1);
+
+    write_file(&mut volume, "/a.bin", &[1; 1500]);
+    volume.create_dir("/dir").unwrap();
+    assert_eq!(volume.free_clusters().unwrap(), cluster_count - 5);
+
+    // 書き戻すまでは FSInfo は変わらない
+    assert_eq!(fsinfo(&disk, &layout), (cluster_count as u32 - 1, 2));
+    volume.sync().unwrap();
+    assert_eq!(fsinfo(&disk, &layout), (cluster_count as u32 - 5, 7));
+
+    // 解放したクラスタから再び使う
+    volume.remove_file("/a.bin").unwrap();
+    volume.sync().unwrap();
+    assert_eq!(fsinfo(&disk, &layout), (cluster_count as u32 - 2, 3));
+    assert_eq!(volume.allocate_cluster_chain(1).unwrap(), 3);
+
+    let mut volume = Volume::new(Box::new(disk)).unwrap();
+    assert_eq!(volume.free_clusters().unwrap(), cluster_count - 2);
+    assert_eq!(volume.check(false).unwrap(), []);
+}
+
+#[test]
+fn test_next_free_hint() {
+    let (disk, layout, _) = new_volume();
+    let last_cluster = layout.cluster_count as u32 + 1;
+
+    // ヒントの位置から探し、最後まで行ったら先頭に戻る
+    disk.write_u32(layout.fsinfo_offset() + FSINFO_NEXT_FREE, last_cluster);
+    let mut volume = Volume::new(Box::new(disk.clone())).unwrap();
+    let first = volume.allocate_cluster_chain(2).unwrap();
+    assert_eq!(first, last_cluster as u64);
+    assert_eq!(volume.next_cluster(first).unwrap(), 3);
+
+    // 範囲外のヒントは使わない
+    disk.write_u32(layout.fsinfo_offset() + FSINFO_NEXT_FREE, last_cluster + 1);
+    let mut volume = Volume::new(Box::new(disk)).unwrap();
+    assert_eq!(volume.allocate_cluster_chain(1).unwrap(), 3);
+}
+
+#[test]
+fn test_invalid_fsinfo() {
+    let (disk, layout, _) = new_volume();
+    let cluster_count = layout.cluster_count as u64;
+
+    // 範囲外の空きクラスタ数は使わずに数える
+    disk.write_u32(
+        layout.fsinfo_offset() + FSINFO_FREE_COUNT,
+        cluster_count as u32 + 1,
+    );
+    let mut volume = Volume::new(Box::new(disk.clone())).unwrap();
+    assert_eq!(volume.free_clusters().unwrap(), cluster_count - 1);
+
+    // シグネチャが正しくない FSInfo は読み書きしない
+    disk.write_u32(layout.fsinfo_offset(), 0);
+    disk.write_u32(layout.fsinfo_offset() + FSINFO_FREE_COUNT, 0);
+    let mut volume = Volume::new(Box::new(disk.clone())).unwrap();
+    assert_eq!(volume.free_clusters().unwrap(), cluster_count - 1);
+    write_file(&mut volume, "/a.txt", b"a");
+    assert_eq!(volume.free_clusters().unwrap(), cluster_count - 2);
+    volume.sync().unwrap();
+    assert_eq!(fsinfo(&disk, &layout), (0, 2));
+}
+
+#[test]
+fn test_stale_free_count() {
+    let (disk, layout, mut volume) = new_volume();
+    let cluster_count = layout.cluster_count as u64;
+    write_file(&mut volume, "/a.bin", &[1; 1500]);
+    volume.sync().unwrap();
+
+    // 他の OS が FSInfo を更新せずに書き込んだ
+    disk.write_u32(layout.fsinfo_offset() + FSINFO_FREE_COUNT, 100);
+    let mut volume = Volume::new(Box::new(disk.clone())).unwrap();
+    assert_eq!(volume.free_clusters().unwrap(), 100);
+
+    let expected = [Problem::FreeCount {
+        recorded: 100,
+        actual: cluster_count - 4,
+    }];
+    assert_eq!(volume.check(false).unwrap(), expected);
+    assert_eq!(volume.check(true).unwrap(), expected);
+    assert_eq!(volume.check(false).unwrap(), []);
+    assert_eq!(volume.free_clusters().unwrap(), cluster_count - 4);
+    volume.sync().unwrap();
+    assert_eq!(fsinfo(&disk, &layout).0, cluster_count as u32 - 4);
+}
+
+#[test]
+fn test_free_clusters_fat16() {
+    let disk = RamDisk::new(512);
+    format_fat(&disk, FatType::Fat16, 512, 1, 10000);
+    let mut volume = Volume::new(Box::new(disk.clone())).unwrap();
+
+    // FSInfo がないので FAT から数える
+    assert_eq!(volume.free_clusters().unwrap(), 10000);
+    write_file(&mut volume, "/a.bin", &[1; 1500]);
+    assert_eq!(volume.free_clusters().unwrap(), 9997);
+    volume.sync().unwrap();
+
+    let mut volume = Volume::new(Box::new(disk)).unwrap();
+    assert_eq!(volume.free_clusters().unwrap(), 9997);
+}
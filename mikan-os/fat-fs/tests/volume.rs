@@ -0,0 +1,108 @@
+mod common;
+
+use common::*;
+use fat_fs::{Error, Volume};
+
+#[test]
+fn test_mount() {
+    let (_, layout, mut volume) = new_volume();
+
+    assert_eq!(volume.root_cluster(), 2);
+    assert_eq!(volume.bytes_per_sector(), 512);
+    assert_eq!(volume.bytes_per_cluster(), 512);
+    assert_eq!(volume.cluster_count(), layout.cluster_count as u64);
+    assert!(list_dir(&mut volume, "/").is_empty());
+}
+
+#[test]
+fn test_mount_large_sector() {
+    let disk = RamDisk::new(512);
+    let layout = format(&disk, 4096, 2, MIN_FAT32_CLUSTERS);
+    let mut volume = Volume::new(Box::new(disk.clone())).unwrap();
+    assert_eq!(volume.bytes_per_cluster(), layout.bytes_per_cluster());
+
+    let data: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+    write_file(&mut volume, "/big.bin", &data);
+    assert_eq!(read_file(&mut volume, "/big.bin"), data);
+
+    volume.sync().unwrap();
+    let mut volume = Volume::new(Box::new(disk)).unwrap();
+    assert_eq!(read_file(&mut volume, "/big.bin"), data);
+}
+
+#[test]
+fn test_reject_invalid() {
+    let disk = RamDisk::new(512);
+    assert_eq!(
+        Volume::new(Box::new(disk.clone())).err(),
+        Some(Error::InvalidFormat)
+    );
+
+    format(&disk, 512, 1, MIN_FAT32_CLUSTERS);
+    // FAT32 のクラスタ数なのに FAT12/16 の FAT のサイズが設定されている
+    disk.write_bytes(22, &1u16.to_le_bytes());
+    assert_eq!(
+        Volume::new(Box::new(disk.clone())).err(),
+        Some(Error::InvalidFormat)
+    );
+    disk.write_bytes(22, &0u16.to_le_bytes());
+
+    disk.write_bytes(13, &[3]);
+    assert_eq!(
+        Volume::new(Box::new(disk.clone())).err(),
+        Some(Error::InvalidFormat)
+    );
+    disk.write_bytes(13, &[1]);
+    assert!(Volume::new(Box::new(disk.clone())).is_ok());
+
+    // ボリュームのセクタがデバイスのセクタより小さい
+    let disk = RamDisk::new(4096);
+    format(&disk, 512, 8, MIN_FAT32_CLUSTERS);
+    assert_eq!(
+        Volume::new(Box::new(disk)).err(),
+        Some(Error::InvalidFormat)
+    );
+}
+
+#[test]
+fn test_sync() {
+    let (disk, layout, mut volume) = new_volume();
+
+    write_file(&mut volume, "/a.txt", b"hello");
+    volume.create_dir("/dir").unwrap();
+    write_file(&mut volume, "/dir/b.txt", &[0xab; 1500]);
+
+    // 書き戻すまではディレクトリも FAT もデバイスに反映されない
+    assert_eq!(disk.read_u32(layout.fat_offset(0, 3)), 0);
+    assert!(Volume::new(Box::new(disk.clone()))
+        .unwrap()
+        .find_file("/a.txt", 0)
+        .unwrap()
+        .0
+        .is_none());
+
+    volume.sync().unwrap();
+    for cluster in 2..10 {
+        assert_eq!(
+            disk.read_u32(layout.fat_offset(0, cluster)),
+            disk.read_u32(layout.fat_offset(1, cluster))
+        );
+    }
+    assert_eq!(disk.read_u32(layout.fat_offset(0, 3)), 0x0fff_ffff);
+
+    let mut volume = Volume::new(Box::new(disk)).unwrap();
+    assert_eq!(read_file(&mut volume, "/a.txt"), b"hello");
+    assert_eq!(read_file(&mut volume, "/dir/b.txt"), [0xab; 1500]);
+    assert_eq!(list_dir(&mut volume, "/"), ["a.txt", "dir"]);
+}
+
+#[test]
+fn test_fat_reserved_bits() {
+    let (disk, layout, mut volume) = new_volume();
+
+    disk.write_u32(layout.fat_offset(0, 3), 0xf000_0000);
+    write_file(&mut volume, "/a.txt", b"a");
+    volume.sync().unwrap();
+
+    assert_eq!(disk.read_u32(layout.fat_offset(0, 3)), 0xffff_ffff);
+}
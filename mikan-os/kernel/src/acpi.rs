@@ -216,6 +216,47 @@ pub fn wait_milli_seconds(msec: u64) {
     while asmfunc::io_in_32(pm_tmr_blk) < end {}
 }
 
+/// PM タイマーで計る待ち時間の期限。
+/// デバイスの状態をポーリングしながら待つときに、待ち続けないように使う。
+pub struct Deadline {
+    pm_tmr_blk: u16,
+    /// カウンタの有効なビット
+    mask: u32,
+    /// 最後に読んだカウンタの値
+    last: u32,
+    /// 期限までの残りのカウント
+    remaining: u64,
+}
+
+impl Deadline {
+    /// 今から `msec` ミリ秒後を期限にする。
+    pub fn after_milli_seconds(msec: u64) -> Self {
+        let fadt = FADT.get();
+        let mask = if fadt.flags().get_bit(8) {
+            u32::MAX
+        } else {
+            0x00ff_ffff
+        };
+        let pm_tmr_blk = fadt.pm_tmr_blk() as u16;
+        Self {
+            pm_tmr_blk,
+            mask,
+            last: asmfunc::io_in_32(pm_tmr_blk) & mask,
+            remaining: PM_TIMER_FREQ as u64 * msec / 1000,
+        }
+    }
+
+    /// 期限を過ぎていれば true を返す。
+    /// カウンタが一周する前に呼び続ければ、何周しても正しく計れる。
+    pub fn expired(&mut self) -> bool {
+        let now = asmfunc::io_in_32(self.pm_tmr_blk) & self.mask;
+        let elapsed = now.wrapping_sub(self.last) & self.mask;
+        self.last = now;
+        self.remaining = self.remaining.saturating_sub(elapsed as u64);
+        self.remaining == 0
+    }
+}
+
 fn sum_bytes<T>(data: &T, bytes: usize) -> u8 {
     sum_bytes_u8(data as *const _ as *const u8, bytes)
 }
@@ -0,0 +1,435 @@
+//! AHCI（SATA）のデバイスドライバ。
+//!
+//! 接続されている SATA ドライブごとにブロックデバイスを登録する。
+//! コマンドはコマンドスロット 0 だけを使って 1 つずつ発行し、完了はポーリングで待つ。
+//! 待ち時間には上限を設け、ポートが応答しない場合は [Code::TransferFailed] を返す。
+
+use core::{
+    hint::spin_loop,
+    ptr,
+    sync::atomic::{fence, Ordering},
+};
+
+use alloc::boxed::Box;
+
+use crate::{
+    acpi::Deadline,
+    bitfield::BitField as _,
+    block::{self, BlockDevice},
+    error::{Code, Result},
+    interrupt::InterruptVector,
+    log,
+    logger::LogLevel,
+    make_error,
+    memory_manager::{FrameId, BYTES_PER_FRAME, MEMORY_MANAGER},
+    pci,
+};
+
+/// SATA ドライブのセクタのサイズ。
+const SECTOR_SIZE: usize = 512;
+
+// HBA 全体のレジスタのオフセット
+const HBA_GHC: usize = 0x04;
+const HBA_IS: usize = 0x08;
+const HBA_PI: usize = 0x0c;
+
+// 各ポートのレジスタのオフセット
+const PORT_CLB: usize = 0x00;
+const PORT_CLBU: usize = 0x04;
+const PORT_FB: usize = 0x08;
+const PORT_FBU: usize = 0x0c;
+const PORT_IS: usize = 0x10;
+const PORT_IE: usize = 0x14;
+const PORT_CMD: usize = 0x18;
+const PORT_TFD: usize = 0x20;
+const PORT_SIG: usize = 0x24;
+const PORT_SSTS: usize = 0x28;
+const PORT_SERR: usize = 0x30;
+const PORT_CI: usize = 0x38;
+
+const GHC_IE: u32 = 1 << 1;
+const GHC_AE: u32 = 1 << 31;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+
+/// Device to Host Register FIS を受け取ったときの割り込み
+const IS_DHRS: u32 = 1 << 0;
+/// Task File Error Status
+const IS_TFES: u32 = 1 << 30;
+
+/// SATA ドライブ（ATAPI などではない）のシグネチャ
+const SATA_SIG_ATA: u32 = 0x0000_0101;
+/// デバイスが接続され、通信が確立している状態
+const SSTS_DET_PRESENT: u32 = 3;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
+const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_CMD_IDENTIFY_DEVICE: u8 = 0xec;
+
+// ポートごとに確保する 1 フレーム内の配置
+const COMMAND_LIST_OFFSET: usize = 0;
+const RECEIVED_FIS_OFFSET: usize = 1024;
+const COMMAND_TABLE_OFFSET: usize = 1280;
+/// コマンドテーブル内の PRDT の位置
+const PRDT_OFFSET: usize = 0x80;
+
+/// バウンスバッファのフレーム数
+const BOUNCE_FRAMES: usize = 16;
+/// 1 つのコマンドで転送する最大のセクタ数
+const MAX_TRANSFER_SECTORS: usize = BOUNCE_FRAMES * BYTES_PER_FRAME / SECTOR_SIZE;
+
+/// ポートの停止や開始を待つ最大のミリ秒数。AHCI の仕様では 500 ミリ秒以内に終わる
+const PORT_TIMEOUT_MSEC: u64 = 500;
+/// コマンドの完了を待つ最大のミリ秒数
+const COMMAND_TIMEOUT_MSEC: u64 = 5000;
+
+pub fn init() {
+    let devices = pci::DEVICES.read();
+    for dev in devices
+        .iter()
+        .filter(|dev| dev.class_code().r#match(0x01, 0x06, 0x01))
+    {
+        log!(
+            LogLevel::Info,
+            "AHCI controller has been found: {}.{}.{}",
+            dev.bus(),
+            dev.device(),
+            dev.function()
+        );
+        if let Err(e) = init_controller(*dev) {
+            log!(
+                LogLevel::Error,
+                "failed to initialize AHCI controller: {}",
+                e
+            );
+        }
+    }
+}
+
+fn init_controller(mut dev: pci::Device) -> Result<()> {
+    // メモリ空間へのアクセスとバスマスタを有効にする
+    let command = dev.read_conf_reg(0x04);
+    dev.write_conf_reg(0x04, command | 0b110);
+
+    // ABAR は BAR5 にある
+    let abar = (dev.read_bar(5)? & !0xf) as usize;
+    let hba = Registers(abar);
+    hba.write(HBA_GHC, hba.read(HBA_GHC) | GHC_AE);
+
+    // 完了はポーリングで確認するが、割り込みも受け取れるようにしておく
+    let bsp_local_apic_id = (unsafe { *(0xfee0_0020 as *const u32) } >> 24) as u8;
+    match dev.configure_msi_fixed_destination(
+        bsp_local_apic_id,
+        pci::MSITriggerMode::Edge,
+        pci::MSIDeliverMode::Fixed,
+        InterruptVector::AHCI as u8,
+        0,
+    ) {
+        Ok(()) => hba.write(HBA_GHC, hba.read(HBA_GHC) | GHC_IE),
+        Err(e) => log!(LogLevel::Warn, "AHCI: MSI is not available: {}", e),
+    }
+
+    let ports_implemented = hba.read(HBA_PI);
+    for port in 0..32 {
+        if !ports_implemented.get_bit(port) {
+            continue;
+        }
+
+        let regs = Registers(abar + 0x100 + port as usize * 0x80);
+        if regs.read(PORT_SSTS).get_bits(..4) != SSTS_DET_PRESENT
+            || regs.read(PORT_SIG) != SATA_SIG_ATA
+        {
+            continue;
+        }
+
+        match AhciPort::new(hba, port, regs) {
+            Ok(port_dev) => {
+                log!(
+                    LogLevel::Info,
+                    "AHCI port {}: capacity = {} sectors",
+                    port,
+                    port_dev.capacity
+                );
+                block::register(Box::new(port_dev));
+            }
+            Err(e) => log!(
+                LogLevel::Error,
+                "failed to initialize AHCI port {}: {}",
+                port,
+                e
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// MMIO のレジスタ群の先頭アドレス。
+#[derive(Clone, Copy)]
+struct Registers(usize);
+
+impl Registers {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.0 + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.0 + offset) as *mut u32, value) }
+    }
+}
+
+pub struct AhciPort {
+    hba: Registers,
+    port: u32,
+    regs: Registers,
+    /// コマンドリスト、受信 FIS 領域、コマンドテーブルを置くフレーム
+    area: *mut u8,
+    /// DMA で転送するデータを置くバッファ
+    bounce: *mut u8,
+    capacity: u64,
+    /// コマンドがタイムアウトした場合は true。
+    /// ポートを止めてあるので、以降は使わない
+    broken: bool,
+}
+
+// Safety: 生ポインタはこの構造体が確保したフレームのみを指している
+unsafe impl Send for AhciPort {}
+
+impl AhciPort {
+    fn new(hba: Registers, port: u32, regs: Registers) -> Result<Self> {
+        let area = MEMORY_MANAGER.allocate(1)?;
+        let bounce = match MEMORY_MANAGER.allocate(BOUNCE_FRAMES) {
+            Ok(bounce) => bounce,
+            Err(e) => {
+                MEMORY_MANAGER.free(area, 1);
+                return Err(e);
+            }
+        };
+        let (area, bounce) = (area.frame(), bounce.frame());
+        unsafe { ptr::write_bytes(area, 0, BYTES_PER_FRAME) };
+
+        // ここから後で失敗した場合は、drop でフレームが解放される
+        let mut port = Self {
+            hba,
+            port,
+            regs,
+            area,
+            bounce,
+            capacity: 0,
+            broken: false,
+        };
+        port.stop()?;
+
+        // カーネルの領域はアイデンティティマッピングされているので、
+        // 仮想アドレスをそのまま物理アドレスとして渡せる
+        let command_list = area as u64 + COMMAND_LIST_OFFSET as u64;
+        let received_fis = area as u64 + RECEIVED_FIS_OFFSET as u64;
+        regs.write(PORT_CLB, command_list as u32);
+        regs.write(PORT_CLBU, (command_list >> 32) as u32);
+        regs.write(PORT_FB, received_fis as u32);
+        regs.write(PORT_FBU, (received_fis >> 32) as u32);
+
+        regs.write(PORT_SERR, u32::MAX);
+        regs.write(PORT_IS, u32::MAX);
+        regs.write(PORT_IE, IS_DHRS | IS_TFES);
+        port.start()?;
+
+        port.issue(ATA_CMD_IDENTIFY_DEVICE, 0, 1, false)?;
+        let identify = unsafe { &*(port.bounce as *const [u16; 256]) };
+        // LBA48 のセクタ数が 0 の場合は LBA28 のセクタ数を使う
+        let lba48 = (0..4).fold(0, |acc, i| acc | (identify[100 + i] as u64) << (16 * i));
+        port.capacity = if lba48 != 0 {
+            lba48
+        } else {
+            identify[60] as u64 | (identify[61] as u64) << 16
+        };
+        Ok(port)
+    }
+
+    fn stop(&self) -> Result<()> {
+        let cmd = self.regs.read(PORT_CMD);
+        self.regs.write(PORT_CMD, cmd & !CMD_ST);
+        self.wait_clear(PORT_CMD, CMD_CR, PORT_TIMEOUT_MSEC)?;
+
+        let cmd = self.regs.read(PORT_CMD);
+        self.regs.write(PORT_CMD, cmd & !CMD_FRE);
+        self.wait_clear(PORT_CMD, CMD_FR, PORT_TIMEOUT_MSEC)
+    }
+
+    fn start(&self) -> Result<()> {
+        self.wait_clear(PORT_CMD, CMD_CR, PORT_TIMEOUT_MSEC)?;
+        let cmd = self.regs.read(PORT_CMD);
+        self.regs.write(PORT_CMD, cmd | CMD_FRE);
+        let cmd = self.regs.read(PORT_CMD);
+        self.regs.write(PORT_CMD, cmd | CMD_ST);
+        Ok(())
+    }
+
+    /// ポートのレジスタ `offset` の `mask` のビットが全て 0 になるまで、最大 `msec` ミリ秒待つ。
+    fn wait_clear(&self, offset: usize, mask: u32, msec: u64) -> Result<()> {
+        let mut deadline = Deadline::after_milli_seconds(msec);
+        while self.regs.read(offset) & mask != 0 {
+            if deadline.expired() {
+                log!(
+                    LogLevel::Error,
+                    "AHCI port {}: register {:#x} is stuck at {:#x}",
+                    self.port,
+                    offset,
+                    self.regs.read(offset)
+                );
+                return Err(make_error!(Code::TransferFailed));
+            }
+            spin_loop();
+        }
+        Ok(())
+    }
+
+    /// バウンスバッファを使って ATA コマンドを発行し、完了まで待つ。
+    fn issue(&mut self, command: u8, lba: u64, count: usize, write: bool) -> Result<()> {
+        if self.broken {
+            return Err(make_error!(Code::TransferFailed));
+        }
+        let bytes = count * SECTOR_SIZE;
+        let table = unsafe { self.area.add(COMMAND_TABLE_OFFSET) };
+
+        // Register Host to Device FIS
+        let mut fis = [0u8; 20];
+        fis[0] = FIS_TYPE_REG_H2D;
+        // コマンドレジスタの更新であることを示す
+        fis[1] = 0x80;
+        fis[2] = command;
+        if command != ATA_CMD_IDENTIFY_DEVICE {
+            fis[4..7].copy_from_slice(&lba.to_le_bytes()[..3]);
+            // LBA モード
+            fis[7] = 1 << 6;
+            fis[8..11].copy_from_slice(&lba.to_le_bytes()[3..6]);
+            fis[12..14].copy_from_slice(&(count as u16).to_le_bytes());
+        }
+
+        unsafe {
+            ptr::copy_nonoverlapping(fis.as_ptr(), table, fis.len());
+
+            // PRDT エントリは 1 つだけ使う
+            let prdt = table.add(PRDT_OFFSET) as *mut u32;
+            ptr::write_volatile(prdt, self.bounce as u64 as u32);
+            ptr::write_volatile(prdt.add(1), (self.bounce as u64 >> 32) as u32);
+            ptr::write_volatile(prdt.add(2), 0);
+            ptr::write_volatile(prdt.add(3), (bytes - 1) as u32 | 1 << 31);
+
+            // コマンドヘッダ 0
+            let header = self.area.add(COMMAND_LIST_OFFSET) as *mut u32;
+            let mut dw0 = (fis.len() / 4) as u32 | 1 << 16;
+            if write {
+                dw0 |= 1 << 6;
+            }
+            ptr::write_volatile(header, dw0);
+            ptr::write_volatile(header.add(1), 0);
+            ptr::write_volatile(header.add(2), table as u64 as u32);
+            ptr::write_volatile(header.add(3), (table as u64 >> 32) as u32);
+        }
+        fence(Ordering::SeqCst);
+
+        self.wait_clear(PORT_TFD, TFD_BSY | TFD_DRQ, COMMAND_TIMEOUT_MSEC)?;
+        self.regs.write(PORT_IS, u32::MAX);
+        self.regs.write(PORT_CI, 1);
+
+        let mut deadline = Deadline::after_milli_seconds(COMMAND_TIMEOUT_MSEC);
+        let result = loop {
+            if self.regs.read(PORT_IS) & IS_TFES != 0 {
+                break Err(make_error!(Code::TransferFailed));
+            }
+            if self.regs.read(PORT_CI) & 1 == 0 {
+                break Ok(());
+            }
+            if deadline.expired() {
+                log!(
+                    LogLevel::Error,
+                    "AHCI port {}: command {:#x} timed out",
+                    self.port,
+                    command
+                );
+                // 後から HBA がバウンスバッファを読み書きしないように、ポートを止める
+                let _ = self.stop();
+                self.broken = true;
+                return Err(make_error!(Code::TransferFailed));
+            }
+            spin_loop();
+        };
+        fence(Ordering::SeqCst);
+
+        self.regs.write(PORT_IS, u32::MAX);
+        self.hba.write(HBA_IS, 1 << self.port);
+        if self.regs.read(PORT_TFD) & TFD_ERR != 0 {
+            return Err(make_error!(Code::TransferFailed));
+        }
+        result
+    }
+
+    fn check_range(&self, sector: u64, len: usize) -> Result<()> {
+        if !len.is_multiple_of(SECTOR_SIZE) {
+            return Err(make_error!(Code::InvalidFormat));
+        }
+        if sector + (len / SECTOR_SIZE) as u64 > self.capacity {
+            return Err(make_error!(Code::IndexOutOfRange));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AhciPort {
+    fn drop(&mut self) {
+        // HBA がコマンドリストや受信 FIS 領域を使わなくなってから解放する。
+        // 止められなかった場合は、まだ使われるかもしれないので解放しない
+        if self.stop().is_err() {
+            return;
+        }
+        MEMORY_MANAGER.free(FrameId::from_addr(self.area as _), 1);
+        MEMORY_MANAGER.free(FrameId::from_addr(self.bounce as _), BOUNCE_FRAMES);
+    }
+}
+
+impl BlockDevice for AhciPort {
+    fn name(&self) -> &str {
+        "ahci"
+    }
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        self.check_range(sector, buf.len())?;
+
+        for (i, chunk) in buf
+            .chunks_mut(MAX_TRANSFER_SECTORS * SECTOR_SIZE)
+            .enumerate()
+        {
+            let lba = sector + (i * MAX_TRANSFER_SECTORS) as u64;
+            self.issue(ATA_CMD_READ_DMA_EXT, lba, chunk.len() / SECTOR_SIZE, false)?;
+            unsafe { ptr::copy_nonoverlapping(self.bounce, chunk.as_mut_ptr(), chunk.len()) };
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<()> {
+        self.check_range(sector, buf.len())?;
+
+        for (i, chunk) in buf.chunks(MAX_TRANSFER_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = sector + (i * MAX_TRANSFER_SECTORS) as u64;
+            unsafe { ptr::copy_nonoverlapping(chunk.as_ptr(), self.bounce, chunk.len()) };
+            self.issue(ATA_CMD_WRITE_DMA_EXT, lba, chunk.len() / SECTOR_SIZE, true)?;
+        }
+        Ok(())
+    }
+}
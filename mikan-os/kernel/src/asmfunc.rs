@@ -3,7 +3,7 @@ use core::{
     ffi::c_char,
 };
 
-use crate::task::TaskContext;
+use crate::task::{TaskContext, DEFAULT_MXCSR};
 
 pub fn halt() -> ! {
     loop {
@@ -35,6 +35,50 @@ pub fn io_in_32(addr: u16) -> u32 {
     data
 }
 
+pub fn io_out_16(addr: u16, data: u16) {
+    unsafe {
+        asm!(
+            "out dx, ax",
+            in("dx") addr,
+            in("ax") data,
+        )
+    };
+}
+
+pub fn io_in_16(addr: u16) -> u16 {
+    let data;
+    unsafe {
+        asm!(
+            "in ax, dx",
+            in("dx") addr,
+            out("ax") data,
+        )
+    };
+    data
+}
+
+pub fn io_out_8(addr: u16, data: u8) {
+    unsafe {
+        asm!(
+            "out dx, al",
+            in("dx") addr,
+            in("al") data,
+        )
+    };
+}
+
+pub fn io_in_8(addr: u16) -> u8 {
+    let data;
+    unsafe {
+        asm!(
+            "in al, dx",
+            in("dx") addr,
+            out("al") data,
+        )
+    };
+    data
+}
+
 pub fn get_cs() -> u16 {
     let cs;
     unsafe {
@@ -119,6 +163,8 @@ pub fn call_app(
     rsp: u64,
     os_stack_ptr: &u64,
 ) -> i32 {
+    // 前に動いていたアプリの FPU、SSE の状態を引き継がないようにする
+    init_fpu();
     unsafe { call_app_unsafe(argc, argv, ss, rip, rsp, os_stack_ptr as *const _ as _) }
 }
 
@@ -172,6 +218,38 @@ pub fn set_cr0(value: u64) {
     };
 }
 
+pub fn get_cr4() -> u64 {
+    let cr4;
+    unsafe {
+        asm!(
+            "mov {}, cr4",
+            out(reg) cr4,
+        )
+    }
+    cr4
+}
+
+pub fn set_cr4(value: u64) {
+    unsafe {
+        asm!(
+            "mov cr4, {}",
+            in(reg) value
+        )
+    };
+}
+
+/// x87 FPU と SSE の状態を初期化する。
+pub fn init_fpu() {
+    let mxcsr = DEFAULT_MXCSR;
+    unsafe {
+        asm!(
+            "fninit",
+            "ldmxcsr [{}]",
+            in(reg) &mxcsr,
+        )
+    };
+}
+
 pub fn invalidate_tlb(addr: u64) {
     unsafe {
         asm!(
@@ -181,6 +259,19 @@ pub fn invalidate_tlb(addr: u64) {
     };
 }
 
+/// タイムスタンプカウンタの値を返す。
+pub fn read_tsc() -> u64 {
+    let (hi, lo): (u32, u32);
+    unsafe {
+        asm!(
+            "rdtsc",
+            out("edx") hi,
+            out("eax") lo,
+        )
+    };
+    ((hi as u64) << 32) | lo as u64
+}
+
 extern "C" {
     fn load_idt_unsafe(limit: u16, offset: u64);
     fn load_gdt_unsafe(limit: u16, offset: u64);
@@ -275,6 +366,8 @@ switch_context_unsafe: # switch_context_unsafe(next_ctx, current_ctx)
     mov [rsi + 0x30], RCX
     mov dx, gs
     mov [rsi + 0x38], RDX
+
+    fxsave64 [rsi + 0xc0]
     # fall through to restore_context_unsafe
 
 .global restore_context_unsafe
@@ -309,6 +402,8 @@ restore_context_unsafe:
     mov r14, [rdi + 0xb0]
     mov r15, [rdi + 0xb8]
 
+    fxrstor64 [rdi + 0xc0]
+
     mov rdi, [rdi + 0x60]
 
     iretq
@@ -0,0 +1,95 @@
+//! ブロックデバイスの共通インターフェース。
+//!
+//! 各ディスクドライバは初期化時に [register] でデバイスを登録し、
+//! ファイルシステムは [BLOCK_DEVICES] から対象のデバイスを選んで読み書きする。
+
+use alloc::{boxed::Box, vec::Vec};
+
+use crate::{
+    error::{Code, Result},
+    make_error,
+    sync::Mutex,
+};
+
+/// 登録されているブロックデバイスの一覧。
+/// インデックスがそのデバイスの ID になる。
+pub static BLOCK_DEVICES: Mutex<Vec<Box<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+/// セクタ単位で読み書きできるデバイス。
+pub trait BlockDevice: Send {
+    /// デバイスの名前を返す。
+    fn name(&self) -> &str;
+
+    /// 1 セクタのバイト数を返す。
+    fn sector_size(&self) -> usize;
+
+    /// デバイスの容量をセクタ数で返す。
+    fn capacity(&self) -> u64;
+
+    /// `sector` から `buf` の長さ分のセクタを読み込む。
+    /// `buf` の長さはセクタサイズの倍数でなければならない。
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// `sector` から `buf` の長さ分のセクタに書き込む。
+    /// `buf` の長さはセクタサイズの倍数でなければならない。
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<()>;
+}
+
+/// ブロックデバイスを登録し、その ID を返す。
+pub fn register(dev: Box<dyn BlockDevice>) -> usize {
+    let mut devices = BLOCK_DEVICES.lock_wait();
+    devices.push(dev);
+    devices.len() - 1
+}
+
+/// メモリ上のディスクイメージを扱うブロックデバイス。
+/// 書き込んだ内容はメモリ上にしか残らない。
+pub struct RamDisk {
+    data: &'static mut [u8],
+}
+
+impl RamDisk {
+    /// RamDisk が扱うセクタのサイズ
+    const SECTOR_SIZE: usize = 512;
+
+    pub fn new(data: &'static mut [u8]) -> Self {
+        Self { data }
+    }
+
+    fn range(&self, sector: u64, len: usize) -> Result<core::ops::Range<usize>> {
+        if !len.is_multiple_of(Self::SECTOR_SIZE) {
+            return Err(make_error!(Code::InvalidFormat));
+        }
+        let start = sector as usize * Self::SECTOR_SIZE;
+        if start + len > self.data.len() {
+            return Err(make_error!(Code::IndexOutOfRange));
+        }
+        Ok(start..start + len)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        "ramdisk"
+    }
+
+    fn sector_size(&self) -> usize {
+        Self::SECTOR_SIZE
+    }
+
+    fn capacity(&self) -> u64 {
+        (self.data.len() / Self::SECTOR_SIZE) as u64
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        let range = self.range(sector, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<()> {
+        let range = self.range(sector, buf.len())?;
+        self.data[range].copy_from_slice(buf);
+        Ok(())
+    }
+}
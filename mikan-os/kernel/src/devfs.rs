@@ -0,0 +1,282 @@
+//! デバイスをファイルとして見せる疑似ファイルシステム。
+//!
+//! ```text
+//! /dev/null       書き込んだデータを捨て、読むと常に終端
+//! /dev/zero       読むと 0 が続く
+//! /dev/random     読むと疑似乱数が続く
+//! /dev/fb0        画面のフレームバッファ。マップすると画面に直接描ける
+//! /dev/stdin      開いたタスクの標準入力
+//! /dev/stdout     開いたタスクの標準出力
+//! /dev/stderr     開いたタスクの標準エラー出力
+//! ```
+
+use core::{cmp, slice};
+
+use alloc::{
+    boxed::Box,
+    string::{String, ToString as _},
+    sync::Arc,
+};
+
+use crate::{
+    asmfunc,
+    error::{Code, Result},
+    fat::Attribute,
+    file::{FileDescriptor, Stat},
+    frame_buffer,
+    graphics::FB_CONFIG,
+    make_error,
+    memory_manager::{FrameId, BYTES_PER_FRAME},
+    sync::Mutex,
+    task,
+    vfs::{File, FileSystem},
+};
+
+/// デバイスの名前の一覧
+const DEVICES: [&str; 7] = ["null", "zero", "random", "fb0", "stdin", "stdout", "stderr"];
+
+/// 疑似乱数の状態。0 の場合はまだ種を設定していない
+static RANDOM_STATE: Mutex<u64> = Mutex::new(0);
+
+pub struct DevFileSystem;
+
+impl FileSystem for DevFileSystem {
+    fn name(&self) -> &str {
+        "dev"
+    }
+
+    fn lookup(&self, path: &str) -> Result<Box<dyn File>> {
+        let post_slash = path.ends_with('/');
+        let name = path.trim_end_matches('/');
+        if name.is_empty() {
+            return Ok(Box::new(DevDir { next: 0 }));
+        }
+        if !DEVICES.contains(&name) {
+            return Err(make_error!(Code::NoSuchEntry));
+        }
+        if post_slash {
+            return Err(make_error!(Code::NotDirectory));
+        }
+
+        let file: Box<dyn File> = match name {
+            "null" => Box::new(NullFile),
+            "zero" => Box::new(ZeroFile),
+            "random" => Box::new(RandomFile),
+            "fb0" => Box::new(FrameBufferFile { off: 0 }),
+            "stdin" => Box::new(StdFile::new(0)?),
+            "stdout" => Box::new(StdFile::new(1)?),
+            "stderr" => Box::new(StdFile::new(2)?),
+            _ => unreachable!(),
+        };
+        Ok(file)
+    }
+}
+
+/// デバイスの一覧を返すディレクトリ。
+struct DevDir {
+    /// 次に返す [DEVICES] のインデックス
+    next: usize,
+}
+
+impl File for DevDir {
+    fn read(&mut self, _buf: &mut [u8]) -> usize {
+        0
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+        Err(make_error!(Code::IsDirectory))
+    }
+
+    fn stat(&self) -> Stat {
+        Stat {
+            attr: Attribute::Directory as _,
+            ..Default::default()
+        }
+    }
+
+    fn read_dir(&mut self) -> Result<Option<(Stat, String)>> {
+        let Some(name) = DEVICES.get(self.next) else {
+            return Ok(None);
+        };
+        self.next += 1;
+
+        let stat = if *name == "fb0" {
+            FrameBufferFile { off: 0 }.stat()
+        } else {
+            Stat::default()
+        };
+        Ok(Some((stat, name.to_string())))
+    }
+}
+
+/// 書き込みを全て捨て、何も読めないデバイス。
+struct NullFile;
+
+impl File for NullFile {
+    fn read(&mut self, _buf: &mut [u8]) -> usize {
+        0
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn stat(&self) -> Stat {
+        Stat::default()
+    }
+}
+
+/// 0 を返し続けるデバイス。
+struct ZeroFile;
+
+impl File for ZeroFile {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        buf.fill(0);
+        buf.len()
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn stat(&self) -> Stat {
+        Stat::default()
+    }
+}
+
+/// 疑似乱数を返し続けるデバイス。
+struct RandomFile;
+
+impl File for RandomFile {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut state = RANDOM_STATE.lock_wait();
+        if *state == 0 {
+            // 0 からは 0 しか出てこないので、最下位ビットを立てておく
+            *state = asmfunc::read_tsc() | 1;
+        }
+
+        for chunk in buf.chunks_mut(8) {
+            // xorshift64*
+            *state ^= *state >> 12;
+            *state ^= *state << 25;
+            *state ^= *state >> 27;
+            let value = state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+            chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+        }
+        buf.len()
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn stat(&self) -> Stat {
+        Stat::default()
+    }
+}
+
+/// 画面のフレームバッファ。
+struct FrameBufferFile {
+    /// 読み書きする位置のフレームバッファ先頭からのオフセット
+    off: usize,
+}
+
+impl FrameBufferFile {
+    /// フレームバッファ全体を返す。
+    fn buffer() -> &'static mut [u8] {
+        let config = FB_CONFIG.as_ref();
+        let len = frame_buffer::bytes_per_scan_line(config) * config.vertical_resolution;
+        // Safety: フレームバッファはアイデンティティマップされていて、起動中に移動しない
+        unsafe { slice::from_raw_parts_mut(config.frame_buffer as *mut u8, len) }
+    }
+}
+
+impl File for FrameBufferFile {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let fb = Self::buffer();
+        let n = cmp::min(buf.len(), fb.len() - self.off);
+        buf[..n].copy_from_slice(&fb[self.off..self.off + n]);
+        self.off += n;
+        n
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let fb = Self::buffer();
+        if self.off == fb.len() && !buf.is_empty() {
+            return Err(make_error!(Code::NoEnoughMemory));
+        }
+        let n = cmp::min(buf.len(), fb.len() - self.off);
+        fb[self.off..self.off + n].copy_from_slice(&buf[..n]);
+        self.off += n;
+        Ok(n)
+    }
+
+    fn stat(&self) -> Stat {
+        Stat {
+            size: Self::buffer().len() as _,
+            ..Default::default()
+        }
+    }
+
+    fn seek(&mut self, offset: usize) -> Result<()> {
+        if offset > Self::buffer().len() {
+            return Err(make_error!(Code::IndexOutOfRange));
+        }
+        self.off = offset;
+        Ok(())
+    }
+
+    fn position(&self) -> usize {
+        self.off
+    }
+
+    fn map_page(&self, offset: usize) -> Result<FrameId> {
+        let fb = Self::buffer();
+        if offset >= fb.len() {
+            return Err(make_error!(Code::IndexOutOfRange));
+        }
+        let addr = fb.as_ptr() as usize + offset;
+        Ok(FrameId::from_addr(addr - addr % BYTES_PER_FRAME))
+    }
+
+    fn map_shared(&self) -> bool {
+        true
+    }
+}
+
+/// 開いたタスクの標準入出力を共有するファイル。
+/// 元のファイルディスクリプタが閉じられても、標準入出力自体は残る。
+struct StdFile {
+    fd: Arc<Mutex<FileDescriptor>>,
+}
+
+impl StdFile {
+    /// 現在のタスクのファイルディスクリプタ `fd` を共有する。
+    fn new(fd: i32) -> Result<Self> {
+        asmfunc::cli();
+        let task = task::current_task();
+        asmfunc::sti();
+
+        let fd = task
+            .files()
+            .lock_wait()
+            .get(&fd)
+            .cloned()
+            .ok_or(make_error!(Code::NoSuchEntry))?;
+        Ok(Self { fd })
+    }
+}
+
+impl File for StdFile {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        self.fd.lock_wait().read(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.fd.lock_wait().write(buf)
+    }
+
+    fn stat(&self) -> Stat {
+        self.fd.lock_wait().stat()
+    }
+}
@@ -34,6 +34,11 @@ pub enum Code {
     NoSuchEntry,
     FreeTypeError,
     EndpointNotInCharge,
+    DirectoryNotEmpty,
+    FileExists,
+    NotDirectory,
+    NameTooLong,
+    CrossDevice,
 }
 
 impl Display for Code {
@@ -68,6 +73,11 @@ impl Display for Code {
             Self::NoSuchEntry => write!(f, "NoSuchEntry"),
             Self::FreeTypeError => write!(f, "FreeTypeError"),
             Self::EndpointNotInCharge => write!(f, "EndpointNotInCharge"),
+            Self::DirectoryNotEmpty => write!(f, "DirectoryNotEmpty"),
+            Self::FileExists => write!(f, "FileExists"),
+            Self::NotDirectory => write!(f, "NotDirectory"),
+            Self::NameTooLong => write!(f, "NameTooLong"),
+            Self::CrossDevice => write!(f, "CrossDevice"),
         }
     }
 }
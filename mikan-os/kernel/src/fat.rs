@@ -1,490 +1,391 @@
-use core::{
-    cmp,
-    ffi::c_void,
-    hash::{Hash, Hasher},
-    mem, ptr, slice, str,
-};
+//! ブートボリュームの FAT を [fat_fs] で扱う。
+//!
+//! ボリュームは [VOLUME] のロックを取って操作する。ページキャッシュを操作するときは
+//! [VOLUME] のロックを外してから呼ぶこと（ページキャッシュはロックを持ったままボリュームを読む）。
+
+use core::slice;
 
-use alloc::vec::Vec;
+use alloc::{boxed::Box, collections::BTreeMap, vec, vec::Vec};
+
+use fat_fs::Volume;
 
 use crate::{
-    bitfield::BitField as _,
-    error::{Code, Result},
-    make_error,
-    util::OnceStatic,
+    block::{self, RamDisk, BLOCK_DEVICES},
+    error::{Code, Error, Result},
+    file::{FatDir, FatFile},
+    log,
+    logger::LogLevel,
+    make_error, page_cache,
+    sync::{Mutex, MutexGuard, OnceMutex},
+    vfs::{File, FileSystem, FsStat},
 };
 
-pub const END_OF_CLUSTER_CHAIN: u64 = 0x0fff_ffff;
+pub use fat_fs::{
+    Attribute, DirCursor, DirectoryEntry, Entry, Location, Problem, END_OF_CLUSTER_CHAIN,
+};
 
-pub static BOOT_VOLUME_IMAGE: OnceStatic<&'static BPB> = OnceStatic::new();
-pub static BYTES_PER_CLUSTER: OnceStatic<u64> = OnceStatic::new();
+/// ブートボリューム
+static VOLUME: OnceMutex<Volume> = OnceMutex::new();
 
-pub fn init(volume_image: *mut c_void) {
-    BOOT_VOLUME_IMAGE.init(unsafe { &*(volume_image as *const BPB) });
+/// 開かれているファイルのディレクトリエントリの位置。
+/// [VOLUME] のロックを取ってからロックすること。
+static OPEN_FILES: Mutex<OpenFiles> = Mutex::new(OpenFiles::new());
 
-    let image = BOOT_VOLUME_IMAGE.get();
-    BYTES_PER_CLUSTER.init(image.byts_per_sec() as u64 * image.sec_per_clus() as u64);
-}
+/// 開かれているファイルを表す ID。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct OpenFileId(usize);
 
-pub fn get_sector_by_cluster<T>(cluster: u64, len: usize) -> &'static mut [T] {
-    unsafe { slice::from_raw_parts_mut(get_cluster_addr(cluster) as *mut T, len) }
+struct OpenFiles {
+    next_id: usize,
+    /// 削除されたファイルは `None` になり、その後は読み書きできない
+    locations: BTreeMap<OpenFileId, Option<Location>>,
 }
 
-pub fn read_name(entry: &DirectoryEntry) -> (&str, &str) {
-    let base_len = entry.name[..8]
-        .iter()
-        .enumerate()
-        .rev()
-        .find_map(|(i, &b)| if b != 0x20 { Some(i + 1) } else { None })
-        .unwrap_or(0);
-
-    let ext_len = entry.name[8..]
-        .iter()
-        .enumerate()
-        .rev()
-        .find_map(|(i, &b)| if b != 0x20 { Some(i + 1) } else { None })
-        .unwrap_or(0);
-
-    // Safety: ASCII 文字列しか入っていない
-    unsafe {
-        (
-            core::str::from_utf8_unchecked(&entry.name[..base_len]),
-            core::str::from_utf8_unchecked(&entry.name[8..8 + ext_len]),
-        )
+impl OpenFiles {
+    const fn new() -> Self {
+        Self {
+            next_id: 0,
+            locations: BTreeMap::new(),
+        }
     }
-}
 
-/// `path` が絶対パスのときはルートディレクトリ、
-/// 相対パスの場合は `directory_cluster` を基準としてファイル、ディレクトリを検索する。
-/// `directory_entry` が `0` のときはルートディレクトリを基準として探索する。
-///
-/// ファイルもしくはディレクトリが見つかった場合、それらを [DirectoryEntry] への参照として返す。
-/// また `/` がそれらの直後にあるかどうかも返す。
-pub fn find_file(
-    path: &str,
-    directory_cluster: u64,
-) -> (Option<&'static mut DirectoryEntry>, bool) {
-    let (rel_path, mut directory_cluster) = if path.starts_with('/') {
-        // Safety: 1バイト文字の '/' が先頭で、元々正当な文字列だから大丈夫
-        let path = unsafe { str::from_utf8_unchecked(&path.as_bytes()[1..]) };
-        (path, BOOT_VOLUME_IMAGE.get().root_clus() as _)
-    } else if directory_cluster == 0 {
-        (path, BOOT_VOLUME_IMAGE.get().root_clus() as _)
-    } else {
-        (path, directory_cluster)
-    };
+    fn open(&mut self, location: Location) -> OpenFileId {
+        let id = OpenFileId(self.next_id);
+        self.next_id += 1;
+        self.locations.insert(id, Some(location));
+        id
+    }
 
-    let (path_elem, next_path, post_slash) = rel_path
-        .split_once('/')
-        // Some になるのは `/` が含まれていた場合なので、`path_elem` のあとは `/`
-        .map(|x| (x.0, x.1, true))
-        // None になるのは `/` がない場合で、`path_elem` のあとはなにもない
-        .unwrap_or_else(|| (rel_path, "", false));
-    let path_last = next_path.is_empty();
-
-    'outer: while directory_cluster != END_OF_CLUSTER_CHAIN {
-        let dir = get_sector_by_cluster::<DirectoryEntry>(
-            directory_cluster,
-            BYTES_PER_CLUSTER.get() as usize / mem::size_of::<DirectoryEntry>(),
-        );
-        for file in dir {
-            // ディレクトリ内の要素が終わったことを示す
-            if file.name[0] == 0 {
-                break 'outer;
-            } else if !file.name_is_equal(path_elem) {
-                continue;
-            }
+    fn location(&self, id: OpenFileId) -> Result<Location> {
+        match self.locations.get(&id) {
+            Some(Some(location)) => Ok(*location),
+            _ => Err(make_error!(Code::NoSuchEntry)),
+        }
+    }
 
-            if file.attr == Attribute::Directory as _ && !path_last {
-                return find_file(next_path, file.first_cluster() as _);
-            } else {
-                return (Some(file), post_slash);
+    /// `old` にあるファイルを開いているものを `new` に向け直す。
+    /// 削除された場合は `new` を `None` にする。
+    fn relocate(&mut self, old: Location, new: Option<Location>) {
+        for location in self.locations.values_mut() {
+            if *location == Some(old) {
+                *location = new;
             }
         }
-
-        directory_cluster = next_cluster(directory_cluster);
     }
-
-    (None, post_slash)
 }
 
-pub fn create_file(path: &str) -> Result<&'static mut DirectoryEntry> {
-    let mut parent_dir_cluster = BOOT_VOLUME_IMAGE.get().root_clus() as u64;
-
-    let filename = if let Some((parent_dir_name, filename)) = path.rsplit_once('/') {
-        if filename.is_empty() {
-            return Err(make_error!(Code::IsDirectory));
-        }
-
-        if !parent_dir_name.is_empty() {
-            let (Some(parent_dir), _) = find_file(parent_dir_name, 0) else {
-                return Err(make_error!(Code::NoSuchEntry));
-            };
-            parent_dir_cluster = parent_dir.first_cluster() as _;
-        }
-        filename
-    } else {
-        path
-    };
-
-    let dir = allocate_entry(parent_dir_cluster);
-    set_file_name(dir, filename);
-
-    Ok(dir)
+/// ローダーから渡されるブートボリュームの情報。
+/// ローダー側の `BootVolume` と同じレイアウトにすること。
+#[repr(C)]
+#[derive(Clone)]
+pub struct BootVolume {
+    /// ローダーがメモリに読み込んだボリュームイメージ。ディスク上のボリュームを使う場合は null
+    pub image: *mut u8,
+    /// `image` のバイト数
+    pub image_size: u64,
+    /// ボリュームがある UEFI の BlockIO のメディア ID
+    pub media_id: u32,
+    /// UEFI の BlockIO のブロックサイズ
+    pub block_size: u32,
+    /// ディスクの先頭からボリュームの先頭までのブロック数
+    pub lba_offset: u64,
+    /// ボリュームの先頭セクタ
+    pub boot_sector: [u8; 512],
 }
 
-pub fn allocate_cluster_chain(n: usize) -> Result<u64> {
-    let fat = get_fat();
-    let first_cluster = 'l: {
-        for (clus, clus_fat) in fat.iter_mut().skip(2).enumerate() {
-            if *clus_fat == 0 {
-                *clus_fat = END_OF_CLUSTER_CHAIN as _;
-                break 'l clus as u64;
-            }
-        }
-        return Err(make_error!(Code::NoEnoughMemory));
-    };
+/// ブートボリュームを見つけてマウントする。
+/// ブロックデバイスのドライバを初期化した後に呼ぶこと。
+pub fn init(boot_volume: &BootVolume) -> Result<()> {
+    let (id, lba_offset) = find_boot_device(boot_volume)?;
+    let volume = Volume::new(Box::new(BootDevice { id, lba_offset }))?;
+    log!(
+        LogLevel::Info,
+        "boot volume is {:?} with {} clusters",
+        volume.fat_type(),
+        volume.cluster_count()
+    );
+    VOLUME.init(volume);
+    Ok(())
+}
 
-    if n > 1 {
-        extend_cluster(first_cluster, n - 1);
-    }
-    Ok(first_cluster)
+/// [VOLUME] のロックを取る。ブートボリュームをマウントできていない場合はエラーを返す。
+fn lock_volume() -> Result<MutexGuard<'static, Volume>> {
+    VOLUME
+        .lock_checked_wait()
+        .ok_or(make_error!(Code::NoSuchEntry))
 }
 
-fn allocate_entry(mut dir_cluster: u64) -> &'static mut DirectoryEntry {
-    loop {
-        let dir = get_sector_by_cluster::<DirectoryEntry>(
-            dir_cluster,
-            BYTES_PER_CLUSTER.get() as usize / mem::size_of::<DirectoryEntry>(),
-        );
-        for entry in dir {
-            if entry.name[0] == 0 || entry.name[0] == 0xe5 {
-                #[allow(clippy::missing_transmute_annotations)]
-                return unsafe { mem::transmute(entry as *const _ as usize) };
-            }
-        }
-        dir_cluster = match next_cluster(dir_cluster) {
-            END_OF_CLUSTER_CHAIN => break,
-            clus => clus,
+/// ブートボリュームがあるブロックデバイスの ID と、デバイス上でのボリュームの開始セクタを返す。
+fn find_boot_device(boot_volume: &BootVolume) -> Result<(usize, u64)> {
+    if !boot_volume.image.is_null() {
+        let image = unsafe {
+            slice::from_raw_parts_mut(boot_volume.image, boot_volume.image_size as usize)
         };
+        log!(LogLevel::Info, "boot volume is on memory");
+        return Ok((block::register(Box::new(RamDisk::new(image))), 0));
     }
 
-    dir_cluster = extend_cluster(dir_cluster, 1);
-    let dir = get_sector_by_cluster::<DirectoryEntry>(dir_cluster, 1)
-        .first()
-        .unwrap();
+    let offset_bytes = boot_volume.lba_offset * boot_volume.block_size as u64;
+    let mut devices = BLOCK_DEVICES.lock_wait();
+    for (id, dev) in devices.iter_mut().enumerate() {
+        let sector_size = dev.sector_size();
+        if !offset_bytes.is_multiple_of(sector_size as u64) {
+            continue;
+        }
 
-    unsafe {
-        #[allow(clippy::useless_transmute)]
-        let ptr: *mut u64 = mem::transmute(dir);
-        ptr::write_bytes(ptr, 0, BYTES_PER_CLUSTER.get() as usize / 8);
+        // 先頭セクタがローダーから渡されたものと一致するデバイスを探す
+        let lba_offset = offset_bytes / sector_size as u64;
+        let mut buf = vec![0; sector_size.max(boot_volume.boot_sector.len())];
+        if dev.read(lba_offset, &mut buf).is_ok() && buf[..512] == boot_volume.boot_sector {
+            log!(
+                LogLevel::Info,
+                "boot volume has been found on {} (MediaId {}, LBA offset {})",
+                dev.name(),
+                boot_volume.media_id,
+                lba_offset
+            );
+            return Ok((id, lba_offset));
+        }
     }
-    unsafe { mem::transmute(dir as *const _ as usize) }
+    log!(
+        LogLevel::Error,
+        "boot volume is not found on any block device"
+    );
+    Err(make_error!(Code::NoSuchEntry))
 }
 
-pub fn extend_cluster(eoc_cluster: u64, n: usize) -> u64 {
-    let mut eoc_cluster = eoc_cluster as usize;
-    let fat = get_fat();
-    while !is_end_of_cluster_chain(fat[eoc_cluster]) {
-        eoc_cluster = fat[eoc_cluster] as _;
-    }
+/// ブートボリュームがあるブロックデバイスを、ボリュームの先頭からのセクタ番号で読み書きする。
+struct BootDevice {
+    /// ブロックデバイスの ID
+    id: usize,
+    /// デバイスの先頭からボリュームの先頭までのデバイスのセクタ数
+    lba_offset: u64,
+}
 
-    let mut num_allocated = 0;
-    let mut current = eoc_cluster;
+impl fat_fs::BlockDevice for BootDevice {
+    fn sector_size(&self) -> usize {
+        BLOCK_DEVICES.lock_wait()[self.id].sector_size()
+    }
 
-    for candidate in 2..fat.len() {
-        // candidate クラスタは既に使われている
-        if fat[candidate] != 0 {
-            continue;
-        }
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> fat_fs::Result<()> {
+        let mut devices = BLOCK_DEVICES.lock_wait();
+        devices[self.id]
+            .read(self.lba_offset + sector, buf)
+            .map_err(|e| {
+                log!(LogLevel::Error, "failed to read sector {}: {}", sector, e);
+                fat_fs::Error::Device
+            })
+    }
 
-        fat[current] = candidate as _;
-        current = candidate;
-        num_allocated += 1;
+    fn write(&mut self, sector: u64, buf: &[u8]) -> fat_fs::Result<()> {
+        let mut devices = BLOCK_DEVICES.lock_wait();
+        devices[self.id]
+            .write(self.lba_offset + sector, buf)
+            .map_err(|e| {
+                log!(LogLevel::Error, "failed to write sector {}: {}", sector, e);
+                fat_fs::Error::Device
+            })
+    }
+}
 
-        if num_allocated == n {
-            break;
+impl From<fat_fs::Error> for Error {
+    fn from(e: fat_fs::Error) -> Self {
+        match e {
+            fat_fs::Error::NoSuchEntry => make_error!(Code::NoSuchEntry),
+            fat_fs::Error::NotDirectory => make_error!(Code::NotDirectory),
+            fat_fs::Error::IsDirectory => make_error!(Code::IsDirectory),
+            fat_fs::Error::FileExists => make_error!(Code::FileExists),
+            fat_fs::Error::DirectoryNotEmpty => make_error!(Code::DirectoryNotEmpty),
+            fat_fs::Error::NameTooLong => make_error!(Code::NameTooLong),
+            fat_fs::Error::NoSpace => make_error!(Code::NoEnoughMemory),
+            fat_fs::Error::InvalidMove => make_error!(Code::InvalidFile),
+            fat_fs::Error::OutOfRange => make_error!(Code::IndexOutOfRange),
+            fat_fs::Error::InvalidFormat => make_error!(Code::InvalidFormat),
+            fat_fs::Error::Device => make_error!(Code::TransferFailed),
         }
     }
-    fat[current] = END_OF_CLUSTER_CHAIN as _;
-    current as _
 }
 
-pub fn set_file_name(entry: &mut DirectoryEntry, name: &str) {
-    let (base, ext) = match name.rsplit_once('.') {
-        Some(pair) => pair,
-        None => (name, ""),
-    };
-
-    for (i, c) in entry.name[..8].iter_mut().enumerate() {
-        *c = base
-            .as_bytes()
-            .get(i)
-            .copied()
-            .unwrap_or(b' ')
-            .to_ascii_uppercase();
-    }
-    for (i, c) in entry.name[8..].iter_mut().enumerate() {
-        *c = ext
-            .as_bytes()
-            .get(i)
-            .copied()
-            .unwrap_or(b' ')
-            .to_ascii_uppercase();
+/// 変更済みのセクタをブロックデバイスに書き戻す。
+pub fn sync() -> Result<()> {
+    if let Err(e) = lock_volume()?.sync() {
+        log!(LogLevel::Error, "failed to sync the boot volume: {}", e);
+        return Err(e.into());
     }
+    Ok(())
 }
 
-pub fn next_cluster(cluster: u64) -> u64 {
-    let image = BOOT_VOLUME_IMAGE.get();
-    let fat_offset = image.rsvd_sec_cnt() * image.byts_per_sec();
-    let fat = unsafe {
-        &*ptr::slice_from_raw_parts(
-            (image.as_ptr().byte_add(fat_offset as usize)) as *const u32,
-            image.fat_sz32() as usize * image.byts_per_sec() as usize / mem::size_of::<u32>(),
-        )
+/// ブートボリュームの整合性を検査し、見つかった問題を返す。
+/// `repair` が true の場合は問題を修復して書き戻す。
+pub fn check(repair: bool) -> Result<Vec<Problem>> {
+    let problems = {
+        let mut volume = lock_volume()?;
+        let problems = volume.check(repair)?;
+        if repair {
+            volume.sync()?;
+        }
+        problems
     };
-
-    let next = fat[cluster as usize];
-    if next >= 0x0fff_fff8 {
-        END_OF_CLUSTER_CHAIN
-    } else {
-        next as u64
+    // ファイルの中身が切り詰められたり、クラスタが解放されたりしているかもしれない
+    if repair && problems.iter().any(Problem::repairable) {
+        page_cache::invalidate_all();
     }
+    Ok(problems)
 }
 
-pub fn load_file(entry: &DirectoryEntry) -> Vec<u8> {
-    let mut cluster = entry.first_cluster() as u64;
-    let mut remain_bytes = entry.file_size as _;
+/// ブートボリュームの FAT を [FileSystem] として扱う。
+pub struct FatFileSystem;
 
-    let mut buf = Vec::<u8>::with_capacity(remain_bytes);
+impl FileSystem for FatFileSystem {
+    fn name(&self) -> &str {
+        "fat"
+    }
 
-    while cluster != 0 && cluster != END_OF_CLUSTER_CHAIN {
-        let copy_bytes = cmp::min(BYTES_PER_CLUSTER.get() as _, remain_bytes);
-        buf.extend_from_slice(get_sector_by_cluster(cluster, copy_bytes));
+    fn lookup(&self, path: &str) -> Result<Box<dyn File>> {
+        if path.trim_matches('/').is_empty() {
+            return Ok(Box::new(FatDir::new(None)));
+        }
 
-        remain_bytes -= copy_bytes;
-        cluster = next_cluster(cluster);
+        // 見つけてから開くまでの間に削除されないように、ボリュームのロックを取ったまま開く
+        let mut volume = lock_volume()?;
+        match volume.find_file(path, 0)? {
+            (Some(entry), _) if entry.is_dir() => {
+                drop(volume);
+                Ok(Box::new(FatDir::new(Some(entry))))
+            }
+            (Some(_), true) => Err(make_error!(Code::NotDirectory)),
+            (Some(entry), false) => {
+                let id = OPEN_FILES.lock_wait().open(entry.location);
+                Ok(Box::new(FatFile::new(id)))
+            }
+            (None, _) => Err(make_error!(Code::NoSuchEntry)),
+        }
     }
 
-    buf
-}
+    fn create(&self, path: &str) -> Result<Box<dyn File>> {
+        let mut volume = lock_volume()?;
+        let entry = volume.create_file(path)?;
+        let id = OPEN_FILES.lock_wait().open(entry.location);
+        Ok(Box::new(FatFile::new(id)))
+    }
 
-/// FAT の全体を返す。
-fn get_fat() -> &'static mut [u32] {
-    let image = BOOT_VOLUME_IMAGE.get();
-    let head = image.as_ptr() as usize + (image.rsvd_sec_cnt * image.byts_per_sec) as usize;
-    let len = (image.fat_sz32 * image.byts_per_sec as u32) / 4;
+    fn remove(&self, path: &str) -> Result<()> {
+        let entry = {
+            let mut volume = lock_volume()?;
+            let entry = volume.remove_file(path)?;
+            // 空いたエントリは別のファイルに使われるので、開いているものからは読み書きさせない
+            OPEN_FILES.lock_wait().relocate(entry.location, None);
+            entry
+        };
+        // 解放したクラスタは別のファイルに使われるので、そのページを残してはいけない
+        if entry.first_cluster() != 0 {
+            page_cache::invalidate(entry.first_cluster());
+        }
+        Ok(())
+    }
 
-    unsafe { slice::from_raw_parts_mut(head as _, len as _) }
-}
+    fn create_dir(&self, path: &str) -> Result<()> {
+        lock_volume()?.create_dir(path)?;
+        Ok(())
+    }
 
-fn is_end_of_cluster_chain(clus: u32) -> bool {
-    clus >= 0x00FF_FFF8
-}
+    fn remove_dir(&self, path: &str) -> Result<()> {
+        lock_volume()?.remove_dir(path)?;
+        Ok(())
+    }
 
-#[repr(packed)]
-pub struct BPB {
-    jmp_bot: [u8; 3],
-    oemname: [u8; 8],
-    byts_per_sec: u16,
-    sec_per_clus: u8,
-    rsvd_sec_cnt: u16,
-    num_fats: u8,
-    root_ent_cnd: u16,
-    tot_sec16: u16,
-    media: u8,
-    fat_sz16: u16,
-    sec_per_trk: u16,
-    num_heads: u16,
-    hidd_sec: u32,
-    tot_sec32: u32,
-    fat_sz32: u32,
-    ext_flags: u16,
-    fsver: u16,
-    root_clus: u32,
-    fsinfo: u16,
-    bk_boot_sec: u16,
-    reserved: [u8; 12],
-    drv_num: u8,
-    reserved1: u8,
-    boot_sig: u8,
-    vol_id: u32,
-    vol_lab: [u8; 11],
-    fil_sys_type: u64,
-}
+    fn rename(&self, old_path: &str, new_path: &str) -> Result<()> {
+        let mut volume = lock_volume()?;
+        let old = volume.find_file(old_path, 0)?.0;
+        volume.rename(old_path, new_path)?;
 
-impl BPB {
-    pub fn jmp_bot(&self) -> [u8; 3] {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.jmp_bot)) }
-    }
-    pub fn oemname(&self) -> [u8; 8] {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.oemname)) }
-    }
-    pub fn byts_per_sec(&self) -> u16 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.byts_per_sec)) }
-    }
-    pub fn sec_per_clus(&self) -> u8 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.sec_per_clus)) }
-    }
-    pub fn rsvd_sec_cnt(&self) -> u16 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.rsvd_sec_cnt)) }
-    }
-    pub fn num_fats(&self) -> u8 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.num_fats)) }
-    }
-    pub fn root_ent_cnd(&self) -> u16 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.root_ent_cnd)) }
-    }
-    pub fn tot_sec16(&self) -> u16 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.tot_sec16)) }
-    }
-    pub fn media(&self) -> u8 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.media)) }
-    }
-    pub fn fat_sz16(&self) -> u16 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.fat_sz16)) }
-    }
-    pub fn sec_per_trk(&self) -> u16 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.sec_per_trk)) }
-    }
-    pub fn num_heads(&self) -> u16 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.num_heads)) }
-    }
-    pub fn hidd_sec(&self) -> u32 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.hidd_sec)) }
-    }
-    pub fn tot_sec32(&self) -> u32 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.tot_sec32)) }
-    }
-    pub fn fat_sz32(&self) -> u32 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.fat_sz32)) }
-    }
-    pub fn ext_flags(&self) -> u16 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.ext_flags)) }
-    }
-    pub fn fsver(&self) -> u16 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.fsver)) }
-    }
-    pub fn root_clus(&self) -> u32 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.root_clus)) }
-    }
-    pub fn fsinfo(&self) -> u16 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.fsinfo)) }
-    }
-    pub fn bk_boot_sec(&self) -> u16 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.bk_boot_sec)) }
-    }
-    pub fn reserved(&self) -> [u8; 12] {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.reserved)) }
-    }
-    pub fn drv_num(&self) -> u8 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.drv_num)) }
-    }
-    pub fn reserved1(&self) -> u8 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.reserved1)) }
-    }
-    pub fn boot_sig(&self) -> u8 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.boot_sig)) }
-    }
-    pub fn vol_id(&self) -> u32 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.vol_id)) }
-    }
-    pub fn vol_lab(&self) -> [u8; 11] {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.vol_lab)) }
+        // 別のエントリに移った場合は、開いているものを新しいエントリに向け直す
+        if let Some(old) = old.filter(|entry| !entry.is_dir()) {
+            let new = volume.find_file(new_path, 0)?.0.map(|entry| entry.location);
+            OPEN_FILES.lock_wait().relocate(old.location, new);
+        }
+        Ok(())
     }
-    pub fn fil_sys_type(&self) -> u64 {
-        unsafe { ptr::read_unaligned(ptr::addr_of!(self.fil_sys_type)) }
+
+    fn sync(&self) -> Result<()> {
+        sync()
     }
 
-    pub fn as_ptr(&self) -> *const Self {
-        self as *const _
+    fn stat_fs(&self) -> Result<FsStat> {
+        let mut volume = lock_volume()?;
+        let bytes_per_cluster = volume.bytes_per_cluster() as u64;
+        Ok(FsStat {
+            total_bytes: volume.cluster_count() * bytes_per_cluster,
+            free_bytes: volume.free_clusters()? * bytes_per_cluster,
+        })
     }
 }
 
-#[repr(C)]
-pub struct DirectoryEntry {
-    pub name: [u8; 11],
-    pub attr: u8,
-    pub nt_res: u8,
-    pub crt_time_tenth: u8,
-    pub crt_time: u16,
-    pub crt_date: u16,
-    pub lst_acc_date: u16,
-    pub fst_clus_hl: u16,
-    pub wrt_time: u16,
-    pub wrt_date: u16,
-    pub fst_clus_lo: u16,
-    pub file_size: u32,
+/// ルートディレクトリの先頭クラスタを返す。ブートボリュームをマウントした後に呼ぶこと。
+pub fn root_cluster() -> u64 {
+    VOLUME.lock_wait().root_cluster()
 }
 
-impl DirectoryEntry {
-    pub fn first_cluster(&self) -> u32 {
-        (self.fst_clus_hl as u32) << 16 | self.fst_clus_lo as u32
-    }
-
-    pub fn set_first_cluster(&mut self, clus: u32) {
-        self.fst_clus_lo = clus as _;
-        self.fst_clus_hl = clus.get_bits(16..) as _;
-    }
+/// `path` が絶対パスのときはルートディレクトリ、
+/// 相対パスの場合は `directory_cluster` を基準としてファイル、ディレクトリを検索する。
+/// `directory_cluster` が `0` のときはルートディレクトリを基準として探索する。
+///
+/// 見つかったファイルもしくはディレクトリと、`/` がその直後にあるかどうかを返す。
+pub fn find_file(path: &str, directory_cluster: u64) -> Result<(Option<Entry>, bool)> {
+    Ok(lock_volume()?.find_file(path, directory_cluster)?)
+}
 
-    fn name_is_equal(&self, name: &str) -> bool {
-        // `name` を名前と拡張子に分割
-        let (base, ext) = match name.rsplit_once('.') {
-            Some(res) => (res.0, res.1),
-            None => (name, ""),
-        };
-        if base.len() > 8 || ext.len() > 3 {
-            return false;
-        }
+/// `cursor` が指すディレクトリ内の次のエントリを返し、`cursor` を進める。
+pub fn next_entry(cursor: &mut DirCursor) -> Result<Option<Entry>> {
+    Ok(lock_volume()?.next_entry(cursor)?)
+}
 
-        let base = base
-            .as_bytes()
-            .iter()
-            .map(|c| c.to_ascii_uppercase())
-            .chain((base.len()..8).map(|_| 0x20));
-        let ext = ext
-            .as_bytes()
-            .iter()
-            .map(|c| c.to_ascii_uppercase())
-            .chain((ext.len()..3).map(|_| 0x20));
-        let name = base.chain(ext);
-
-        self.name.into_iter().eq(name)
-    }
+/// 開いているファイル `id` を閉じる。
+pub fn close_file(id: OpenFileId) {
+    OPEN_FILES.lock_wait().locations.remove(&id);
 }
 
-impl PartialEq<&DirectoryEntry> for &DirectoryEntry {
-    fn eq(&self, other: &&DirectoryEntry) -> bool {
-        ptr::eq(*self, *other)
-    }
+/// 開いているファイル `id` のディレクトリエントリを読み込む。
+/// ファイルが削除されている場合はエラーを返す。
+pub fn dir_entry(id: OpenFileId) -> Result<DirectoryEntry> {
+    let mut volume = lock_volume()?;
+    let location = OPEN_FILES.lock_wait().location(id)?;
+    Ok(volume.dir_entry(location)?)
 }
 
-impl Eq for &DirectoryEntry {}
+/// 開いているファイル `id` の `offset` バイト目から `data` を書き込み、書き込んだバイト数を返す。
+pub fn write_file(id: OpenFileId, offset: usize, data: &[u8]) -> Result<usize> {
+    let mut volume = lock_volume()?;
+    let location = OPEN_FILES.lock_wait().location(id)?;
+    Ok(volume.write_file(location, offset, data)?)
+}
 
-impl Hash for &DirectoryEntry {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        let ptr = *self as *const DirectoryEntry;
-        ptr.hash(state);
+/// 開いているファイル `id` を `size` バイトに切り詰め、要らなくなったクラスタを解放する。
+pub fn truncate_file(id: OpenFileId, size: usize) -> Result<()> {
+    let first_cluster = {
+        let mut volume = lock_volume()?;
+        let location = OPEN_FILES.lock_wait().location(id)?;
+        let first_cluster = volume.dir_entry(location)?.first_cluster();
+        volume.truncate_file(location, size)?;
+        first_cluster
+    };
+    // 解放したクラスタのページや、切り詰めた部分の内容を残してはいけない
+    if first_cluster != 0 {
+        page_cache::invalidate(first_cluster as _);
     }
+    Ok(())
 }
 
-#[repr(u8)]
-pub enum Attribute {
-    ReadOnly = 0x01,
-    Hidden = 0x02,
-    System = 0x04,
-    VolumeID = 0x08,
-    Directory = 0x10,
-    Archive = 0x20,
-    LongName = 0x0f,
+pub fn load_file(entry: &DirectoryEntry) -> Vec<u8> {
+    let mut buf = vec![0; entry.file_size as usize];
+    let n = page_cache::read(entry, 0, &mut buf);
+    buf.truncate(n);
+    buf
 }
 
-fn get_cluster_addr(cluster: u64) -> *const u32 {
-    let image = BOOT_VOLUME_IMAGE.get();
-    let sector_num = image.rsvd_sec_cnt() as u64
-        + image.num_fats() as u64 * image.fat_sz32() as u64
-        + (cluster - 2) * image.sec_per_clus() as u64;
-    let offset = sector_num * image.byts_per_sec() as u64;
-
-    unsafe { (image.as_ptr() as *const u32).byte_add(offset as usize) }
+/// `first_cluster` から始まるクラスタチェーンの `offset` バイト目から `buf` の長さ分を読み込み、
+/// 読み込んだバイト数を返す。クラスタチェーンの終わりに達した場合はそこまでを読む。
+/// `offset` と `buf` の長さはセクタサイズの倍数でなければならない。
+pub fn read_cluster_chain(first_cluster: u64, offset: usize, buf: &mut [u8]) -> Result<usize> {
+    Ok(lock_volume()?.read_cluster_chain(first_cluster, offset, buf)?)
 }
@@ -1,344 +1,504 @@
 use core::{
-    cmp, mem,
+    cmp,
     ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign, Not},
 };
 
-use alloc::{string::String, sync::Arc};
+use alloc::{boxed::Box, string::String, sync::Arc};
 
 use crate::{
     bitfield::BitField,
-    error::Result,
-    fat::{self, DirectoryEntry, BYTES_PER_CLUSTER, END_OF_CLUSTER_CHAIN},
+    error::{Code, Result},
+    fat::{self, DirCursor, DirectoryEntry, Entry, OpenFileId},
     keyboard::{LCONTROL_BIT, RCONTROL_BIT},
+    log,
+    logger::LogLevel,
+    make_error,
+    memory_manager::{FrameId, BYTES_PER_FRAME},
     message::MessageType,
+    page_cache,
     task::Task,
     terminal::TerminalRef,
+    vfs::File,
 };
 
+/// アプリやターミナルが開いているファイル。
+/// 読み書きは開いたファイルシステムの [File] に任せる。
 pub struct FileDescriptor {
-    inner: InnerFileDescriptor,
+    file: Box<dyn File>,
+    /// 開いたときのパス。ファイルシステム上にないものは、その種類を表す名前
+    path: String,
+    /// 開いたときのフラグ
+    flags: FileFlags,
 }
 
 impl FileDescriptor {
-    pub fn new_fat(fat_entry: &'static mut DirectoryEntry) -> Self {
-        let cluster = fat_entry.first_cluster() as _;
+    pub fn new(file: Box<dyn File>, path: &str) -> Self {
+        Self::with_flags(file, path, FileFlags::RDWR)
+    }
+
+    /// `flags` で開いたファイルディスクリプタを作る。
+    /// アクセスモードと [FileFlags::APPEND] 以外のフラグは開くときに処理しておくこと。
+    pub fn with_flags(file: Box<dyn File>, path: &str, flags: FileFlags) -> Self {
         Self {
-            inner: InnerFileDescriptor::Fat {
-                fat_entry,
-                rd_off: 0,
-                rd_cluster: cluster,
-                rd_cluster_off: 0,
-                wr_off: 0,
-                wr_cluster: cluster,
-                wr_cluster_off: 0,
-            },
+            file,
+            path: String::from(path),
+            flags,
         }
     }
 
     pub fn new_term(task: Arc<Task>, term: TerminalRef) -> Self {
-        Self {
-            inner: InnerFileDescriptor::Terminal { task, term },
-        }
+        Self::new(Box::new(TerminalFile { task, term }), "terminal")
     }
 
     pub fn new_pipe(task: Arc<Task>) -> Self {
-        Self {
-            inner: InnerFileDescriptor::Pipe {
-                task,
-                data: [0; 16],
-                len: 0,
-                closed: false,
-            },
-        }
+        let pipe = PipeFile {
+            task,
+            data: [0; 16],
+            len: 0,
+            closed: false,
+        };
+        Self::new(Box::new(pipe), "pipe")
+    }
+
+    /// 開いたときのパスを返す。
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// 読み込みが許されている場合は true を返す。
+    pub fn readable(&self) -> bool {
+        self.flags & FileFlags::ACCMODE != FileFlags::WRONLY
+    }
+
+    /// 書き込みが許されている場合は true を返す。
+    pub fn writable(&self) -> bool {
+        self.flags & FileFlags::ACCMODE != FileFlags::RDONLY
     }
 
     pub fn read(&mut self, buf: &mut [u8]) -> usize {
-        match self.inner {
-            InnerFileDescriptor::Fat {
-                ref fat_entry,
-                ref mut rd_off,
-                ref mut rd_cluster,
-                ref mut rd_cluster_off,
-                ..
-            } => {
-                let len = cmp::min(buf.len(), fat_entry.file_size as usize - *rd_off);
-                let bytes_per_cluster = fat::BYTES_PER_CLUSTER.get() as usize;
-
-                let mut total = 0;
-                while total < len {
-                    let n = cmp::min(len - total, bytes_per_cluster);
-                    let sec = fat::get_sector_by_cluster::<u8>(*rd_cluster, n);
-                    buf[total..total + n].copy_from_slice(sec);
-                    total += n;
-
-                    *rd_cluster_off += n;
-                    if *rd_cluster_off == bytes_per_cluster {
-                        *rd_cluster = fat::next_cluster(*rd_cluster);
-                        *rd_cluster_off = 0;
-                    }
-                }
+        self.file.read(buf)
+    }
 
-                *rd_off += total;
-                total
+    /// `buf` を書き込み、書き込んだバイト数を返す。
+    /// [FileFlags::APPEND] で開いた場合は、その時点のファイルの終わりに書き込む。
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.flags & FileFlags::APPEND != FileFlags::new(0) {
+            match self.file.seek(self.size()) {
+                // デバイスやパイプは書き込む位置を持たないので、そのまま書き込む
+                Err(e) if e.cause() == Code::InvalidFile => {}
+                res => res?,
             }
-            InnerFileDescriptor::Terminal {
-                ref task,
-                ref mut term,
-            } => {
-                loop {
-                    // Task::recieve_message は Mutex でガードされているので、
-                    // 割り込みは禁止しなくて良い
-                    let msg = match task.receive_message() {
-                        Some(m) => m,
-                        None => {
-                            task.sleep();
-                            continue;
-                        }
-                    };
-                    if let MessageType::KeyPush {
-                        ascii,
-                        press,
-                        modifier,
-                        keycode,
-                    } = msg.ty
-                    {
-                        if !press {
-                            continue;
-                        }
-                        if modifier.get_bit(LCONTROL_BIT) | modifier.get_bit(RCONTROL_BIT) {
-                            let mut s = [b'^', 0];
-                            s[1] = ascii.to_ascii_uppercase();
-                            // Safety: キーボードから入力できる文字と ^ から構成されている
-                            let s = unsafe { core::str::from_utf8_unchecked(&s) };
-                            term.print(s);
-                            // D
-                            if keycode == 7 {
-                                // EOT
-                                return 0;
-                            }
-                            continue;
-                        }
-
-                        buf[0] = ascii;
-                        // Safety: キーボードから入力できる文字から構成されている
-                        let buf = unsafe { core::str::from_utf8_unchecked(&buf[..1]) };
-                        term.print(buf);
-                        term.redraw();
-                        return 1;
-                    }
+        }
+        self.file.write(buf)
+    }
+
+    /// 読み書きする位置を、ファイル先頭から `offset` バイトの位置に移動する。
+    /// `offset` はファイルサイズ以下でなければならない。
+    pub fn seek(&mut self, offset: usize) -> Result<()> {
+        self.file.seek(offset)
+    }
+
+    /// ファイルを `size` バイトに切り詰める。`size` は今のファイルサイズ以下でなければならない。
+    pub fn truncate(&mut self, size: usize) -> Result<()> {
+        self.file.truncate(size)
+    }
+
+    /// 読み書きする位置のファイル先頭からのオフセットを返す。
+    pub fn position(&self) -> usize {
+        self.file.position()
+    }
+
+    /// ファイルのメタデータを返す。
+    pub fn stat(&self) -> Stat {
+        self.file.stat()
+    }
+
+    /// ディレクトリ内の次のエントリのメタデータと名前を返す。
+    /// ディレクトリ以外を指している場合はエラーを返す。
+    pub fn read_dir(&mut self) -> Result<Option<(Stat, String)>> {
+        self.file.read_dir()
+    }
+
+    pub fn size(&self) -> usize {
+        self.file.stat().size as _
+    }
+
+    /// ファイルの `offset` バイト目からの 1 ページを保持するフレームを返す。
+    /// 返したフレームのマップを外すときは [page_cache::unmap_page] を呼ぶこと。
+    pub fn map_page(&self, offset: usize) -> Result<FrameId> {
+        self.file.map_page(offset)
+    }
+
+    /// [FileDescriptor::map_page] で返すフレームを、書き込み可能なまま共有する場合は true を返す。
+    pub fn map_shared(&self) -> bool {
+        self.file.map_shared()
+    }
+
+    pub fn set_terminal(&mut self, terminal: TerminalRef) {
+        self.file.set_terminal(terminal);
+    }
+
+    pub fn finish_write(&self) {
+        self.file.finish_write();
+    }
+}
+
+/// FAT 上のファイル。
+/// ディレクトリエントリは他のファイルディスクリプタからも変更されるので、使うたびにボリュームから読み込む。
+/// 削除されたファイルは読み書きできなくなる。
+pub struct FatFile {
+    /// 開いているファイルの ID。
+    id: OpenFileId,
+    /// 読み書きする位置のファイル先頭からのオフセット。
+    off: usize,
+}
+
+impl FatFile {
+    pub fn new(id: OpenFileId) -> Self {
+        Self { id, off: 0 }
+    }
+}
+
+impl Drop for FatFile {
+    fn drop(&mut self) {
+        fat::close_file(self.id);
+    }
+}
+
+impl File for FatFile {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let Ok(entry) = fat::dir_entry(self.id) else {
+            return 0;
+        };
+        let total = page_cache::read(&entry, self.off, buf);
+        self.off += total;
+        total
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        // `buf` がアプリのページを指しているとボリュームのロック中にページフォルトが起きうるので、
+        // 先にカーネルのメモリにコピーしておく
+        let data = buf.to_vec();
+        let total = fat::write_file(self.id, self.off, &data)?;
+
+        // 読み込みやマップで使われているページにも書き込んだ内容を反映する
+        let entry = fat::dir_entry(self.id)?;
+        page_cache::write(entry.first_cluster() as _, self.off, &data[..total]);
+        self.off += total;
+        Ok(total)
+    }
+
+    fn stat(&self) -> Stat {
+        Stat::from(&fat::dir_entry(self.id).unwrap_or_default())
+    }
+
+    fn seek(&mut self, offset: usize) -> Result<()> {
+        if offset > fat::dir_entry(self.id)?.file_size as _ {
+            return Err(make_error!(Code::IndexOutOfRange));
+        }
+        self.off = offset;
+        Ok(())
+    }
+
+    fn truncate(&mut self, size: usize) -> Result<()> {
+        fat::truncate_file(self.id, size)?;
+        self.off = cmp::min(self.off, size);
+        Ok(())
+    }
+
+    fn position(&self) -> usize {
+        self.off
+    }
+
+    fn map_page(&self, offset: usize) -> Result<FrameId> {
+        let entry = fat::dir_entry(self.id)?;
+        page_cache::map_page(&entry, (offset / BYTES_PER_FRAME) as _)
+    }
+}
+
+/// FAT 上のディレクトリ。
+pub struct FatDir {
+    /// ディレクトリのメタデータ。
+    stat: Stat,
+    /// 次に読むエントリの位置。
+    cursor: DirCursor,
+}
+
+impl FatDir {
+    /// `entry` が指すディレクトリを開く。`None` の場合はルートディレクトリを開く。
+    pub fn new(entry: Option<Entry>) -> Self {
+        // ルートディレクトリを指す `..` は先頭クラスタが 0 になっている
+        match entry {
+            Some(entry) if entry.first_cluster() != 0 => Self {
+                stat: Stat::from(&entry.dir_entry),
+                cursor: DirCursor::new(entry.first_cluster()),
+            },
+            _ => {
+                let cluster = fat::root_cluster();
+                Self {
+                    stat: Stat {
+                        first_cluster: cluster as _,
+                        attr: fat::Attribute::Directory as _,
+                        ..Default::default()
+                    },
+                    cursor: DirCursor::new(cluster),
                 }
             }
-            InnerFileDescriptor::Pipe {
-                ref task,
-                ref mut data,
-                ref mut len,
-                ref mut closed,
-            } => {
-                // パイプ内に溜まっているデータがあればそれを渡す
-                if *len > 0 {
-                    let copy_bytes = cmp::min(buf.len(), *len);
-                    buf[..copy_bytes].copy_from_slice(&data[..copy_bytes]);
-                    *len -= copy_bytes;
-                    let (dest, src) = data.split_at_mut(*len);
-                    dest.copy_from_slice(&src[..*len]);
-                    return copy_bytes;
+        }
+    }
+}
+
+impl File for FatDir {
+    fn read(&mut self, _buf: &mut [u8]) -> usize {
+        0
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+        Err(make_error!(Code::IsDirectory))
+    }
+
+    fn stat(&self) -> Stat {
+        self.stat
+    }
+
+    fn read_dir(&mut self) -> Result<Option<(Stat, String)>> {
+        // `.` と `..`、ボリュームラベルはファイルとして見せない
+        while let Some(entry) = fat::next_entry(&mut self.cursor)? {
+            if entry.name == "." || entry.name == ".." || entry.dir_entry.is_volume_label() {
+                continue;
+            }
+            return Ok(Some((Stat::from(&entry.dir_entry), entry.name)));
+        }
+        Ok(None)
+    }
+}
+
+/// ターミナルの入出力。
+struct TerminalFile {
+    task: Arc<Task>,
+    term: TerminalRef,
+}
+
+impl File for TerminalFile {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        loop {
+            // Task::recieve_message は Mutex でガードされているので、
+            // 割り込みは禁止しなくて良い
+            let msg = match self.task.receive_message() {
+                Some(m) => m,
+                None => {
+                    self.task.sleep();
+                    continue;
                 }
-                // 溜まっているデータの処理を終えて closed なら終了
-                if *closed {
-                    return 0;
+            };
+            if let MessageType::KeyPush {
+                ascii,
+                press,
+                modifier,
+                keycode,
+            } = msg.ty
+            {
+                if !press {
+                    continue;
                 }
-
-                // パイプ内にデータは無いので、Pipe メッセージを受け取るのを待つ
-                let msg = loop {
-                    if let Some(msg) = task.receive_message() {
-                        break msg;
-                    } else {
-                        task.sleep();
-                        continue;
-                    }
-                };
-
-                if let MessageType::Pipe {
-                    data: received_data,
-                    len: received_len,
-                } = msg.ty
-                {
-                    // Pipe メッセージ長が 0 の場合はこのパイプは閉じられたので終了
-                    if received_len == 0 {
-                        *closed = true;
+                if modifier.get_bit(LCONTROL_BIT) | modifier.get_bit(RCONTROL_BIT) {
+                    let mut s = [b'^', 0];
+                    s[1] = ascii.to_ascii_uppercase();
+                    // Safety: キーボードから入力できる文字と ^ から構成されている
+                    let s = unsafe { core::str::from_utf8_unchecked(&s) };
+                    self.term.print(s);
+                    // D
+                    if keycode == 7 {
+                        // EOT
                         return 0;
                     }
-                    // それ以外の場合は、buf が保持できる分は渡し、残りは自分に溜めておいて返す
-                    let received_len = received_len as _;
-                    let copy_bytes = cmp::min(received_len, buf.len());
-                    buf[..copy_bytes].copy_from_slice(&received_data[..copy_bytes]);
-                    *len = received_len - copy_bytes;
-                    data[..*len].copy_from_slice(&received_data[copy_bytes..copy_bytes + *len]);
-                    copy_bytes
-                } else {
-                    0
+                    continue;
                 }
+
+                buf[0] = ascii;
+                // Safety: キーボードから入力できる文字から構成されている
+                let buf = unsafe { core::str::from_utf8_unchecked(&buf[..1]) };
+                self.term.print(buf);
+                self.term.redraw();
+                return 1;
             }
         }
     }
 
-    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        match self.inner {
-            InnerFileDescriptor::Fat {
-                ref mut fat_entry,
-                ref mut wr_off,
-                ref mut wr_cluster,
-                ref mut wr_cluster_off,
-                ref mut rd_cluster,
-                ..
-            } => {
-                let bytes_per_cluster = BYTES_PER_CLUSTER.get() as _;
-                let num_cluster = |bytes| (bytes + bytes_per_cluster - 1) / bytes_per_cluster;
-
-                // コンストラクタで初期化しているので、ここが 0 なのは新規ファイルのみ
-                if *wr_cluster == 0 {
-                    *wr_cluster = fat::allocate_cluster_chain(num_cluster(buf.len()))?;
-                    *rd_cluster = *wr_cluster;
-                    fat_entry.set_first_cluster(*wr_cluster as _);
-                }
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let buf = String::from_utf8_lossy(buf);
+        self.term.print(&buf);
+        self.term.redraw();
+        Ok(buf.len())
+    }
 
-                let mut total = 0;
-                while total < buf.len() {
-                    if *wr_cluster_off == bytes_per_cluster as _ {
-                        let next_cluster = fat::next_cluster(*wr_cluster);
-                        *wr_cluster = if next_cluster == END_OF_CLUSTER_CHAIN {
-                            fat::extend_cluster(*wr_cluster, num_cluster(buf.len() - total))
-                        } else {
-                            next_cluster
-                        };
-                        *wr_cluster_off = 0;
-                    }
+    fn stat(&self) -> Stat {
+        Stat::default()
+    }
 
-                    let sec = fat::get_sector_by_cluster(*wr_cluster, bytes_per_cluster);
-                    let n = cmp::min(buf.len() - total, bytes_per_cluster - *wr_cluster_off);
-                    sec[*wr_cluster_off..*wr_cluster_off + n]
-                        .copy_from_slice(&buf[total..total + n]);
+    fn set_terminal(&mut self, term: TerminalRef) {
+        self.term = term;
+    }
+}
 
-                    total += n;
-                    *wr_cluster_off += n;
-                }
+/// 別のタスクへのパイプ。書き込んだデータは Pipe メッセージで `task` に送る。
+struct PipeFile {
+    task: Arc<Task>,
+    data: [u8; 16],
+    len: usize,
+    closed: bool,
+}
 
-                *wr_off += buf.len();
-                fat_entry.file_size = *wr_off as _;
-                Ok(total)
+impl File for PipeFile {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        // パイプ内に溜まっているデータがあればそれを渡す
+        if self.len > 0 {
+            let copy_bytes = cmp::min(buf.len(), self.len);
+            buf[..copy_bytes].copy_from_slice(&self.data[..copy_bytes]);
+            self.len -= copy_bytes;
+            let (dest, src) = self.data.split_at_mut(self.len);
+            dest.copy_from_slice(&src[..self.len]);
+            return copy_bytes;
+        }
+        // 溜まっているデータの処理を終えて closed なら終了
+        if self.closed {
+            return 0;
+        }
+
+        // パイプ内にデータは無いので、Pipe メッセージを受け取るのを待つ
+        let msg = loop {
+            if let Some(msg) = self.task.receive_message() {
+                break msg;
+            } else {
+                self.task.sleep();
+                continue;
             }
-            InnerFileDescriptor::Terminal { ref mut term, .. } => {
-                let buf = String::from_utf8_lossy(buf);
-                term.print(&buf);
-                term.redraw();
-                Ok(buf.len())
-            }
-            InnerFileDescriptor::Pipe { ref task, .. } => {
-                let mut sent_bytes = 0;
-                while sent_bytes < buf.len() {
-                    let mut data = [0; 16];
-                    let len = cmp::min(buf.len() - sent_bytes, data.len());
-                    data[..len].copy_from_slice(&buf[sent_bytes..sent_bytes + len]);
-                    let msg = MessageType::Pipe {
-                        data,
-                        len: len as _,
-                    }
-                    .into();
-                    sent_bytes += len;
-                    task.send_message(msg);
-                }
-                Ok(buf.len())
+        };
+
+        if let MessageType::Pipe {
+            data: received_data,
+            len: received_len,
+        } = msg.ty
+        {
+            // Pipe メッセージ長が 0 の場合はこのパイプは閉じられたので終了
+            if received_len == 0 {
+                self.closed = true;
+                return 0;
             }
+            // それ以外の場合は、buf が保持できる分は渡し、残りは自分に溜めておいて返す
+            let received_len = received_len as _;
+            let copy_bytes = cmp::min(received_len, buf.len());
+            buf[..copy_bytes].copy_from_slice(&received_data[..copy_bytes]);
+            self.len = received_len - copy_bytes;
+            self.data[..self.len]
+                .copy_from_slice(&received_data[copy_bytes..copy_bytes + self.len]);
+            copy_bytes
+        } else {
+            0
         }
     }
 
-    pub fn size(&self) -> usize {
-        match self.inner {
-            InnerFileDescriptor::Fat { ref fat_entry, .. } => fat_entry.file_size as _,
-            _ => 0,
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut sent_bytes = 0;
+        while sent_bytes < buf.len() {
+            let mut data = [0; 16];
+            let len = cmp::min(buf.len() - sent_bytes, data.len());
+            data[..len].copy_from_slice(&buf[sent_bytes..sent_bytes + len]);
+            let msg = MessageType::Pipe {
+                data,
+                len: len as _,
+            }
+            .into();
+            sent_bytes += len;
+            self.task.send_message(msg);
         }
+        Ok(buf.len())
     }
 
-    pub fn load(&self, buf: &mut [u8], mut offset: usize) -> usize {
-        match self.inner {
-            InnerFileDescriptor::Fat { ref fat_entry, .. } => {
-                // ここで作った fat_entry は 'static ライフタイムを要求されるが、
-                // 実際はこのスコープ内で落ちる変数に渡すだけなので問題ない
-                // ただ排他参照にするのが大丈夫かは怪しい。
-                // ただ他の方法が思いつかないので一旦これで
-                let fat_entry: &mut DirectoryEntry = unsafe { mem::transmute_copy(fat_entry) };
-                let rd_off = offset;
-
-                let mut rd_cluster = fat_entry.first_cluster() as _;
-                let bytes_per_cluster = BYTES_PER_CLUSTER.get() as _;
-                while offset >= bytes_per_cluster {
-                    offset -= bytes_per_cluster;
-                    rd_cluster = fat::next_cluster(rd_cluster);
-                }
+    fn stat(&self) -> Stat {
+        Stat::default()
+    }
 
-                let inner = InnerFileDescriptor::Fat {
-                    fat_entry,
-                    rd_off,
-                    rd_cluster,
-                    rd_cluster_off: offset,
-                    wr_off: 0,
-                    wr_cluster: 0,
-                    wr_cluster_off: 0,
-                };
-                let mut fd = Self { inner };
-                fd.read(buf)
-            }
-            _ => 0,
+    fn finish_write(&self) {
+        let msg = MessageType::Pipe {
+            data: [0; 16],
+            len: 0,
         }
+        .into();
+        self.task.send_message(msg);
     }
+}
 
-    pub fn set_terminal(&mut self, terminal: TerminalRef) {
-        if let InnerFileDescriptor::Terminal { ref mut term, .. } = self.inner {
-            *term = terminal;
-        }
+/// `stat`、`fstat` システムコールで返すファイルのメタデータ。
+/// 日時は FAT のディレクトリエントリの形式のまま。
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stat {
+    pub size: u64,
+    pub first_cluster: u32,
+    pub attr: u8,
+    pub crt_time_tenth: u8,
+    pub crt_time: u16,
+    pub crt_date: u16,
+    pub wrt_time: u16,
+    pub wrt_date: u16,
+    pub lst_acc_date: u16,
+}
+
+impl Stat {
+    /// ディレクトリかどうかを返す。
+    pub fn is_dir(&self) -> bool {
+        self.attr != fat::Attribute::LongName as u8
+            && self.attr & fat::Attribute::Directory as u8 != 0
     }
+}
 
-    pub fn finish_write(&self) {
-        if let InnerFileDescriptor::Pipe { ref task, .. } = self.inner {
-            let msg = MessageType::Pipe {
-                data: [0; 16],
-                len: 0,
-            }
-            .into();
-            task.send_message(msg);
+impl From<&DirectoryEntry> for Stat {
+    fn from(entry: &DirectoryEntry) -> Self {
+        Self {
+            size: entry.file_size as _,
+            first_cluster: entry.first_cluster(),
+            attr: entry.attr,
+            crt_time_tenth: entry.crt_time_tenth,
+            crt_time: entry.crt_time,
+            crt_date: entry.crt_date,
+            wrt_time: entry.wrt_time,
+            wrt_date: entry.wrt_date,
+            lst_acc_date: entry.lst_acc_date,
         }
     }
 }
 
-enum InnerFileDescriptor {
-    Fat {
-        /// ファイルディスクリプタが指すファイルへの参照。
-        fat_entry: &'static mut DirectoryEntry,
-        /// 読み込みのファイル先頭からの読み込みオフセット。
-        rd_off: usize,
-        /// 読み込みの`rd_off` が指す位置に対応するクラスタの番号。
-        rd_cluster: u64,
-        /// 読み込みのクラスタ先頭からのオフセット。
-        rd_cluster_off: usize,
-        /// 書き込みのファイル先頭からのオフセット
-        wr_off: usize,
-        /// 書き込みのクラスタ番号
-        wr_cluster: u64,
-        /// 書き込みのクラスタ先頭からのオフセット
-        wr_cluster_off: usize,
-    },
-    Terminal {
-        task: Arc<Task>,
-        term: TerminalRef,
-    },
-    Pipe {
-        task: Arc<Task>,
-        data: [u8; 16],
-        len: usize,
-        closed: bool,
-    },
+/// `read_dir` システムコールで返すディレクトリエントリ。
+#[repr(C)]
+pub struct DirEnt {
+    pub stat: Stat,
+    /// ヌル終端されたファイル名。
+    pub name: [u8; 256],
 }
 
+impl DirEnt {
+    /// メタデータ `stat` と名前 `name` から作る。
+    /// 名前が長すぎる場合は、文字の境界で 255 バイト以下に切り詰める。
+    pub fn new(stat: Stat, name: &str) -> Self {
+        let mut len = cmp::min(name.len(), 255);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut buf = [0; 256];
+        buf[..len].copy_from_slice(&name.as_bytes()[..len]);
+
+        Self { stat, name: buf }
+    }
+}
+
+/// ファイル先頭を基準にシークする。
+pub const SEEK_SET: u64 = 0;
+/// 現在の読み書きする位置を基準にシークする。
+pub const SEEK_CUR: u64 = 1;
+/// ファイル末尾を基準にシークする。
+pub const SEEK_END: u64 = 2;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct FileFlags(i32);
 
@@ -352,6 +512,9 @@ impl FileFlags {
     pub const WRONLY: Self = Self(1);
     pub const RDWR: Self = Self(2);
     pub const CREAT: Self = Self(0o100);
+    pub const EXCL: Self = Self(0o200);
+    pub const TRUNC: Self = Self(0o1000);
+    pub const APPEND: Self = Self(0o2000);
 }
 
 impl From<FileFlags> for i32 {
@@ -412,6 +575,14 @@ impl Not for FileFlags {
     }
 }
 
+/// `fd` に `s` を書き込み、書き込んだバイト数を返す。
+/// 書き込めなかった場合（書き込めないファイルや、空きのないファイルシステムなど）はログに残して 0 を返す。
 pub fn print_to_fd(fd: &mut FileDescriptor, s: &str) -> usize {
-    fd.write(s.as_bytes()).unwrap()
+    match fd.write(s.as_bytes()) {
+        Ok(n) => n,
+        Err(e) => {
+            log!(LogLevel::Warn, "failed to write to {}: {}", fd.path(), e);
+            0
+        }
+    }
 }
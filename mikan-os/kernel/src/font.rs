@@ -14,11 +14,11 @@ const FONT_PATH: &str = "/ipag.ttf";
 static FONT: OnceStatic<Font> = OnceStatic::new();
 
 pub fn init() -> Result<()> {
-    let (Some(entry), false) = fat::find_file(FONT_PATH, 0) else {
+    let (Some(entry), false) = fat::find_file(FONT_PATH, 0)? else {
         return Err(make_error!(Code::NoSuchEntry));
     };
 
-    let buf = fat::load_file(entry);
+    let buf = fat::load_file(&entry.dir_entry);
     let Some(font) = Font::try_from_vec_and_index(buf, 0) else {
         return Err(make_error!(Code::FreeTypeError));
     };
@@ -50,6 +50,10 @@ pub fn write_unicode(writer: &mut dyn PixelWrite, pos: Vector2D<i32>, c: char, c
 
     if c.is_ascii() {
         write_ascii(writer, pos, c as u8, color);
+    } else if !FONT.is_initialized() {
+        // フォントを読み込めなかった場合は、ASCII 以外の文字を描けない
+        write_ascii(writer, pos, b'?', color);
+        write_ascii(writer, pos + Vector2D::new(8, 0), b'?', color);
     } else {
         let font = FONT.as_ref();
         // フォントに含まれる文字のベースラインからの最高点らしい
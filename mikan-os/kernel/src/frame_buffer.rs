@@ -225,7 +225,8 @@ fn bytes_per_pixel(format: &PixelFormat) -> usize {
     }
 }
 
-fn bytes_per_scan_line(config: &FrameBufferConfig) -> usize {
+/// 1 行分のピクセルが占めるバイト数を返す。
+pub fn bytes_per_scan_line(config: &FrameBufferConfig) -> usize {
     bytes_per_pixel(&config.pixel_format) * config.pixels_per_scan_line
 }
 
@@ -29,6 +29,8 @@ pub fn init() {
     let mut set_idt_entry = |irq: usize, handler| idt[irq].set_idt_entry(handler, KERNEL_CS, 0);
 
     set_idt_entry(InterruptVector::XHCI as _, int_handler_xhci);
+    set_idt_entry(InterruptVector::AHCI as _, int_handler_ahci);
+    set_idt_entry(InterruptVector::NVMe as _, int_handler_nvme);
     set_idt_entry(0, int_handler_de);
     set_idt_entry(1, int_handler_db);
     set_idt_entry(3, int_handler_bp);
@@ -144,6 +146,19 @@ fn int_handler_xhci(_frame: &InterruptFrame) {
     notify_end_of_interrupt();
 }
 
+#[custom_attribute::interrupt]
+fn int_handler_ahci(_frame: &InterruptFrame) {
+    // コマンドの完了はドライバがポーリングで確認している
+    notify_end_of_interrupt();
+}
+
+#[custom_attribute::interrupt]
+fn int_handler_nvme(_frame: &InterruptFrame) {
+    // コマンドの完了はドライバがコンプリーションキューをポーリングして確認している。
+    // ロックを持ったまま読み書きされることもあるので、ここでタスクを起こして待たせることはしない
+    notify_end_of_interrupt();
+}
+
 #[custom_attribute::interrupt]
 fn int_handler_pf(frame: &InterruptFrame, error_code: u64) {
     let cr2 = asmfunc::get_cr2();
@@ -174,6 +189,11 @@ int_handler_lapic_timer: # int_handler_lapic_timer()
     push rbp
     mov rbp, rsp
 
+    # TaskContext の fxsave_area に当たる領域を 16 バイト境界に確保して保存する
+    sub rsp, 512
+    and rsp, 0xfffffffffffffff0
+    fxsave64 [rsp]
+
     # スタック上に TaskContex 型の構造を構築する
     push r15
     push r14
@@ -226,6 +246,8 @@ int_handler_lapic_timer: # int_handler_lapic_timer()
     pop r14
     pop r15
 
+    fxrstor64 [rsp]
+
     mov rsp, rbp
     pop rbp
     iretq
@@ -325,6 +347,8 @@ pub fn notify_end_of_interrupt() {
 pub enum InterruptVector {
     XHCI = 0x40,
     LAPICTimer = 0x41,
+    AHCI = 0x42,
+    NVMe = 0x43,
 }
 
 pub struct InterruptFrame {
@@ -3,11 +3,14 @@
 extern crate alloc;
 
 pub mod acpi;
+pub mod ahci;
 pub mod app_event;
 pub mod asmfunc;
 pub mod bitfield;
+pub mod block;
 pub mod collections;
 pub mod console;
+pub mod devfs;
 pub mod elf;
 pub mod errno;
 pub mod error;
@@ -27,16 +30,22 @@ pub mod memory_map;
 pub mod message;
 pub mod mouse;
 pub mod msr;
+pub mod nvme;
+pub mod page_cache;
 pub mod paging;
 pub mod pci;
+pub mod procfs;
 pub mod segment;
 pub mod sync;
 pub mod syscall;
 pub mod task;
 pub mod terminal;
 pub mod timer;
+pub mod tmpfs;
 pub mod usb;
 pub mod util;
+pub mod vfs;
+pub mod virtio_blk;
 pub mod window;
 pub mod x86_descriptor;
 pub mod xhci;
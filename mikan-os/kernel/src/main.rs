@@ -3,28 +3,32 @@
 
 extern crate alloc;
 
-use alloc::format;
-use core::{ffi::c_void, panic::PanicInfo};
+use alloc::{format, sync::Arc};
+use core::panic::PanicInfo;
 use uefi::table::boot::MemoryMap;
 
 use kernel::{
     acpi::RSDP,
+    ahci,
     asmfunc::{self, cli, halt, sti},
     console::{self, PanicConsole},
+    devfs,
     error::Result,
-    fat, font,
+    fat::{self, BootVolume},
+    font,
     frame_buffer_config::FrameBufferConfig,
     graphics::{PixelColor, PixelWrite, Vector2D, FB_CONFIG},
     interrupt, keyboard,
     layer::{self, LAYER_MANAGER, LAYER_TASK_MAP, SCREEN},
     log,
     logger::{set_log_level, LogLevel},
-    memory_manager::{GLOBAL, MEMORY_MANAGER},
+    memory_manager::MEMORY_MANAGER,
     message::{Message, MessageType},
-    mouse, paging, pci, printk, printkln, segment, syscall,
+    mouse, nvme, paging, pci, printk, printkln, procfs, segment, syscall,
     task::{self, Stack},
     terminal,
     timer::{self, Timer, TIMER_MANAGER},
+    tmpfs, vfs, virtio_blk,
     window::Window,
     xhci::{self, XHC},
 };
@@ -32,6 +36,9 @@ use kernel::{
 #[no_mangle]
 static KERNEL_MAIN_STACK: Stack<STACK_SIZE> = Stack::new();
 
+/// /tmp に置けるファイルの合計サイズ
+const TMP_SIZE: usize = 16 * 1024 * 1024;
+
 /// メインウィンドウの初期化を行う。
 fn initialize_main_window() -> u32 {
     let mut layer_manager = LAYER_MANAGER.lock_wait();
@@ -91,19 +98,21 @@ fn kernel_entry(
     kernel_base: usize,
     kernel_size: usize,
     acpi_table: &RSDP,
-    volume_image: *mut c_void,
+    boot_volume: &'static BootVolume,
 ) {
     FB_CONFIG.init(frame_buffer_config.clone());
+    // ローダーのスタック上にあり、メモリアロケータの初期化後は上書きされ得るので複製しておく
+    let boot_volume = boot_volume.clone();
     // メモリアロケータの初期化
+    // ヒープは必要になったときに MEMORY_MANAGER から確保される
     MEMORY_MANAGER.init(memory_map, kernel_base, kernel_size);
-    GLOBAL.init(64 * 512); // 128 MiB 確保
 
-    if let Err(err) = main(acpi_table, volume_image) {
+    if let Err(err) = main(acpi_table, &boot_volume) {
         printkln!("{}", err);
     }
 }
 
-fn main(acpi_table: &RSDP, volume_image: *mut c_void) -> Result<()> {
+fn main(acpi_table: &RSDP, boot_volume: &BootVolume) -> Result<()> {
     layer::init();
     console::init();
 
@@ -118,10 +127,24 @@ fn main(acpi_table: &RSDP, volume_image: *mut c_void) -> Result<()> {
     segment::init();
     paging::init();
     interrupt::init();
+    // ブロックデバイスのドライバがタイムアウトを計るのに PM タイマーを使う
+    acpi_table.init()?;
 
-    fat::init(volume_image);
-    font::init()?;
     pci::init()?;
+    virtio_blk::init();
+    ahci::init();
+    nvme::init();
+    // ブートボリュームを読めるドライバがない場合でも、ルートをマウントせずに起動を続ける
+    match fat::init(boot_volume) {
+        Ok(()) => vfs::mount("/", Arc::new(fat::FatFileSystem))?,
+        Err(e) => log!(LogLevel::Error, "failed to mount the boot volume: {}", e),
+    }
+    vfs::mount("/proc", Arc::new(procfs::ProcFileSystem))?;
+    vfs::mount("/dev", Arc::new(devfs::DevFileSystem))?;
+    vfs::mount("/tmp", Arc::new(tmpfs::TmpFileSystem::new(TMP_SIZE)))?;
+    if let Err(e) = font::init() {
+        log!(LogLevel::Error, "failed to load the font: {}", e);
+    }
 
     let main_window_id = initialize_main_window();
     let text_window_id = initialize_text_window();
@@ -130,7 +153,6 @@ fn main(acpi_table: &RSDP, volume_image: *mut c_void) -> Result<()> {
     //        必ず全て表示されるが、ハードコードは良くなさそう
     LAYER_MANAGER.lock_wait().draw_id(1);
 
-    acpi_table.init()?;
     timer::init();
 
     // カーソル点滅用のタイマを追加
@@ -342,7 +364,7 @@ fn panic(info: &PanicInfo) -> ! {
 fn test_lib() {
     use alloc::string::String;
 
-    use kernel::collections::HashMap;
+    use kernel::{collections::HashMap, file::FileDescriptor, vfs};
 
     let mut map = HashMap::<String, _>::new();
 
@@ -370,5 +392,18 @@ fn test_lib() {
         assert_eq!(map.remove(&format!("{}", i)).unwrap(), -i);
     }
 
+    // 書き込んだ後の位置は書き込んだ分だけ進んでいて、そこにシークしても続きから書ける
+    let path = "/tmp/test_lib";
+    let mut fd = FileDescriptor::new(vfs::create(path).unwrap(), path);
+    assert_eq!(fd.write(b"hello").unwrap(), 5);
+    assert_eq!(fd.position(), 5);
+    fd.seek(fd.position()).unwrap();
+    assert_eq!(fd.write(b" world").unwrap(), 6);
+    fd.seek(0).unwrap();
+    let mut buf = [0; 16];
+    assert_eq!(fd.read(&mut buf), 11);
+    assert_eq!(&buf[..11], b"hello world");
+    vfs::remove(path).unwrap();
+
     log!(LogLevel::Info, "tests in test_lib() all succeeds");
 }
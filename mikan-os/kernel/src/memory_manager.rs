@@ -237,65 +237,86 @@ impl BitmapMemoryManager {
     }
 }
 
-/// 割り当てられた領域の先頭から必要な領域を渡し、
-/// 先頭をその分増加させいくだけのメモリ割り当てを行う。
+/// スラブアロケータで扱う最小のブロックサイズ。
+const MIN_SLAB_SIZE: usize = 16;
+/// スラブアロケータで扱う最大のブロックサイズ。
+/// これより大きい領域は [MEMORY_MANAGER] からフレーム単位で直接割り当てる。
+const MAX_SLAB_SIZE: usize = 2 * KIB;
+/// サイズクラスの数。
+/// [MIN_SLAB_SIZE] から [MAX_SLAB_SIZE] まで2の冪ごとに1つのクラスがある。
+const NUM_SIZE_CLASSES: usize =
+    (MAX_SLAB_SIZE.trailing_zeros() - MIN_SLAB_SIZE.trailing_zeros()) as usize + 1;
+
+/// カーネルヒープのアロケータ。
+///
+/// [MAX_SLAB_SIZE] 以下の領域は2の冪のサイズクラスごとのスラブから割り当て、
+/// それより大きい領域は [MEMORY_MANAGER] からフレーム単位で割り当てる。
+/// スラブが足りなくなったら [MEMORY_MANAGER] から1フレームずつ追加するので、
+/// 物理メモリが尽きるまでヒープを拡張できる。
 pub struct Global {
-    /// 現在の先頭アドレスを表す。
-    cur: Mutex<usize>,
-    /// 割り当てられる領域の末尾のアドレスを表す。
-    end: Mutex<usize>,
+    /// サイズクラスごとの空きブロックのリストの先頭アドレス。`0` の場合は空。
+    ///
+    /// 空きブロックの先頭には次の空きブロックのアドレスが書き込まれている。
+    free_lists: Mutex<[usize; NUM_SIZE_CLASSES]>,
 }
 
 impl Global {
     pub const fn new() -> Self {
         Self {
-            cur: Mutex::new(0),
-            end: Mutex::new(0),
+            free_lists: Mutex::new([0; NUM_SIZE_CLASSES]),
         }
     }
 
-    pub fn init(&self, num_frames: usize) {
-        // Sefety: シングルコア時に初期化されるはずなのでロックは取らない
-        if self.is_initialized() {
-            panic!("initialize Global multiple times");
+    /// `free_lists[class]` にブロックを1フレーム分追加する。
+    ///
+    /// * `free_lists` - サイズクラスごとの空きブロックのリスト。
+    /// * `class` - ブロックを追加するサイズクラス。
+    fn refill(free_lists: &mut [usize; NUM_SIZE_CLASSES], class: usize) -> Result<()> {
+        let frame = MEMORY_MANAGER.allocate(1)?;
+        let block_size = block_size(class);
+
+        // フレームの末尾から順にリストへつなげていき、先頭のブロックから使われるようにする
+        for i in (0..BYTES_PER_FRAME / block_size).rev() {
+            let block = frame.frame() as usize + i * block_size;
+            unsafe { (block as *mut usize).write(free_lists[class]) };
+            free_lists[class] = block;
         }
-
-        let start = match MEMORY_MANAGER.allocate(num_frames) {
-            Ok(ptr) => ptr,
-            Err(e) => panic!("{}", e),
-        };
-
-        *self.cur.lock_wait() = start.frame() as _;
-        *self.end.lock_wait() = start.frame() as usize + num_frames * BYTES_PER_FRAME;
-    }
-
-    fn is_initialized(&self) -> bool {
-        *self.cur.lock_wait() != 0
+        Ok(())
     }
 }
 
 unsafe impl GlobalAlloc for Global {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut cur = self.cur.lock_wait();
-
-        let start = *cur;
-        // アラインメント調整
-        let start = match start % layout.align() {
-            0 => start,
-            rem => start + layout.align() - rem,
+        let Some(class) = size_class(&layout) else {
+            // フレームは 4 KiB にしかアラインされていない
+            if layout.align() > BYTES_PER_FRAME {
+                return ptr::null_mut();
+            }
+            return match MEMORY_MANAGER.allocate(get_num_frames(layout.size())) {
+                Ok(frame) => frame.frame(),
+                Err(_) => ptr::null_mut(),
+            };
         };
 
-        // 領域を超えないか確認
-        let end = start + layout.size();
-        if end >= *self.end.lock_wait() {
+        let mut free_lists = self.free_lists.lock_wait();
+        if free_lists[class] == 0 && Self::refill(&mut free_lists, class).is_err() {
             return ptr::null_mut();
         }
-        *cur = end;
-        start as _
+
+        let block = free_lists[class];
+        free_lists[class] = (block as *const usize).read();
+        block as _
     }
 
-    unsafe fn dealloc(&self, _: *mut u8, _: Layout) {
-        // 解放は特になにもしない
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(class) = size_class(&layout) else {
+            MEMORY_MANAGER.free(FrameId::from_addr(ptr as _), get_num_frames(layout.size()));
+            return;
+        };
+
+        let mut free_lists = self.free_lists.lock_wait();
+        (ptr as *mut usize).write(free_lists[class]);
+        free_lists[class] = ptr as _;
     }
 }
 
@@ -310,6 +331,28 @@ pub struct MemoryStat {
     pub total_frames: usize,
 }
 
+/// `layout` に対応するサイズクラスを返す。
+/// スラブで扱えない大きさの場合は `None` を返す。
+///
+/// ブロックはそのサイズでアラインされているので、アラインメントもサイズとして扱う。
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = layout
+        .size()
+        .max(layout.align())
+        .max(MIN_SLAB_SIZE)
+        .next_power_of_two();
+    if size > MAX_SLAB_SIZE {
+        None
+    } else {
+        Some((size.trailing_zeros() - MIN_SLAB_SIZE.trailing_zeros()) as _)
+    }
+}
+
+/// サイズクラス `class` のブロックのサイズ。
+const fn block_size(class: usize) -> usize {
+    MIN_SLAB_SIZE << class
+}
+
 fn get_num_frames(size: usize) -> usize {
     (size + BYTES_PER_FRAME - 1) / BYTES_PER_FRAME
 }
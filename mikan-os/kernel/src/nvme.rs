@@ -0,0 +1,458 @@
+//! NVMe のデバイスドライバ。
+//!
+//! アドミンキューと 1 組の I/O キューを作り、名前空間ごとにブロックデバイスを登録する。
+//! コマンドは 1 つずつ発行し、完了キューのフェーズビットをポーリングして完了を待つ。
+//! ブロックデバイスはロックを持ったままや、タスクを切り替えられないところからも読み書きされるので、
+//! MSI 割り込みで起こしてもらうのではなくポーリングで待つ。
+//! 待ち時間には上限を設け、コントローラが応答しない場合は [Code::TransferFailed] を返す。
+
+use core::{
+    hint::spin_loop,
+    ptr,
+    sync::atomic::{fence, Ordering},
+};
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+
+use crate::{
+    acpi::Deadline,
+    bitfield::BitField as _,
+    block::{self, BlockDevice},
+    error::{Code, Result},
+    interrupt::InterruptVector,
+    log,
+    logger::LogLevel,
+    make_error,
+    memory_manager::{BYTES_PER_FRAME, MEMORY_MANAGER},
+    pci,
+    sync::Mutex,
+};
+
+// コントローラのレジスタのオフセット
+const REG_CAP: usize = 0x00;
+const REG_CC: usize = 0x14;
+const REG_CSTS: usize = 0x1c;
+const REG_AQA: usize = 0x24;
+const REG_ASQ: usize = 0x28;
+const REG_ACQ: usize = 0x30;
+const REG_DOORBELL: usize = 0x1000;
+
+const CC_EN: u32 = 1 << 0;
+/// I/O サブミッションキューのエントリサイズ（2^6 = 64 バイト）
+const CC_IOSQES: u32 = 6 << 16;
+/// I/O コンプリーションキューのエントリサイズ（2^4 = 16 バイト）
+const CC_IOCQES: u32 = 4 << 20;
+
+const CSTS_RDY: u32 = 1 << 0;
+const CSTS_CFS: u32 = 1 << 1;
+
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+/// Identify コマンドで名前空間の情報を得る
+const CNS_NAMESPACE: u32 = 0x00;
+/// Identify コマンドで有効な名前空間 ID の一覧を得る
+const CNS_ACTIVE_NAMESPACES: u32 = 0x02;
+
+/// 各キューのエントリ数
+const QUEUE_SIZE: u16 = 64;
+const IO_QUEUE_ID: u16 = 1;
+
+/// コマンドの完了を待つ最大のミリ秒数
+const COMMAND_TIMEOUT_MSEC: u64 = 5000;
+
+/// 1 つのコマンドで転送する最大のバイト数。
+/// PRP1 と PRP2 だけで表せる 2 ページ分に制限する。
+const MAX_TRANSFER_BYTES: usize = 2 * BYTES_PER_FRAME;
+
+pub fn init() {
+    let devices = pci::DEVICES.read();
+    for dev in devices
+        .iter()
+        .filter(|dev| dev.class_code().r#match(0x01, 0x08, 0x02))
+    {
+        log!(
+            LogLevel::Info,
+            "NVMe controller has been found: {}.{}.{}",
+            dev.bus(),
+            dev.device(),
+            dev.function()
+        );
+        if let Err(e) = init_controller(*dev) {
+            log!(
+                LogLevel::Error,
+                "failed to initialize NVMe controller: {}",
+                e
+            );
+        }
+    }
+}
+
+fn init_controller(mut dev: pci::Device) -> Result<()> {
+    // メモリ空間へのアクセスとバスマスタを有効にする
+    let command = dev.read_conf_reg(0x04);
+    dev.write_conf_reg(0x04, command | 0b110);
+
+    let bsp_local_apic_id = (unsafe { *(0xfee0_0020 as *const u32) } >> 24) as u8;
+    if let Err(e) = dev.configure_msi_fixed_destination(
+        bsp_local_apic_id,
+        pci::MSITriggerMode::Edge,
+        pci::MSIDeliverMode::Fixed,
+        InterruptVector::NVMe as u8,
+        0,
+    ) {
+        log!(LogLevel::Warn, "NVMe: MSI is not available: {}", e);
+    }
+
+    let mmio_base = (dev.read_bar(0)? & !0xf) as usize;
+    let mut ctrl = Controller::new(mmio_base)?;
+
+    let namespaces = ctrl.identify(CNS_ACTIVE_NAMESPACES, 0)?;
+    let nsids: Vec<u32> = namespaces
+        .chunks_exact(4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .take_while(|&nsid| nsid != 0)
+        .collect();
+
+    let mut infos = Vec::new();
+    for nsid in nsids {
+        let info = ctrl.identify(CNS_NAMESPACE, nsid)?;
+        let capacity = u64::from_le_bytes(info[0..8].try_into().unwrap());
+        // FLBAS が指す LBA フォーマットからセクタサイズを得る
+        let format = (info[26] & 0xf) as usize;
+        let lbads = info[128 + format * 4 + 2];
+        infos.push((nsid, capacity, 1usize << lbads));
+    }
+
+    let ctrl = Arc::new(Mutex::new(ctrl));
+    for (nsid, capacity, sector_size) in infos {
+        log!(
+            LogLevel::Info,
+            "NVMe namespace {}: capacity = {} sectors, sector size = {}",
+            nsid,
+            capacity,
+            sector_size
+        );
+        block::register(Box::new(Namespace {
+            ctrl: ctrl.clone(),
+            nsid,
+            capacity,
+            sector_size,
+        }));
+    }
+    Ok(())
+}
+
+/// サブミッションキューとコンプリーションキューの組。
+struct QueuePair {
+    id: u16,
+    /// キューのエントリ数
+    size: u16,
+    sq: *mut [u32; 16],
+    cq: *mut [u32; 4],
+    sq_tail: u16,
+    cq_head: u16,
+    /// 次に読むコンプリーションエントリが新しいことを示すフェーズビットの値
+    phase: bool,
+    /// コマンドがタイムアウトした場合は true。
+    /// 後から完了したエントリを別のコマンドの完了と取り違えないように、以降は使わない
+    broken: bool,
+}
+
+impl QueuePair {
+    fn new(id: u16, size: u16) -> Result<Self> {
+        let sq = MEMORY_MANAGER.allocate(1)?.frame();
+        let cq = MEMORY_MANAGER.allocate(1)?.frame();
+        unsafe {
+            ptr::write_bytes(sq, 0, BYTES_PER_FRAME);
+            ptr::write_bytes(cq, 0, BYTES_PER_FRAME);
+        }
+        Ok(Self {
+            id,
+            size,
+            sq: sq as _,
+            cq: cq as _,
+            sq_tail: 0,
+            cq_head: 0,
+            phase: true,
+            broken: false,
+        })
+    }
+}
+
+struct Controller {
+    mmio_base: usize,
+    /// ドアベルレジスタの間隔（バイト）
+    doorbell_stride: usize,
+    /// CSTS.RDY が変わるのを待つ最大のミリ秒数
+    ready_timeout_msec: u64,
+    admin: QueuePair,
+    io: QueuePair,
+    /// DMA で転送するデータを置くバッファ
+    bounce: *mut u8,
+    next_command_id: u16,
+}
+
+// Safety: 生ポインタはこの構造体が確保したフレームのみを指している
+unsafe impl Send for Controller {}
+
+impl Controller {
+    fn new(mmio_base: usize) -> Result<Self> {
+        let bounce = MEMORY_MANAGER
+            .allocate(MAX_TRANSFER_BYTES / BYTES_PER_FRAME)?
+            .frame();
+        let cap = unsafe { ptr::read_volatile((mmio_base + REG_CAP) as *const u64) };
+        let max_entries = cap.get_bits(0..16) as u16 + 1;
+        let queue_size = QUEUE_SIZE.min(max_entries);
+
+        let mut ctrl = Self {
+            mmio_base,
+            doorbell_stride: 4 << cap.get_bits(32..36),
+            // CAP.TO は 500 ミリ秒単位
+            ready_timeout_msec: cap.get_bits(24..32).max(1) * 500,
+            admin: QueuePair::new(0, queue_size)?,
+            io: QueuePair::new(IO_QUEUE_ID, queue_size)?,
+            bounce,
+            next_command_id: 0,
+        };
+        let queue_size = queue_size as u32;
+
+        // コントローラを止めてからアドミンキューを設定する
+        ctrl.write32(REG_CC, 0);
+        ctrl.wait_ready(false)?;
+
+        ctrl.write32(REG_AQA, (queue_size - 1) << 16 | (queue_size - 1));
+        // カーネルの領域はアイデンティティマッピングされているので、
+        // 仮想アドレスをそのまま物理アドレスとして渡せる
+        ctrl.write64(REG_ASQ, ctrl.admin.sq as u64);
+        ctrl.write64(REG_ACQ, ctrl.admin.cq as u64);
+        ctrl.write32(REG_CC, CC_EN | CC_IOSQES | CC_IOCQES);
+        ctrl.wait_ready(true)?;
+
+        // I/O コンプリーションキューは割り込みを有効にして作る
+        let io_cq = ctrl.io.cq as u64;
+        let io_sq = ctrl.io.sq as u64;
+        let size = (queue_size - 1) << 16;
+        ctrl.admin_command(
+            ADMIN_CREATE_IO_CQ,
+            0,
+            io_cq,
+            [IO_QUEUE_ID as u32 | size, 0b11, 0, 0, 0, 0],
+        )?;
+        ctrl.admin_command(
+            ADMIN_CREATE_IO_SQ,
+            0,
+            io_sq,
+            [
+                IO_QUEUE_ID as u32 | size,
+                (IO_QUEUE_ID as u32) << 16 | 1,
+                0,
+                0,
+                0,
+                0,
+            ],
+        )?;
+        Ok(ctrl)
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.mmio_base + offset) as *const u32) }
+    }
+
+    fn write32(&self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.mmio_base + offset) as *mut u32, value) }
+    }
+
+    fn write64(&self, offset: usize, value: u64) {
+        self.write32(offset, value as u32);
+        self.write32(offset + 4, (value >> 32) as u32);
+    }
+
+    /// CSTS.RDY が `ready` になるまで待つ。
+    fn wait_ready(&self, ready: bool) -> Result<()> {
+        let mut deadline = Deadline::after_milli_seconds(self.ready_timeout_msec);
+        loop {
+            let csts = self.read32(REG_CSTS);
+            if csts & CSTS_CFS != 0 {
+                return Err(make_error!(Code::TransferFailed));
+            }
+            if (csts & CSTS_RDY != 0) == ready {
+                return Ok(());
+            }
+            if deadline.expired() {
+                log!(LogLevel::Error, "NVMe: controller did not become ready");
+                return Err(make_error!(Code::TransferFailed));
+            }
+            spin_loop();
+        }
+    }
+
+    /// `nsid` の名前空間について Identify コマンドを発行し、結果の 4 KiB を返す。
+    fn identify(&mut self, cns: u32, nsid: u32) -> Result<Vec<u8>> {
+        let bounce = self.bounce as u64;
+        self.admin_command(ADMIN_IDENTIFY, nsid, bounce, [cns, 0, 0, 0, 0, 0])?;
+        let data = unsafe { core::slice::from_raw_parts(self.bounce, BYTES_PER_FRAME) };
+        Ok(data.to_vec())
+    }
+
+    fn admin_command(&mut self, opcode: u8, nsid: u32, prp1: u64, cdw: [u32; 6]) -> Result<()> {
+        let cmd = self.make_command(opcode, nsid, prp1, 0, cdw);
+        let (mmio_base, stride) = (self.mmio_base, self.doorbell_stride);
+        submit(mmio_base, stride, &mut self.admin, cmd)
+    }
+
+    fn io_command(
+        &mut self,
+        opcode: u8,
+        nsid: u32,
+        lba: u64,
+        bytes: usize,
+        lba_size: usize,
+    ) -> Result<()> {
+        let prp1 = self.bounce as u64;
+        // 2 ページ目にまたがる場合は PRP2 でその先頭を指す
+        let prp2 = if bytes > BYTES_PER_FRAME {
+            prp1 + BYTES_PER_FRAME as u64
+        } else {
+            0
+        };
+        let num_blocks = (bytes / lba_size) as u32;
+        let cdw = [lba as u32, (lba >> 32) as u32, num_blocks - 1, 0, 0, 0];
+        let cmd = self.make_command(opcode, nsid, prp1, prp2, cdw);
+        let (mmio_base, stride) = (self.mmio_base, self.doorbell_stride);
+        submit(mmio_base, stride, &mut self.io, cmd)
+    }
+
+    fn make_command(
+        &mut self,
+        opcode: u8,
+        nsid: u32,
+        prp1: u64,
+        prp2: u64,
+        cdw: [u32; 6],
+    ) -> [u32; 16] {
+        let id = self.next_command_id;
+        self.next_command_id = self.next_command_id.wrapping_add(1);
+
+        let mut cmd = [0; 16];
+        cmd[0] = opcode as u32 | (id as u32) << 16;
+        cmd[1] = nsid;
+        cmd[6] = prp1 as u32;
+        cmd[7] = (prp1 >> 32) as u32;
+        cmd[8] = prp2 as u32;
+        cmd[9] = (prp2 >> 32) as u32;
+        cmd[10..].copy_from_slice(&cdw);
+        cmd
+    }
+}
+
+/// `queue` にコマンドを積み、その完了を待つ。
+fn submit(mmio_base: usize, stride: usize, queue: &mut QueuePair, cmd: [u32; 16]) -> Result<()> {
+    if queue.broken {
+        return Err(make_error!(Code::TransferFailed));
+    }
+    let sq_doorbell = mmio_base + REG_DOORBELL + 2 * queue.id as usize * stride;
+    let cq_doorbell = sq_doorbell + stride;
+
+    unsafe {
+        ptr::write_volatile(queue.sq.add(queue.sq_tail as usize), cmd);
+        fence(Ordering::SeqCst);
+        queue.sq_tail = (queue.sq_tail + 1) % queue.size;
+        ptr::write_volatile(sq_doorbell as *mut u32, queue.sq_tail as u32);
+    }
+
+    let mut deadline = Deadline::after_milli_seconds(COMMAND_TIMEOUT_MSEC);
+    let entry = loop {
+        let entry = unsafe { ptr::read_volatile(queue.cq.add(queue.cq_head as usize)) };
+        if entry[3].get_bit(16) == queue.phase {
+            break entry;
+        }
+        if deadline.expired() {
+            log!(
+                LogLevel::Error,
+                "NVMe: command {:#x} on queue {} timed out",
+                cmd[0],
+                queue.id
+            );
+            queue.broken = true;
+            return Err(make_error!(Code::TransferFailed));
+        }
+        spin_loop();
+    };
+    fence(Ordering::SeqCst);
+
+    queue.cq_head = (queue.cq_head + 1) % queue.size;
+    if queue.cq_head == 0 {
+        queue.phase = !queue.phase;
+    }
+    unsafe { ptr::write_volatile(cq_doorbell as *mut u32, queue.cq_head as u32) };
+
+    // ステータスフィールドが 0 なら成功
+    if entry[3].get_bits(17..) != 0 {
+        return Err(make_error!(Code::TransferFailed));
+    }
+    Ok(())
+}
+
+/// NVMe の名前空間 1 つ分のブロックデバイス。
+pub struct Namespace {
+    ctrl: Arc<Mutex<Controller>>,
+    nsid: u32,
+    capacity: u64,
+    sector_size: usize,
+}
+
+impl Namespace {
+    fn check_range(&self, sector: u64, len: usize) -> Result<()> {
+        if !len.is_multiple_of(self.sector_size) {
+            return Err(make_error!(Code::InvalidFormat));
+        }
+        if sector + (len / self.sector_size) as u64 > self.capacity {
+            return Err(make_error!(Code::IndexOutOfRange));
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for Namespace {
+    fn name(&self) -> &str {
+        "nvme"
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        self.check_range(sector, buf.len())?;
+
+        let mut ctrl = self.ctrl.lock_wait();
+        let sectors_per_chunk = MAX_TRANSFER_BYTES / self.sector_size;
+        for (i, chunk) in buf.chunks_mut(MAX_TRANSFER_BYTES).enumerate() {
+            let lba = sector + (i * sectors_per_chunk) as u64;
+            ctrl.io_command(IO_READ, self.nsid, lba, chunk.len(), self.sector_size)?;
+            unsafe { ptr::copy_nonoverlapping(ctrl.bounce, chunk.as_mut_ptr(), chunk.len()) };
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<()> {
+        self.check_range(sector, buf.len())?;
+
+        let mut ctrl = self.ctrl.lock_wait();
+        let sectors_per_chunk = MAX_TRANSFER_BYTES / self.sector_size;
+        for (i, chunk) in buf.chunks(MAX_TRANSFER_BYTES).enumerate() {
+            let lba = sector + (i * sectors_per_chunk) as u64;
+            unsafe { ptr::copy_nonoverlapping(chunk.as_ptr(), ctrl.bounce, chunk.len()) };
+            ctrl.io_command(IO_WRITE, self.nsid, lba, chunk.len(), self.sector_size)?;
+        }
+        Ok(())
+    }
+}
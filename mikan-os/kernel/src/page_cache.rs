@@ -0,0 +1,298 @@
+//! ファイルの内容をページ単位で保持するキャッシュ。
+//!
+//! ファイルの読み込みと、mmap したファイルのページフォルトの処理は、どちらもこのキャッシュを通す。
+//! mmap したページにはキャッシュのフレームを読み込み専用でそのままマップするので、
+//! 同じファイルをマップしたアプリ同士は同じフレームを共有する。書き込まれたページはコピーオンライトで複製される。
+//!
+//! どこにもマップされていないページは、空きフレームが少なくなったときに最後に使われたのが古いものから解放する。
+
+use core::{cmp, ptr, slice};
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use crate::{
+    error::{Code, Result},
+    fat::{self, DirectoryEntry},
+    log,
+    logger::LogLevel,
+    make_error,
+    memory_manager::{FrameId, BYTES_PER_FRAME, MEMORY_MANAGER},
+    sync::Mutex,
+};
+
+/// 空きフレームがこれを下回ったら、マップされていないページを解放する
+const LOW_WATERMARK: usize = 2048;
+/// 1 度に解放するページ数の上限
+const RECLAIM_BATCH: usize = 16;
+
+pub static PAGE_CACHE: Mutex<PageCache> = Mutex::new(PageCache::new());
+
+/// キャッシュしているページを識別するキー。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct PageKey {
+    /// ファイルの先頭クラスタ
+    file: u64,
+    /// ファイル先頭から何ページ目か
+    index: u64,
+}
+
+struct Page {
+    /// ページの内容を保持するフレーム
+    frame: FrameId,
+    /// このフレームをマップしているページの数
+    refs: usize,
+    /// 最後に使われた時点の [PageCache::clock] の値
+    last_used: u64,
+}
+
+pub struct PageCache {
+    pages: BTreeMap<PageKey, Page>,
+    /// フレームの ID から、そのフレームを持つページへの対応
+    frames: BTreeMap<usize, PageKey>,
+    /// キャッシュから外したがまだマップされているフレームの ID と、それをマップしているページの数
+    orphans: BTreeMap<usize, usize>,
+    /// ページが使われるたびに増える値
+    clock: u64,
+}
+
+impl PageCache {
+    pub const fn new() -> Self {
+        Self {
+            pages: BTreeMap::new(),
+            frames: BTreeMap::new(),
+            orphans: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// `entry` が指すファイルの `index` ページ目を保持するフレームを返す。
+    /// キャッシュにない場合はフレームを確保してファイルから読み込む。
+    fn get_page(&mut self, entry: &DirectoryEntry, index: u64) -> Result<FrameId> {
+        let key = PageKey {
+            file: entry.first_cluster() as _,
+            index,
+        };
+        self.clock += 1;
+        if let Some(page) = self.pages.get_mut(&key) {
+            page.last_used = self.clock;
+            return Ok(page.frame);
+        }
+
+        let frame = self.allocate_frame()?;
+        // Safety: 確保したフレームはアイデンティティマップされていて、まだ誰も参照していない
+        let buf = unsafe { slice::from_raw_parts_mut(frame.frame(), BYTES_PER_FRAME) };
+        if let Err(e) = fill_page(entry, index, buf) {
+            MEMORY_MANAGER.free(frame, 1);
+            return Err(e);
+        }
+
+        self.frames.insert(frame.id(), key);
+        self.pages.insert(
+            key,
+            Page {
+                frame,
+                refs: 0,
+                last_used: self.clock,
+            },
+        );
+        Ok(frame)
+    }
+
+    /// ページ用のフレームを確保する。
+    /// 空きフレームが少ない場合や確保に失敗した場合は、マップされていないページを解放してから確保する。
+    fn allocate_frame(&mut self) -> Result<FrameId> {
+        let stat = MEMORY_MANAGER.stat();
+        if stat.total_frames - stat.allocated_frames < LOW_WATERMARK {
+            self.reclaim(RECLAIM_BATCH);
+        }
+
+        loop {
+            match MEMORY_MANAGER.allocate(1) {
+                Ok(frame) => return Ok(frame),
+                Err(e) => {
+                    if self.reclaim(1) == 0 {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// マップされていないページを最後に使われたのが古いものから最大 `n` 個解放し、解放した数を返す。
+    fn reclaim(&mut self, n: usize) -> usize {
+        let mut reclaimed = 0;
+        while reclaimed < n {
+            let Some(key) = self
+                .pages
+                .iter()
+                .filter(|(_, page)| page.refs == 0)
+                .min_by_key(|(_, page)| page.last_used)
+                .map(|(&key, _)| key)
+            else {
+                break;
+            };
+
+            let page = self.pages.remove(&key).unwrap();
+            self.frames.remove(&page.frame.id());
+            MEMORY_MANAGER.free(page.frame, 1);
+            reclaimed += 1;
+        }
+        reclaimed
+    }
+
+    /// `frame` をマップしているページを 1 つ減らす。
+    fn release(&mut self, frame: FrameId) {
+        if let Some(key) = self.frames.get(&frame.id()) {
+            let page = self.pages.get_mut(key).unwrap();
+            page.refs -= 1;
+            return;
+        }
+
+        let Some(refs) = self.orphans.get_mut(&frame.id()) else {
+            return;
+        };
+        *refs -= 1;
+        if *refs == 0 {
+            self.orphans.remove(&frame.id());
+            MEMORY_MANAGER.free(frame, 1);
+        }
+    }
+
+    /// `file` のページを全てキャッシュから外す。
+    /// マップされているページのフレームは、全てのマップが外れたときに解放する。
+    fn invalidate(&mut self, file: u64) {
+        let keys = self
+            .pages
+            .range(
+                PageKey { file, index: 0 }..=PageKey {
+                    file,
+                    index: u64::MAX,
+                },
+            )
+            .map(|(&key, _)| key)
+            .collect::<Vec<_>>();
+
+        for key in keys {
+            self.remove_page(key);
+        }
+    }
+
+    /// 全てのページをキャッシュから外す。
+    fn invalidate_all(&mut self) {
+        let keys = self.pages.keys().copied().collect::<Vec<_>>();
+        for key in keys {
+            self.remove_page(key);
+        }
+    }
+
+    /// `key` のページをキャッシュから外す。
+    /// マップされている場合は、全てのマップが外れたときにフレームを解放する。
+    fn remove_page(&mut self, key: PageKey) {
+        let page = self.pages.remove(&key).unwrap();
+        self.frames.remove(&page.frame.id());
+        if page.refs == 0 {
+            MEMORY_MANAGER.free(page.frame, 1);
+        } else {
+            self.orphans.insert(page.frame.id(), page.refs);
+        }
+    }
+}
+
+impl Default for PageCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `entry` が指すファイルの `index` ページ目の内容を `buf` に読み込む。
+/// ファイルの終わりより後ろは 0 で埋める。
+fn fill_page(entry: &DirectoryEntry, index: u64, buf: &mut [u8]) -> Result<()> {
+    let offset = index as usize * BYTES_PER_FRAME;
+    let file_size = entry.file_size as usize;
+    if offset >= file_size {
+        return Err(make_error!(Code::IndexOutOfRange));
+    }
+
+    let n = fat::read_cluster_chain(entry.first_cluster() as _, offset, buf)?;
+    let valid = cmp::min(n, file_size - offset);
+    unsafe { ptr::write_bytes(buf[valid..].as_mut_ptr(), 0, buf.len() - valid) };
+    Ok(())
+}
+
+/// `entry` が指すファイルの `offset` バイト目から `buf` に読み込み、読み込んだバイト数を返す。
+pub fn read(entry: &DirectoryEntry, offset: usize, buf: &mut [u8]) -> usize {
+    let len = cmp::min(buf.len(), (entry.file_size as usize).saturating_sub(offset));
+
+    let mut total = 0;
+    while total < len {
+        let pos = offset + total;
+        let page_off = pos % BYTES_PER_FRAME;
+        let n = cmp::min(len - total, BYTES_PER_FRAME - page_off);
+
+        // `buf` がアプリのページを指しているとコピー中にページフォルトが起きうるので、
+        // ロックを外してからコピーする。その間にページが解放されないように参照を持っておく
+        let frame = match map_page(entry, (pos / BYTES_PER_FRAME) as _) {
+            Ok(frame) => frame,
+            Err(e) => {
+                log!(LogLevel::Error, "failed to read a file page: {}", e);
+                break;
+            }
+        };
+        let page = unsafe { slice::from_raw_parts(frame.frame(), BYTES_PER_FRAME) };
+        buf[total..total + n].copy_from_slice(&page[page_off..page_off + n]);
+        unmap_page(frame);
+        total += n;
+    }
+    total
+}
+
+/// `entry` が指すファイルの `index` ページ目を保持するフレームを、マップするために返す。
+/// マップを外すときは [unmap_page] を呼ぶこと。
+pub fn map_page(entry: &DirectoryEntry, index: u64) -> Result<FrameId> {
+    let mut cache = PAGE_CACHE.lock_wait();
+    let frame = cache.get_page(entry, index)?;
+    let key = cache.frames[&frame.id()];
+    cache.pages.get_mut(&key).unwrap().refs += 1;
+    Ok(frame)
+}
+
+/// [map_page] で返したフレームのマップを 1 つ外す。
+/// キャッシュのフレームでない場合は何もしない。
+pub fn unmap_page(frame: FrameId) {
+    PAGE_CACHE.lock_wait().release(frame);
+}
+
+/// 先頭クラスタが `file` のファイルの `offset` バイト目から `data` を書き込んだことを、キャッシュ済みのページに反映する。
+pub fn write(file: u64, offset: usize, data: &[u8]) {
+    let cache = PAGE_CACHE.lock_wait();
+    let first = offset / BYTES_PER_FRAME;
+    let last = (offset + data.len()).div_ceil(BYTES_PER_FRAME);
+    for index in first..last {
+        let Some(page) = cache.pages.get(&PageKey {
+            file,
+            index: index as _,
+        }) else {
+            continue;
+        };
+
+        let page_begin = index * BYTES_PER_FRAME;
+        let begin = cmp::max(offset, page_begin);
+        let end = cmp::min(offset + data.len(), page_begin + BYTES_PER_FRAME);
+        // Safety: キャッシュのフレームはロックを持っている間は解放されない
+        let buf = unsafe { slice::from_raw_parts_mut(page.frame.frame(), BYTES_PER_FRAME) };
+        buf[begin - page_begin..end - page_begin]
+            .copy_from_slice(&data[begin - offset..end - offset]);
+    }
+}
+
+/// 先頭クラスタが `file` のファイルのページをキャッシュから破棄する。
+/// ファイルのクラスタを解放するときに呼ぶ。
+pub fn invalidate(file: u64) {
+    PAGE_CACHE.lock_wait().invalidate(file);
+}
+
+/// 全てのファイルのページをキャッシュから破棄する。
+/// どのファイルのクラスタが変わったかわからないとき（ボリュームを修復したときなど）に呼ぶ。
+pub fn invalidate_all() {
+    PAGE_CACHE.lock_wait().invalidate_all();
+}
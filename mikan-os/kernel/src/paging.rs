@@ -13,6 +13,7 @@ use crate::{
     file::FileDescriptor,
     make_error,
     memory_manager::{FrameId, BYTES_PER_FRAME, MEMORY_MANAGER},
+    page_cache,
     sync::Mutex,
     task::{self, FileMapping, Task, TaskContext},
     terminal::APP_STACK_ADDR,
@@ -196,14 +197,62 @@ pub fn clean_page_map(page_maps: &mut [PageMapEntry], page_map_level: i32) {
         }
 
         // 読み込みのみの場合はコピーオンライトの雛形なのでメモリ割り当ては解除しない
-        if entry.writable() {
-            let entry_ptr = entry.pointer().as_ptr();
-            MEMORY_MANAGER.free(FrameId::from_addr(entry_ptr as _), 1);
+        // ページキャッシュのフレームなら、マップを外したことだけを伝える
+        let frame = FrameId::from_addr(entry.pointer().as_ptr() as _);
+        if entry.device() {
+            // デバイスのメモリは解放しない
+        } else if entry.writable() {
+            MEMORY_MANAGER.free(frame, 1);
+        } else if page_map_level == 1 {
+            page_cache::unmap_page(frame);
         }
         entry.data = 0;
     }
 }
 
+/// `begin` から `end` までの仮想アドレスに対応するページの割り当てを解除する。
+/// 書き込み可能なページに割り当てられていたフレームは解放する。
+pub fn clean_page_range(begin: u64, end: u64) {
+    let pml4_table =
+        unsafe { &mut *slice::from_raw_parts_mut(asmfunc::get_cr3() as *mut PageMapEntry, 512) };
+
+    let mut addr = begin & !(PAGE_SIZE_4K - 1);
+    while addr < end {
+        if let Some(entry) = find_page_entry(pml4_table, 4, LinearAddress4Level { addr }) {
+            let frame = FrameId::from_addr(entry.pointer().as_ptr() as _);
+            if entry.device() {
+                // デバイスのメモリは解放しない
+            } else if entry.writable() {
+                MEMORY_MANAGER.free(frame, 1);
+            } else {
+                page_cache::unmap_page(frame);
+            }
+            entry.data = 0;
+            asmfunc::invalidate_tlb(addr);
+        }
+        addr += PAGE_SIZE_4K;
+    }
+}
+
+/// `addr` に対応する 1 階層ページマップのエントリが存在すれば、それを返す。
+fn find_page_entry(
+    page_map: &mut [PageMapEntry],
+    page_map_level: i32,
+    addr: LinearAddress4Level,
+) -> Option<&'static mut PageMapEntry> {
+    let entry = &mut page_map[addr.part(page_map_level) as usize];
+    if !entry.persent() {
+        return None;
+    }
+
+    if page_map_level == 1 {
+        // Safety: ページマップはフレーム上にあり、静的に存在し続ける
+        Some(unsafe { &mut *entry.as_mut_ptr() })
+    } else {
+        find_page_entry(entry.mut_pointer(), page_map_level - 1, addr)
+    }
+}
+
 /// 新しい PML4 テーブルを作り、下位 256 ページ（OS 用）を元の PML4 テーブルからコピーする。
 /// そしてそれを CR3 に設定し、新しい PML4 への排他参照を返す。
 pub fn setup_pml4(current_task: &Arc<Task>) -> Result<&'static mut [PageMapEntry]> {
@@ -389,6 +438,16 @@ impl PageMapEntry {
         self.data.set_bit(8, value);
     }
 
+    /// OS が自由に使えるビットを、デバイスのメモリをマップしている印として使う。
+    /// このページのフレームはマップを外しても解放しない。
+    pub fn device(&self) -> bool {
+        self.data.get_bit(9)
+    }
+
+    pub fn set_device(&mut self, value: bool) {
+        self.data.set_bit(9, value);
+    }
+
     pub fn addr(&self) -> u64 {
         self.data.get_bits(12..52)
     }
@@ -500,16 +559,44 @@ fn find_file_mapping(fmaps: &[FileMapping], causal_addr: u64) -> Option<&FileMap
         .find(|m| (m.vaddr_begin..m.vaddr_end).contains(&causal_addr))
 }
 
-/// `map` と `causal_addr` に従って1ページ分のファイルの内容を `fd` からメモリにキャッシュする。
+/// `map` と `causal_addr` に従って、1ページ分のファイルの内容を保持するページキャッシュのフレームをマップする。
+/// フレームは他のアプリと共有するので読み込み専用でマップし、書き込まれたらコピーする。
 fn prepare_page_cache(fd: &FileDescriptor, map: &FileMapping, causal_addr: u64) -> Result<()> {
     let mut page_vaddr = LinearAddress4Level { addr: causal_addr };
     page_vaddr.set_offset(0);
-    setup_page_maps(page_vaddr, 1, true)?;
 
     let file_offset = page_vaddr.addr - map.vaddr_begin;
-    let page_cache =
-        unsafe { slice::from_raw_parts_mut(page_vaddr.addr as *mut u8, BYTES_PER_FRAME) };
-    fd.load(page_cache, file_offset as _);
+    let frame = fd.map_page(file_offset as _)?;
+    if fd.map_shared() {
+        return map_frame(page_vaddr, frame, true);
+    }
+    if let Err(e) = map_frame(page_vaddr, frame, false) {
+        page_cache::unmap_page(frame);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// `addr` を含むページに、確保済みの `frame` をマップする。
+/// `device` が true の場合はデバイスのメモリとして書き込み可能でマップし、そうでなければ読み込み専用でマップする。
+fn map_frame(addr: LinearAddress4Level, frame: FrameId, device: bool) -> Result<()> {
+    let mut page_map =
+        unsafe { &mut *slice::from_raw_parts_mut(asmfunc::get_cr3() as *mut PageMapEntry, 512) };
+    for page_map_level in (2..=4).rev() {
+        let entry = &mut page_map[addr.part(page_map_level) as usize];
+        let child_map = set_new_page_map_if_not_present(entry)?;
+        entry.set_user(true);
+        entry.set_writable(true);
+        page_map = child_map;
+    }
+
+    let entry = &mut page_map[addr.part(1) as usize];
+    entry.set_addr(frame.id() as _);
+    entry.set_present(true);
+    entry.set_user(true);
+    entry.set_writable(device);
+    entry.set_device(device);
+    asmfunc::invalidate_tlb(addr.addr);
     Ok(())
 }
 
@@ -527,7 +614,16 @@ fn copy_one_page(causal_addr: u64) -> Result<()> {
     };
     // 現在のページディレクトリに `p` を登録する
     let table = unsafe { slice::from_raw_parts_mut(asmfunc::get_cr3() as *mut _, BYTES_PER_FRAME) };
-    set_page_content(table, 4, LinearAddress4Level { addr: causal_addr }, &p[0])
+    let addr = LinearAddress4Level { addr: causal_addr };
+    let old_frame = find_page_entry(table, 4, addr)
+        .map(|entry| FrameId::from_addr(entry.pointer().as_ptr() as _));
+    set_page_content(table, 4, addr, &p[0])?;
+
+    // コピー元がページキャッシュのフレームなら、そのマップを外す
+    if let Some(frame) = old_frame {
+        page_cache::unmap_page(frame);
+    }
+    Ok(())
 }
 
 /// `part` 番目のページディレクトリ `table` に対して、
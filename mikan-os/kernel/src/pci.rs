@@ -148,6 +148,10 @@ impl Device {
         read_vendor_id(self.bus, self.device, self.function)
     }
 
+    pub fn read_device_id(&self) -> u16 {
+        read_device_id(self.bus, self.device, self.function)
+    }
+
     pub fn read_conf_reg(&self, reg_addr: u8) -> u32 {
         write_address(make_address(self.bus, self.device, self.function, reg_addr));
         read_data()
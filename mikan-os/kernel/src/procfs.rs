@@ -0,0 +1,291 @@
+//! カーネルの状態をファイルとして見せる疑似ファイルシステム。
+//!
+//! ```text
+//! /proc/meminfo           物理メモリのフレーム数
+//! /proc/uptime            起動してからの秒数
+//! /proc/pci               PCI デバイスの一覧
+//! /proc/<id>/state        タスクが動作中かスリープ中か
+//! /proc/<id>/level        タスクのランレベル
+//! /proc/<id>/fds          開いているファイルディスクリプタ
+//! /proc/<id>/maps         ファイルをマップしている範囲
+//! /proc/<id>/dpaging      デマンドページングの範囲
+//! ```
+//!
+//! ファイルの内容は開いたときに作るので、同じファイルを読み続けても値は変わらない。
+
+use core::{cmp, fmt::Write as _};
+
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString as _},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+
+use crate::{
+    asmfunc,
+    error::{Code, Result},
+    fat::Attribute,
+    file::Stat,
+    make_error,
+    memory_manager::{BYTES_PER_FRAME, MEMORY_MANAGER},
+    pci,
+    task::{self, Task},
+    timer::{TIMER_FREQ, TIMER_MANAGER},
+    vfs::{File, FileSystem},
+};
+
+/// ルートディレクトリにあるファイル
+const ROOT_FILES: [&str; 3] = ["meminfo", "uptime", "pci"];
+/// タスクのディレクトリにあるファイル
+const TASK_FILES: [&str; 5] = ["state", "level", "fds", "maps", "dpaging"];
+
+pub struct ProcFileSystem;
+
+impl FileSystem for ProcFileSystem {
+    fn name(&self) -> &str {
+        "proc"
+    }
+
+    fn lookup(&self, path: &str) -> Result<Box<dyn File>> {
+        let post_slash = path.ends_with('/');
+        let mut elems = path.split('/').filter(|s| !s.is_empty());
+
+        let file: Box<dyn File> = match (elems.next(), elems.next(), elems.next()) {
+            (None, ..) => {
+                let mut entries: Vec<_> = ROOT_FILES
+                    .iter()
+                    .map(|name| (name.to_string(), false))
+                    .collect();
+                entries.extend(task_ids().into_iter().map(|id| (id.to_string(), true)));
+                Box::new(ProcDir::new(entries))
+            }
+            (Some(name), None, _) if ROOT_FILES.contains(&name) => {
+                if post_slash {
+                    return Err(make_error!(Code::NotDirectory));
+                }
+                Box::new(ProcFile::new(root_file(name)))
+            }
+            (Some(id), None, _) => {
+                find_task(id)?;
+                let entries = TASK_FILES
+                    .iter()
+                    .map(|name| (name.to_string(), false))
+                    .collect();
+                Box::new(ProcDir::new(entries))
+            }
+            (Some(id), Some(name), None) if TASK_FILES.contains(&name) => {
+                let task = find_task(id)?;
+                if post_slash {
+                    return Err(make_error!(Code::NotDirectory));
+                }
+                Box::new(ProcFile::new(task_file(&task, name)))
+            }
+            _ => return Err(make_error!(Code::NoSuchEntry)),
+        };
+        Ok(file)
+    }
+}
+
+/// 開いた時点の内容を保持する読み込み専用のファイル。
+struct ProcFile {
+    data: String,
+    rd_off: usize,
+}
+
+impl ProcFile {
+    fn new(data: String) -> Self {
+        Self { data, rd_off: 0 }
+    }
+}
+
+impl File for ProcFile {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let n = cmp::min(buf.len(), self.data.len() - self.rd_off);
+        buf[..n].copy_from_slice(&self.data.as_bytes()[self.rd_off..self.rd_off + n]);
+        self.rd_off += n;
+        n
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+        Err(make_error!(Code::NotImplemented))
+    }
+
+    fn truncate(&mut self, _size: usize) -> Result<()> {
+        Err(make_error!(Code::NotImplemented))
+    }
+
+    fn stat(&self) -> Stat {
+        Stat {
+            size: self.data.len() as _,
+            attr: Attribute::ReadOnly as _,
+            ..Default::default()
+        }
+    }
+
+    fn seek(&mut self, offset: usize) -> Result<()> {
+        if offset > self.data.len() {
+            return Err(make_error!(Code::IndexOutOfRange));
+        }
+        self.rd_off = offset;
+        Ok(())
+    }
+
+    fn position(&self) -> usize {
+        self.rd_off
+    }
+}
+
+/// 開いた時点のエントリの一覧を保持するディレクトリ。
+struct ProcDir {
+    /// エントリの名前と、それがディレクトリかどうか
+    entries: vec::IntoIter<(String, bool)>,
+}
+
+impl ProcDir {
+    fn new(entries: Vec<(String, bool)>) -> Self {
+        Self {
+            entries: entries.into_iter(),
+        }
+    }
+}
+
+impl File for ProcDir {
+    fn read(&mut self, _buf: &mut [u8]) -> usize {
+        0
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+        Err(make_error!(Code::IsDirectory))
+    }
+
+    fn stat(&self) -> Stat {
+        Stat {
+            attr: Attribute::Directory as _,
+            ..Default::default()
+        }
+    }
+
+    fn read_dir(&mut self) -> Result<Option<(Stat, String)>> {
+        Ok(self.entries.next().map(|(name, is_dir)| {
+            let attr = if is_dir {
+                Attribute::Directory
+            } else {
+                Attribute::ReadOnly
+            };
+            let stat = Stat {
+                attr: attr as _,
+                ..Default::default()
+            };
+            (stat, name)
+        }))
+    }
+}
+
+fn task_ids() -> Vec<u64> {
+    asmfunc::cli();
+    let ids = task::task_ids();
+    asmfunc::sti();
+    ids
+}
+
+/// ID を表す文字列 `id` のタスクを返す。
+fn find_task(id: &str) -> Result<Arc<Task>> {
+    let id = id.parse().map_err(|_| make_error!(Code::NoSuchEntry))?;
+    asmfunc::cli();
+    let task = task::get_task(id);
+    asmfunc::sti();
+    task.ok_or(make_error!(Code::NoSuchEntry))
+}
+
+/// ルートディレクトリにあるファイル `name` の内容を作る。
+fn root_file(name: &str) -> String {
+    match name {
+        "meminfo" => {
+            let stat = MEMORY_MANAGER.stat();
+            format!(
+                "frame_size: {}\ntotal_frames: {}\nallocated_frames: {}\nfree_frames: {}\n",
+                BYTES_PER_FRAME,
+                stat.total_frames,
+                stat.allocated_frames,
+                stat.total_frames - stat.allocated_frames,
+            )
+        }
+        "uptime" => {
+            let tick = TIMER_MANAGER.lock_wait().current_tick();
+            format!(
+                "{}.{:02}\n",
+                tick / TIMER_FREQ,
+                tick % TIMER_FREQ * 100 / TIMER_FREQ
+            )
+        }
+        "pci" => {
+            let mut s = String::new();
+            for dev in pci::DEVICES.read().iter() {
+                let _ = writeln!(
+                    s,
+                    "{:02x}:{:02x}.{} vend={:04x} head={:02x} class={:02x}.{:02x}:{:02x}",
+                    dev.bus(),
+                    dev.device(),
+                    dev.function(),
+                    dev.read_vendor_id(),
+                    dev.header_type(),
+                    dev.class_code().base(),
+                    dev.class_code().sub(),
+                    dev.class_code().interface(),
+                );
+            }
+            s
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// タスクのディレクトリにあるファイル `name` の内容を作る。
+fn task_file(task: &Task, name: &str) -> String {
+    match name {
+        "state" => {
+            let state = if task.is_running() {
+                "running"
+            } else {
+                "sleeping"
+            };
+            format!("{}\n", state)
+        }
+        "level" => format!("{}\n", task.run_level()),
+        "fds" => {
+            let mut s = String::new();
+            let files = task.files().lock_wait();
+            for fd in 0..files.cap() as i32 {
+                let Some(file) = files.get(&fd) else {
+                    continue;
+                };
+                // 読み込みで待機しているファイルはロックされたままなので、待たずに飛ばす
+                let _ = match file.lock() {
+                    Some(file) => writeln!(s, "{} {}", fd, file.path()),
+                    None => writeln!(s, "{} (busy)", fd),
+                };
+            }
+            s
+        }
+        "maps" => {
+            let mut s = String::new();
+            for map in task.file_maps().lock_wait().iter() {
+                let _ = writeln!(
+                    s,
+                    "{:016x}-{:016x} {}",
+                    map.vaddr_begin, map.vaddr_end, map.fd
+                );
+            }
+            s
+        }
+        "dpaging" => format!(
+            "{:016x}-{:016x}\n",
+            task.dpaging_begin(),
+            task.dpaging_end()
+        ),
+        _ => unreachable!(),
+    }
+}
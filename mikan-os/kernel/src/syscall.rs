@@ -1,6 +1,6 @@
 use core::{ffi::CStr, mem, slice};
 
-use alloc::sync::Arc;
+use alloc::{boxed::Box, sync::Arc};
 
 use crate::{
     app_event::AppEvent,
@@ -8,8 +8,7 @@ use crate::{
     bitfield::BitField,
     errno::ErrNo,
     error::Code,
-    fat::{self, DirectoryEntry},
-    file::{FileDescriptor, FileFlags},
+    file::{DirEnt, FileDescriptor, FileFlags, Stat, SEEK_CUR, SEEK_END, SEEK_SET},
     font,
     graphics::{PixelColor, PixelWrite as _, Vector2D, FB_CONFIG},
     keyboard::{LCONTROL_BIT, RCONTROL_BIT},
@@ -19,16 +18,18 @@ use crate::{
     memory_manager::BYTES_PER_FRAME,
     message::MessageType,
     msr::{IA32_EFER, IA32_FMASK, IA32_LSTAR, IA32_STAR},
+    paging,
     sync::{Mutex, SharedLock},
     task::{self, FileMapping, Task},
     timer::{Timer, TIMER_FREQ, TIMER_MANAGER},
+    vfs::{self, File},
     window::Window,
 };
 
 pub type SyscallFuncType = extern "sysv64" fn(u64, u64, u64, u64, u64, u64) -> Result;
 
 #[no_mangle]
-pub static SYSCALL_TABLE: [SyscallFuncType; 16] = [
+pub static SYSCALL_TABLE: [SyscallFuncType; 27] = [
     log_string,
     put_string,
     exit,
@@ -45,6 +46,17 @@ pub static SYSCALL_TABLE: [SyscallFuncType; 16] = [
     read_file,
     demand_pages,
     map_file,
+    close_file,
+    write_file,
+    seek_file,
+    stat,
+    fstat,
+    open_dir,
+    read_dir,
+    unlink,
+    mkdir,
+    rmdir,
+    rename,
 ];
 
 pub fn init() {
@@ -103,26 +115,32 @@ extern "sysv64" fn log_string(arg1: u64, arg2: u64, _: u64, _: u64, _: u64, _: u
 extern "sysv64" fn put_string(arg1: u64, arg2: u64, arg3: u64, _: u64, _: u64, _: u64) -> Result {
     let fd = arg1 as i32;
     let s: &[u8] = unsafe { slice::from_raw_parts(arg2 as _, arg3 as _) };
+    write_to_fd(fd, s)
+}
 
+fn write_to_fd(fd: i32, s: &[u8]) -> Result {
     asmfunc::cli();
     let task = task::current_task();
     asmfunc::sti();
 
-    let mut files = task.files().lock_wait();
+    let files = task.files().lock_wait();
     if fd < 0 || files.cap() <= fd as _ {
         return ErrNo::EBADF.into();
     }
-    let Some(file) = files.get_mut(&fd).cloned() else {
+    let Some(file) = files.get(&fd).cloned() else {
         return ErrNo::EBADF.into();
     };
-    let res = file.lock_wait().write(s);
+    // 書き込み中にブロックしても他のファイル操作ができるように、ロックは先に外しておく
+    drop(files);
+    let mut file = file.lock_wait();
+    if !file.writable() {
+        return ErrNo::EBADF.into();
+    }
+    let res = file.write(s);
     match res {
         Ok(len) => Result::value(len as _),
-        Err(e) => match e.cause() {
-            // 実際はデバイスでなくメモリだが、まあ一旦こうしておく
-            Code::NoEnoughMemory => ErrNo::ENOSPC.into(),
-            e => unreachable!("{}", e),
-        },
+        // 他のファイルディスクリプタで切り詰められて、書き込み位置がファイルの終わりを越えた場合は EINVAL
+        Err(e) => fs_errno(e.cause()).into(),
     }
 }
 
@@ -451,38 +469,44 @@ extern "sysv64" fn open_file(path: u64, flags: u64, _: u64, _: u64, _: u64, _: u
         Err(_) => return ErrNo::EINVAL.into(),
     };
     let flags = FileFlags::new(flags as _);
+    if flags & FileFlags::ACCMODE == FileFlags::ACCMODE {
+        return ErrNo::EINVAL.into();
+    }
     asmfunc::cli();
     let task = task::current_task();
     asmfunc::sti();
 
-    // @stdin の場合は標準入力で特別扱い
-    if path == "@stdin" {
-        return Result::value(0);
-    }
-
-    let file = match fat::find_file(path, 0) {
-        (Some(dir), post_slash) => {
-            if dir.attr != fat::Attribute::Directory as _ && post_slash {
-                return ErrNo::ENOENT.into();
+    let exclusive = FileFlags::CREAT | FileFlags::EXCL;
+    let file = match vfs::lookup(path) {
+        Ok(_) if flags & exclusive == exclusive => return ErrNo::EEXIST.into(),
+        Ok(file) => file,
+        Err(e) => match e.cause() {
+            Code::NoSuchEntry if flags & FileFlags::CREAT != FileFlags::new(0) => {
+                match create_file(path) {
+                    Ok(f) => f,
+                    Err(e) => return e.into(),
+                }
             }
-            dir
+            e => return fs_errno(e).into(),
+        },
+    };
+
+    let mut fd = FileDescriptor::with_flags(file, path, flags);
+    // 読み込み専用で開いた場合は切り詰めない
+    if flags & FileFlags::TRUNC != FileFlags::new(0) && fd.writable() {
+        if fd.stat().is_dir() {
+            return ErrNo::EISDIR.into();
         }
-        (None, _) => {
-            if flags & FileFlags::CREAT == FileFlags::new(0) {
-                return ErrNo::ENOENT.into();
-            }
-            match create_file(path) {
-                Ok(f) => f,
-                Err(e) => return e.into(),
-            }
+        if let Err(e) = fd.truncate(0) {
+            return fs_errno(e.cause()).into();
         }
-    };
+    }
 
-    let fd = allocate_fd(&task);
+    let fd_num = allocate_fd(&task);
     task.files()
         .lock_wait()
-        .insert(fd, Arc::new(Mutex::new(FileDescriptor::new_fat(file))));
-    Result::value(fd as _)
+        .insert(fd_num, Arc::new(Mutex::new(fd)));
+    Result::value(fd_num as _)
 }
 
 extern "sysv64" fn read_file(fd: u64, buf: u64, count: u64, _: u64, _: u64, _: u64) -> Result {
@@ -496,11 +520,17 @@ extern "sysv64" fn read_file(fd: u64, buf: u64, count: u64, _: u64, _: u64, _: u
     let task = task::current_task();
     asmfunc::sti();
 
-    let mut files = task.files().lock_wait();
-    let Some(fd) = files.get_mut(&fd) else {
+    let files = task.files().lock_wait();
+    let Some(fd) = files.get(&fd).cloned() else {
         return ErrNo::EBADF.into();
     };
-    let len = fd.lock_wait().read(buf) as _;
+    // 読み込み中にブロックしても他のファイル操作ができるように、ロックは先に外しておく
+    drop(files);
+    let mut fd = fd.lock_wait();
+    if !fd.readable() {
+        return ErrNo::EBADF.into();
+    }
+    let len = fd.read(buf) as _;
     Result::value(len)
 }
 
@@ -530,8 +560,14 @@ extern "sysv64" fn map_file(fd: u64, pfile_size: u64, _: u64, _: u64, _: u64, _:
     let Some(fild) = files.get_mut(&fd) else {
         return ErrNo::EBADF.into();
     };
+    let fild = fild.lock_wait();
+    // マップしたページは読み込めてしまう
+    if !fild.readable() {
+        return ErrNo::EACCES.into();
+    }
 
-    *file_size = fild.lock_wait().size();
+    *file_size = fild.size();
+    drop(fild);
 
     let vaddr_end = task.file_map_end();
     let vaddr_begin = (vaddr_end - *file_size as u64) & !0xfff;
@@ -545,6 +581,208 @@ extern "sysv64" fn map_file(fd: u64, pfile_size: u64, _: u64, _: u64, _: u64, _:
     Result::value(vaddr_begin)
 }
 
+extern "sysv64" fn close_file(fd: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> Result {
+    let fd = fd as i32;
+    if fd < 0 {
+        return ErrNo::EBADF.into();
+    }
+
+    asmfunc::cli();
+    let task = task::current_task();
+    asmfunc::sti();
+
+    if task.files().lock_wait().remove(&fd).is_none() {
+        return ErrNo::EBADF.into();
+    }
+
+    // 閉じたファイルをマップしている領域は、もう読み込めないので解放する
+    task.file_maps().lock_wait().retain(|map| {
+        if map.fd != fd {
+            return true;
+        }
+        paging::clean_page_range(map.vaddr_begin, map.vaddr_end);
+        false
+    });
+
+    // 書き込んだ内容をディスクに反映する
+    if vfs::sync().is_err() {
+        return ErrNo::EIO.into();
+    }
+    Result::value(0)
+}
+
+extern "sysv64" fn write_file(fd: u64, buf: u64, count: u64, _: u64, _: u64, _: u64) -> Result {
+    let buf = unsafe { slice::from_raw_parts(buf as _, count as _) };
+    write_to_fd(fd as _, buf)
+}
+
+extern "sysv64" fn seek_file(fd: u64, offset: u64, whence: u64, _: u64, _: u64, _: u64) -> Result {
+    let fd = fd as i32;
+    if fd < 0 {
+        return ErrNo::EBADF.into();
+    }
+
+    asmfunc::cli();
+    let task = task::current_task();
+    asmfunc::sti();
+
+    let Some(file) = task.files().lock_wait().get(&fd).cloned() else {
+        return ErrNo::EBADF.into();
+    };
+    let mut file = file.lock_wait();
+
+    let base = match whence {
+        SEEK_SET => 0,
+        SEEK_CUR => file.position() as i64,
+        SEEK_END => file.size() as i64,
+        _ => return ErrNo::EINVAL.into(),
+    };
+    let Some(new_off) = base.checked_add(offset as i64).filter(|&off| off >= 0) else {
+        return ErrNo::EINVAL.into();
+    };
+    match file.seek(new_off as _) {
+        Ok(()) => Result::value(new_off as _),
+        Err(e) => match e.cause() {
+            Code::InvalidFile => ErrNo::ESPIPE.into(),
+            e => fs_errno(e).into(),
+        },
+    }
+}
+
+extern "sysv64" fn stat(path: u64, buf: u64, _: u64, _: u64, _: u64, _: u64) -> Result {
+    let path = match unsafe { CStr::from_ptr(path as _) }.to_str() {
+        Ok(s) => s,
+        Err(_) => return ErrNo::EINVAL.into(),
+    };
+    let buf = unsafe { &mut *(buf as *mut Stat) };
+
+    match vfs::stat(path) {
+        Ok(stat) => {
+            *buf = stat;
+            Result::value(0)
+        }
+        Err(e) => fs_errno(e.cause()).into(),
+    }
+}
+
+extern "sysv64" fn fstat(fd: u64, buf: u64, _: u64, _: u64, _: u64, _: u64) -> Result {
+    let fd = fd as i32;
+    if fd < 0 {
+        return ErrNo::EBADF.into();
+    }
+    let buf = unsafe { &mut *(buf as *mut Stat) };
+
+    asmfunc::cli();
+    let task = task::current_task();
+    asmfunc::sti();
+
+    let Some(file) = task.files().lock_wait().get(&fd).cloned() else {
+        return ErrNo::EBADF.into();
+    };
+    *buf = file.lock_wait().stat();
+    Result::value(0)
+}
+
+extern "sysv64" fn open_dir(path: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> Result {
+    let path = match unsafe { CStr::from_ptr(path as _) }.to_str() {
+        Ok(s) => s,
+        Err(_) => return ErrNo::EINVAL.into(),
+    };
+
+    asmfunc::cli();
+    let task = task::current_task();
+    asmfunc::sti();
+
+    let dir = match vfs::lookup(path) {
+        Ok(dir) if dir.stat().is_dir() => dir,
+        Ok(_) => return ErrNo::ENOTDIR.into(),
+        Err(e) => return fs_errno(e.cause()).into(),
+    };
+
+    let fd = allocate_fd(&task);
+    task.files()
+        .lock_wait()
+        .insert(fd, Arc::new(Mutex::new(FileDescriptor::new(dir, path))));
+    Result::value(fd as _)
+}
+
+/// ディレクトリ内の次のエントリを `buf` に書き込む。
+/// 書き込んだ場合は 1、もうエントリがない場合は 0 を返す。
+extern "sysv64" fn read_dir(fd: u64, buf: u64, _: u64, _: u64, _: u64, _: u64) -> Result {
+    let fd = fd as i32;
+    if fd < 0 {
+        return ErrNo::EBADF.into();
+    }
+    let buf = unsafe { &mut *(buf as *mut DirEnt) };
+
+    asmfunc::cli();
+    let task = task::current_task();
+    asmfunc::sti();
+
+    let Some(file) = task.files().lock_wait().get(&fd).cloned() else {
+        return ErrNo::EBADF.into();
+    };
+    let res = file.lock_wait().read_dir();
+    match res {
+        Ok(Some((stat, name))) => {
+            *buf = DirEnt::new(stat, &name);
+            Result::value(1)
+        }
+        Ok(None) => Result::value(0),
+        Err(_) => ErrNo::ENOTDIR.into(),
+    }
+}
+
+extern "sysv64" fn unlink(path: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> Result {
+    let path = match unsafe { CStr::from_ptr(path as _) }.to_str() {
+        Ok(s) => s,
+        Err(_) => return ErrNo::EINVAL.into(),
+    };
+
+    match vfs::remove(path) {
+        Ok(()) => Result::value(0),
+        Err(e) => fs_errno(e.cause()).into(),
+    }
+}
+
+extern "sysv64" fn mkdir(path: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> Result {
+    let path = match unsafe { CStr::from_ptr(path as _) }.to_str() {
+        Ok(s) => s,
+        Err(_) => return ErrNo::EINVAL.into(),
+    };
+
+    match vfs::create_dir(path) {
+        Ok(()) => Result::value(0),
+        Err(e) => fs_errno(e.cause()).into(),
+    }
+}
+
+extern "sysv64" fn rmdir(path: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> Result {
+    let path = match unsafe { CStr::from_ptr(path as _) }.to_str() {
+        Ok(s) => s,
+        Err(_) => return ErrNo::EINVAL.into(),
+    };
+
+    match vfs::remove_dir(path) {
+        Ok(()) => Result::value(0),
+        Err(e) => fs_errno(e.cause()).into(),
+    }
+}
+
+extern "sysv64" fn rename(old_path: u64, new_path: u64, _: u64, _: u64, _: u64, _: u64) -> Result {
+    let (Ok(old_path), Ok(new_path)) = (
+        unsafe { CStr::from_ptr(old_path as _) }.to_str(),
+        unsafe { CStr::from_ptr(new_path as _) }.to_str(),
+    ) else {
+        return ErrNo::EINVAL.into();
+    };
+
+    match vfs::rename(old_path, new_path) {
+        Ok(()) => Result::value(0),
+        Err(e) => fs_errno(e.cause()).into(),
+    }
+}
+
 fn allocate_fd(task: &Task) -> i32 {
     let files = task.files().lock_wait();
     let num_files = files.cap() as _;
@@ -553,11 +791,25 @@ fn allocate_fd(task: &Task) -> i32 {
         .unwrap_or(num_files as _)
 }
 
-fn create_file(path: &str) -> core::result::Result<&'static mut DirectoryEntry, ErrNo> {
-    fat::create_file(path).map_err(|e| match e.cause() {
-        Code::IsDirectory => ErrNo::EISDIR,
+fn create_file(path: &str) -> core::result::Result<Box<dyn File>, ErrNo> {
+    vfs::create(path).map_err(|e| fs_errno(e.cause()))
+}
+
+/// ファイルシステムの操作で返されたエラーを、アプリに返すエラー番号にする。
+fn fs_errno(code: Code) -> ErrNo {
+    match code {
         Code::NoSuchEntry => ErrNo::ENOENT,
+        Code::NotDirectory => ErrNo::ENOTDIR,
+        Code::IsDirectory => ErrNo::EISDIR,
+        Code::FileExists => ErrNo::EEXIST,
+        Code::DirectoryNotEmpty => ErrNo::ENOTEMPTY,
+        Code::NameTooLong => ErrNo::ENAMETOOLONG,
+        // 実際はデバイスでなくメモリのこともあるが、まあ一旦こうしておく
         Code::NoEnoughMemory => ErrNo::ENOSPC,
-        _ => unreachable!(),
-    })
+        Code::CrossDevice => ErrNo::EXDEV,
+        Code::NotImplemented => ErrNo::EPERM,
+        Code::InvalidFile | Code::IndexOutOfRange => ErrNo::EINVAL,
+        // デバイスの読み書きに失敗した場合や、ボリュームが壊れている場合など
+        _ => ErrNo::EIO,
+    }
 }
@@ -7,6 +7,7 @@ use core::{
 
 use crate::{
     asmfunc::{self, restore_context},
+    bitfield::BitField as _,
     collections::HashMap,
     error::{Code, Result},
     file::FileDescriptor,
@@ -35,6 +36,17 @@ static mut TASK_MANAGER: TaskManager = TaskManager::new();
 pub type TaskFunc = fn(u64, i64, u32);
 
 pub fn init() {
+    // FXSAVE、FXRSTOR と SSE を使えるようにする
+    let mut cr0 = asmfunc::get_cr0();
+    cr0.set_bit(1, true); // MP
+    cr0.set_bit(2, false); // EM
+    cr0.set_bit(3, false); // TS
+    asmfunc::set_cr0(cr0);
+    let mut cr4 = asmfunc::get_cr4();
+    cr4.set_bit(9, true); // OSFXSR
+    cr4.set_bit(10, true); // OSXMMEXCPT
+    asmfunc::set_cr4(cr4);
+
     unsafe {
         TASK_MANAGER
             .new_task()
@@ -102,11 +114,21 @@ pub fn get_task(task_id: u64) -> Option<Arc<Task>> {
     unsafe { TASK_MANAGER.get_task(task_id) }
 }
 
+/// 存在している全てのタスクの ID を返す。
+pub fn task_ids() -> Vec<u64> {
+    unsafe { TASK_MANAGER.task_ids() }
+}
+
 #[no_mangle]
 pub fn get_current_task_os_stack_pointer() -> u64 {
     *unsafe { TASK_MANAGER.current_task().os_stack_ptr() }
 }
 
+/// x87 FPU 制御ワードの初期値。
+pub const DEFAULT_FCW: u16 = 0x037f;
+/// MXCSR レジスタの初期値。
+pub const DEFAULT_MXCSR: u32 = 0x1f80;
+
 #[repr(C, align(16))]
 #[derive(Debug)]
 pub struct TaskContext {
@@ -134,11 +156,27 @@ pub struct TaskContext {
     pub r13: u64,
     pub r14: u64,
     pub r15: u64,
+    /// FXSAVE 命令で保存される x87 FPU、SSE レジスタの領域。
+    pub fxsave_area: [u8; 512],
 }
 
 impl TaskContext {
+    /// FPU、SSE の状態は初期値（全ての例外がマスクされた状態）にしておく。
     pub const fn new() -> Self {
-        unsafe { mem::zeroed() }
+        let mut ctx: Self = unsafe { mem::zeroed() };
+
+        let fcw = DEFAULT_FCW.to_le_bytes();
+        ctx.fxsave_area[0] = fcw[0];
+        ctx.fxsave_area[1] = fcw[1];
+
+        let mxcsr = DEFAULT_MXCSR.to_le_bytes();
+        let mut i = 0;
+        while i < mxcsr.len() {
+            ctx.fxsave_area[24 + i] = mxcsr[i];
+            i += 1;
+        }
+
+        ctx
     }
 
     pub fn as_ptr(&self) -> *const Self {
@@ -287,6 +325,11 @@ impl<const STACK_SIZE: usize> Task<STACK_SIZE> {
         unsafe { TASK_MANAGER.change_level_running(self.id, level) };
     }
 
+    /// ランキューに入っている（スリープしていない）かどうかを返す。
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
     pub fn run_level(&self) -> i32 {
         self.level.load(Ordering::Relaxed)
     }
@@ -594,6 +637,10 @@ impl TaskManager {
     fn get_task(&self, task_id: u64) -> Option<Arc<Task>> {
         self.tasks.iter().find(|task| task.id() == task_id).cloned()
     }
+
+    fn task_ids(&self) -> Vec<u64> {
+        self.tasks.iter().map(|task| task.id()).collect()
+    }
 }
 
 /// [`Arc<Task>`][Arc<Task>] の [VecDeque] から ID が `id` の [Task] を削除する。
@@ -18,7 +18,7 @@ use crate::{
     collections::HashMap,
     elf::{Elf64Ehdr, Elf64Phdr, ExecuteType, ProgramType},
     error::{Code, Result},
-    fat::{self, Attribute, DirectoryEntry, BYTES_PER_CLUSTER},
+    fat::{self, Entry, Location},
     file::{self, FileDescriptor},
     font,
     graphics::{PixelColor, PixelWrite, Rectangle, Vector2D, FB_CONFIG},
@@ -33,6 +33,7 @@ use crate::{
     sync::{Mutex, SharedLock},
     task::{self, Task},
     timer::{Timer, TIMER_FREQ, TIMER_MANAGER},
+    vfs::{self, File},
     window::Window,
 };
 pub const APP_STACK_ADDR: u64 = 0xffff_ffff_ffff_e000;
@@ -40,8 +41,8 @@ pub const DEFAULT_APP_STACK_SIZE: u64 = 8 << 20;
 
 pub const FILE_MAP_END: u64 = 0xffff_c000_0000_0000;
 
-static APP_LOADS: Mutex<HashMap<&'static DirectoryEntry, AppLoadInfoTemplate>> =
-    Mutex::new(HashMap::new());
+/// ロード済みのアプリ。ファイルのディレクトリエントリの位置で識別する
+static APP_LOADS: Mutex<HashMap<Location, AppLoadInfoTemplate>> = Mutex::new(HashMap::new());
 
 /// [Terminal] のアドレスを保持し、参照を得るための構造体。
 #[derive(Debug, Clone, Copy)]
@@ -401,6 +402,10 @@ impl Terminal {
                 };
 
                 self.execute_line(command);
+                // コマンドによるファイルシステムの変更をディスクに反映する
+                if let Err(e) = vfs::sync() {
+                    self.print(&format!("failed to sync the file system: {}\n", e));
+                }
                 self.print(">");
                 if let Some(ref window) = self.window {
                     draw_area.pos = Vector2D::new(0, 0);
@@ -506,31 +511,30 @@ impl Terminal {
         };
 
         if let Some(redir_dest) = redir_dest {
-            let file = match fat::find_file(redir_dest, 0) {
-                (Some(f), false) => {
-                    if f.attr == Attribute::Directory as u8 {
-                        file::print_to_fd(
-                            &mut self.files[2].lock_wait(),
-                            "cannot redirect to a directory",
-                        );
-                        self.last_exit_code = 1;
-                        return;
-                    } else {
-                        f
-                    }
+            let file = match vfs::lookup(redir_dest) {
+                Ok(f) if f.stat().attr & fat::Attribute::ReadOnly as u8 != 0 => {
+                    file::print_to_fd(
+                        &mut self.files[2].lock_wait(),
+                        "cannot redirect to a read-only file\n",
+                    );
+                    self.last_exit_code = 1;
+                    return;
                 }
-                (None, false) => match fat::create_file(redir_dest) {
-                    Ok(f) => f,
-                    Err(e) => {
-                        file::print_to_fd(
-                            &mut self.files[2].lock_wait(),
-                            &format!("failed to create a redirect file: {}\n", e),
-                        );
-                        self.last_exit_code = 1;
-                        return;
+                Ok(f) if !f.stat().is_dir() => f,
+                Err(e) if e.cause() == Code::NoSuchEntry && !redir_dest.ends_with('/') => {
+                    match vfs::create(redir_dest) {
+                        Ok(f) => f,
+                        Err(e) => {
+                            file::print_to_fd(
+                                &mut self.files[2].lock_wait(),
+                                &format!("failed to create a redirect file: {}\n", e),
+                            );
+                            self.last_exit_code = 1;
+                            return;
+                        }
                     }
-                },
-                (_, true) => {
+                }
+                _ => {
                     file::print_to_fd(
                         &mut self.files[2].lock_wait(),
                         "cannot redirect to a directory",
@@ -540,7 +544,7 @@ impl Terminal {
                 }
             };
 
-            let mut new = Arc::new(Mutex::new(FileDescriptor::new_fat(file)));
+            let mut new = Arc::new(Mutex::new(FileDescriptor::new(file, redir_dest)));
             mem::swap(&mut self.files[1], &mut new);
             fd_term_out = Some(new);
         }
@@ -606,20 +610,20 @@ impl Terminal {
         'exe: {
             match command {
                 "echo" => {
-                    match args.get(1) {
-                        Some(&"$?") => {
-                            file::print_to_fd(
-                                &mut self.files[1].lock_wait(),
-                                &format!("{}", self.last_exit_code),
-                            );
-                        }
-                        Some(arg) => {
-                            file::print_to_fd(&mut self.files[1].lock_wait(), arg);
-                        }
-                        None => {}
-                    }
+                    let s = match args.get(1) {
+                        Some(&"$?") => format!("{}", self.last_exit_code),
+                        Some(arg) => String::from(*arg),
+                        None => String::new(),
+                    };
+                    let written = file::print_to_fd(&mut self.files[1].lock_wait(), &s);
                     self.print("\n");
-                    self.last_exit_code = 0;
+                    // リダイレクト先の tmpfs がいっぱいの場合など
+                    if written < s.len() {
+                        file::print_to_fd(&mut self.files[2].lock_wait(), "echo: write error\n");
+                        self.last_exit_code = 1;
+                    } else {
+                        self.last_exit_code = 0;
+                    }
                 }
                 "clear" => {
                     if let Some(ref window) = self.window {
@@ -651,61 +655,54 @@ impl Terminal {
                     self.last_exit_code = 0;
                 }
                 "ls" => {
-                    let Some(&first_arg) = args.get(1) else {
-                        self.list_all_entries(fat::BOOT_VOLUME_IMAGE.get().root_clus());
-                        break 'exe;
-                    };
-
-                    let (Some(dir), post_slash) = fat::find_file(first_arg, 0) else {
-                        let mut stderr = self.files[2].lock_wait();
-                        file::print_to_fd(&mut stderr, "No such file or directory: ");
-                        file::print_to_fd(&mut stderr, first_arg);
-                        file::print_to_fd(&mut stderr, "\n");
-                        self.last_exit_code = 1;
-                        break 'exe;
-                    };
-                    if dir.attr == fat::Attribute::Directory as _ {
-                        self.list_all_entries(dir.first_cluster());
-                    } else {
-                        let (base, ext) = fat::read_name(dir);
-                        // Safety: ASCII 文字列だけが格納されている
-                        let name = if ext.is_empty() {
-                            base.to_string()
-                        } else {
-                            format!("{}.{}", base, ext)
-                        };
-                        if post_slash {
-                            let mut stderr = self.files[2].lock_wait();
-                            file::print_to_fd(&mut stderr, &name);
-                            file::print_to_fd(&mut stderr, " is not a directory\n");
-                            self.last_exit_code = 1;
-                        } else {
+                    let first_arg = args.get(1).copied().unwrap_or("/");
+                    match vfs::lookup(first_arg) {
+                        Ok(mut dir) if dir.stat().is_dir() => {
+                            self.list_all_entries(dir.as_mut());
+                        }
+                        Ok(_) => {
                             let mut stdout = self.files[1].lock_wait();
-                            file::print_to_fd(&mut stdout, &name);
+                            file::print_to_fd(&mut stdout, first_arg);
                             file::print_to_fd(&mut stdout, "\n");
                             self.last_exit_code = 0;
                         }
-                    }
-                }
-                "cat" => {
-                    let fd = if let Some(file_path) = args.get(1) {
-                        let (Some(file_entry), post_slash) = fat::find_file(file_path, 0) else {
+                        Err(e) if e.cause() == Code::NotDirectory => {
                             let mut stderr = self.files[2].lock_wait();
-                            file::print_to_fd(
-                                &mut stderr,
-                                &format!("no such file: {}\n", file_path),
-                            );
+                            file::print_to_fd(&mut stderr, first_arg.trim_end_matches('/'));
+                            file::print_to_fd(&mut stderr, " is not a directory\n");
                             self.last_exit_code = 1;
-                            break 'exe;
-                        };
-                        if file_entry.attr != fat::Attribute::Directory as _ && post_slash {
+                        }
+                        Err(_) => {
                             let mut stderr = self.files[2].lock_wait();
-                            file::print_to_fd(&mut stderr, file_path);
-                            file::print_to_fd(&mut stderr, " is not a directory\n");
+                            file::print_to_fd(&mut stderr, "No such file or directory: ");
+                            file::print_to_fd(&mut stderr, first_arg);
+                            file::print_to_fd(&mut stderr, "\n");
                             self.last_exit_code = 1;
-                            break 'exe;
                         }
-                        Arc::new(Mutex::new(FileDescriptor::new_fat(file_entry)))
+                    }
+                }
+                "cat" => {
+                    let fd = if let Some(file_path) = args.get(1) {
+                        let file = match vfs::lookup(file_path) {
+                            Ok(file) => file,
+                            Err(e) if e.cause() == Code::NotDirectory => {
+                                let mut stderr = self.files[2].lock_wait();
+                                file::print_to_fd(&mut stderr, file_path);
+                                file::print_to_fd(&mut stderr, " is not a directory\n");
+                                self.last_exit_code = 1;
+                                break 'exe;
+                            }
+                            Err(_) => {
+                                let mut stderr = self.files[2].lock_wait();
+                                file::print_to_fd(
+                                    &mut stderr,
+                                    &format!("no such file: {}\n", file_path),
+                                );
+                                self.last_exit_code = 1;
+                                break 'exe;
+                            }
+                        };
+                        Arc::new(Mutex::new(FileDescriptor::new(file, file_path)))
                     } else {
                         self.files[0].clone()
                     };
@@ -740,6 +737,155 @@ impl Terminal {
                     }
                     self.last_exit_code = 0;
                 }
+                "rm" => {
+                    if args.len() < 2 {
+                        let mut stderr = self.files[2].lock_wait();
+                        file::print_to_fd(&mut stderr, "Usage: rm <file>...\n");
+                        self.last_exit_code = 1;
+                        break 'exe;
+                    }
+
+                    self.last_exit_code = 0;
+                    for path in &args[1..] {
+                        let Err(e) = vfs::remove(path) else {
+                            continue;
+                        };
+                        let msg = match e.cause() {
+                            Code::DirectoryNotEmpty => "directory not empty",
+                            Code::NotImplemented => "operation not permitted",
+                            _ => "no such file or directory",
+                        };
+                        let mut stderr = self.files[2].lock_wait();
+                        file::print_to_fd(&mut stderr, &format!("rm: {}: {}\n", path, msg));
+                        self.last_exit_code = 1;
+                    }
+                }
+                "mkdir" => {
+                    if args.len() < 2 {
+                        let mut stderr = self.files[2].lock_wait();
+                        file::print_to_fd(&mut stderr, "Usage: mkdir <dir>...\n");
+                        self.last_exit_code = 1;
+                        break 'exe;
+                    }
+
+                    self.last_exit_code = 0;
+                    for path in &args[1..] {
+                        let Err(e) = vfs::create_dir(path) else {
+                            continue;
+                        };
+                        let msg = match e.cause() {
+                            Code::FileExists => "file exists",
+                            Code::NotDirectory => "not a directory",
+                            Code::NoEnoughMemory => "no space left on device",
+                            Code::NameTooLong => "file name too long",
+                            Code::NotImplemented => "operation not permitted",
+                            _ => "no such file or directory",
+                        };
+                        let mut stderr = self.files[2].lock_wait();
+                        file::print_to_fd(&mut stderr, &format!("mkdir: {}: {}\n", path, msg));
+                        self.last_exit_code = 1;
+                    }
+                }
+                "rmdir" => {
+                    if args.len() < 2 {
+                        let mut stderr = self.files[2].lock_wait();
+                        file::print_to_fd(&mut stderr, "Usage: rmdir <dir>...\n");
+                        self.last_exit_code = 1;
+                        break 'exe;
+                    }
+
+                    self.last_exit_code = 0;
+                    for path in &args[1..] {
+                        let Err(e) = vfs::remove_dir(path) else {
+                            continue;
+                        };
+                        let msg = match e.cause() {
+                            Code::DirectoryNotEmpty => "directory not empty",
+                            Code::NotDirectory => "not a directory",
+                            Code::NotImplemented => "operation not permitted",
+                            _ => "no such file or directory",
+                        };
+                        let mut stderr = self.files[2].lock_wait();
+                        file::print_to_fd(&mut stderr, &format!("rmdir: {}: {}\n", path, msg));
+                        self.last_exit_code = 1;
+                    }
+                }
+                "mv" => {
+                    let (Some(&src), Some(&dest)) = (args.get(1), args.get(2)) else {
+                        let mut stderr = self.files[2].lock_wait();
+                        file::print_to_fd(&mut stderr, "Usage: mv <src> <dest>\n");
+                        self.last_exit_code = 1;
+                        break 'exe;
+                    };
+
+                    // 移動先が既存のディレクトリなら、その中に同じ名前で移動する。
+                    // 大文字小文字だけが違う場合は、名前を変えたいので移動元と同じものとみなす
+                    let same_name = src
+                        .trim_end_matches('/')
+                        .eq_ignore_ascii_case(dest.trim_end_matches('/'));
+                    let dest = match vfs::stat(dest) {
+                        Ok(stat) if stat.is_dir() && !same_name => {
+                            let name = src.trim_end_matches('/');
+                            let name = name.rsplit_once('/').map_or(name, |(_, name)| name);
+                            format!("{}/{}", dest.trim_end_matches('/'), name)
+                        }
+                        _ => dest.to_string(),
+                    };
+
+                    self.last_exit_code = 0;
+                    if let Err(e) = vfs::rename(src, &dest) {
+                        let msg = match e.cause() {
+                            Code::FileExists => "file exists",
+                            Code::NotDirectory => "not a directory",
+                            Code::IsDirectory => "is a directory",
+                            Code::InvalidFile => "cannot move a directory into itself",
+                            Code::NameTooLong => "file name too long",
+                            Code::CrossDevice => "cannot move across file systems",
+                            Code::NotImplemented => "operation not permitted",
+                            _ => "no such file or directory",
+                        };
+                        let mut stderr = self.files[2].lock_wait();
+                        file::print_to_fd(&mut stderr, &format!("mv: {}: {}\n", src, msg));
+                        self.last_exit_code = 1;
+                    }
+                }
+                "fsck" => {
+                    let repair = match args.get(1) {
+                        None => false,
+                        Some(&"-r") if args.len() == 2 => true,
+                        _ => {
+                            let mut stderr = self.files[2].lock_wait();
+                            file::print_to_fd(&mut stderr, "Usage: fsck [-r]\n");
+                            self.last_exit_code = 1;
+                            break 'exe;
+                        }
+                    };
+
+                    let problems = match fat::check(repair) {
+                        Ok(problems) => problems,
+                        Err(e) => {
+                            let mut stderr = self.files[2].lock_wait();
+                            file::print_to_fd(&mut stderr, &format!("fsck: {}\n", e));
+                            self.last_exit_code = 1;
+                            break 'exe;
+                        }
+                    };
+
+                    let mut stdout = self.files[1].lock_wait();
+                    for problem in &problems {
+                        file::print_to_fd(&mut stdout, &format!("{}\n", problem));
+                    }
+                    let repaired = if repair {
+                        problems.iter().filter(|p| p.repairable()).count()
+                    } else {
+                        0
+                    };
+                    file::print_to_fd(
+                        &mut stdout,
+                        &format!("{} problems found, {} repaired\n", problems.len(), repaired),
+                    );
+                    self.last_exit_code = if repaired < problems.len() { 1 } else { 0 };
+                }
                 "noterm" => {
                     if args.len() >= 2 {
                         let args = args[1..].iter().map(|&s| String::from(s)).collect();
@@ -794,6 +940,43 @@ impl Terminal {
                     file::print_to_fd(&mut stdout, &s);
                     self.last_exit_code = 0;
                 }
+                "df" => {
+                    if args.len() > 1 {
+                        let mut stderr = self.files[2].lock_wait();
+                        file::print_to_fd(&mut stderr, "Usage: df\n");
+                        self.last_exit_code = 1;
+                        break 'exe;
+                    }
+
+                    let mut s = format!(
+                        "{:<10} {:>12} {:>12} {:>12}  Mounted on\n",
+                        "Filesystem", "Total", "Used", "Free"
+                    );
+                    self.last_exit_code = 0;
+                    for (path, fs) in vfs::mounts() {
+                        // 容量を持たないファイルシステムは表示しない
+                        let stat = match fs.stat_fs() {
+                            Ok(stat) => stat,
+                            Err(e) if e.cause() == Code::NotImplemented => continue,
+                            Err(e) => {
+                                let mut stderr = self.files[2].lock_wait();
+                                file::print_to_fd(&mut stderr, &format!("df: {}: {}\n", path, e));
+                                self.last_exit_code = 1;
+                                continue;
+                            }
+                        };
+                        s += &format!(
+                            "{:<10} {:>12} {:>12} {:>12}  {}\n",
+                            fs.name(),
+                            stat.total_bytes,
+                            stat.total_bytes - stat.free_bytes,
+                            stat.free_bytes,
+                            path
+                        );
+                    }
+                    let mut stdout = self.files[1].lock_wait();
+                    file::print_to_fd(&mut stdout, &s);
+                }
                 command => {
                     if let Some(file_entry) = find_command(command, 0) {
                         match self.execute_file(file_entry, args) {
@@ -889,18 +1072,14 @@ impl Terminal {
         draw_area
     }
 
-    fn execute_file(
-        &mut self,
-        file_entry: &'static DirectoryEntry,
-        args: Vec<&str>,
-    ) -> Result<i32> {
+    fn execute_file(&mut self, file_entry: Entry, args: Vec<&str>) -> Result<i32> {
         asmfunc::cli();
         let task = task::current_task();
         asmfunc::sti();
 
         paging::setup_pml4(&task)?;
 
-        let app_load = load_app(file_entry, &task)?;
+        let app_load = load_app(&file_entry, &task)?;
 
         // デマンドページを ELF バイナリの最後から割り当てる
         let elf_next_page = (app_load.vaddr_end + 4095) & !0xfff;
@@ -961,35 +1140,10 @@ impl Terminal {
         Ok(ret)
     }
 
-    fn list_all_entries(&mut self, mut dir_cluster: u32) {
-        let entries_per_cluster =
-            BYTES_PER_CLUSTER.get() as usize / mem::size_of::<fat::DirectoryEntry>();
-
-        while dir_cluster != fat::END_OF_CLUSTER_CHAIN as _ {
-            let dir = fat::get_sector_by_cluster::<fat::DirectoryEntry>(
-                dir_cluster as _,
-                entries_per_cluster,
-            );
-
-            for entry in dir {
-                let (base, ext) = fat::read_name(entry);
-                // ファイル終了
-                if base.as_bytes()[0] == 0x00 {
-                    return;
-                } else if base.as_bytes()[0] == 0x5e || entry.attr == fat::Attribute::LongName as u8
-                {
-                    continue;
-                }
-
-                let s = if !ext.is_empty() {
-                    format!("{}.{}\n", base, ext)
-                } else {
-                    format!("{}\n", base)
-                };
-                self.print(&s);
-            }
-
-            dir_cluster = fat::next_cluster(dir_cluster as _) as _;
+    fn list_all_entries(&mut self, dir: &mut dyn File) {
+        while let Ok(Some((_, name))) = dir.read_dir() {
+            self.print(&name);
+            self.print("\n");
         }
     }
 }
@@ -1036,16 +1190,16 @@ fn load_elf(ehdr: &Elf64Ehdr) -> Result<u64> {
 
 /// アプリがロードされていなければ読み取り専用でロードし、
 /// 既にどこかにロードされている場合は PT（ページテーブル）ごとその浅いコピーを返す。
-fn load_app(file_entry: &'static DirectoryEntry, task: &Arc<Task>) -> Result<AppLoadInfo> {
+fn load_app(file_entry: &Entry, task: &Arc<Task>) -> Result<AppLoadInfo> {
     let temp_pml4 = paging::setup_pml4(task)?;
 
     let mut app_loads = APP_LOADS.lock_wait();
-    if let Some(app_load) = app_loads.get(&file_entry).cloned() {
+    if let Some(app_load) = app_loads.get(&file_entry.location).cloned() {
         paging::copy_page_maps(temp_pml4, app_load.pml4, 4, 256)?;
         return Ok(AppLoadInfo::new(&app_load, temp_pml4));
     }
 
-    let file_buf = fat::load_file(file_entry);
+    let file_buf = fat::load_file(&file_entry.dir_entry);
 
     let elf_header: &Elf64Ehdr = unsafe { &*(file_buf.as_ptr() as *const _) };
     if &elf_header.ident[..4] != b"\x7fELF" {
@@ -1060,7 +1214,7 @@ fn load_app(file_entry: &'static DirectoryEntry, task: &Arc<Task>) -> Result<App
         pml4: &*temp_pml4,
     };
 
-    app_loads.insert(file_entry, app_load_temp.clone());
+    app_loads.insert(file_entry.location, app_load_temp.clone());
 
     let app_load = AppLoadInfo::new(&app_load_temp, paging::setup_pml4(task)?);
     paging::copy_page_maps(app_load.pml4, app_load_temp.pml4, 4, 256)?;
@@ -1153,11 +1307,11 @@ fn make_arg_vector(args: Vec<&str>, buf: &mut [u8]) -> Result<usize> {
 }
 
 /// `command` を絶対パス、相対パス、もしくは `/apps` に含まれているファイル名として探索する。
-fn find_command(command: &str, dir_cluster: u64) -> Option<&'static DirectoryEntry> {
-    match fat::find_file(command, dir_cluster) {
+fn find_command(command: &str, dir_cluster: u64) -> Option<Entry> {
+    match fat::find_file(command, dir_cluster).ok()? {
         (_, true) => return None,
         (Some(entry), false) => {
-            if entry.attr == Attribute::Directory as _ {
+            if entry.is_dir() {
                 return None;
             } else {
                 return Some(entry);
@@ -1170,8 +1324,8 @@ fn find_command(command: &str, dir_cluster: u64) -> Option<&'static DirectoryEnt
         }
     }
 
-    if let (Some(apps_entry), _) = fat::find_file("apps", 0) {
-        find_command(command, apps_entry.first_cluster() as _)
+    if let Ok((Some(apps_entry), _)) = fat::find_file("apps", 0) {
+        find_command(command, apps_entry.first_cluster())
     } else {
         None
     }
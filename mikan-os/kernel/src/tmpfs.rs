@@ -0,0 +1,445 @@
+//! メモリ上にファイルを置く一時ファイルシステム。
+//!
+//! ファイルの内容は [MEMORY_MANAGER] から 1 フレームずつ確保して保持し、ファイルを削除したときに解放する。
+//! 削除したファイルを開いたままの場合は、最後に閉じたときに解放する。
+//! 確保できるフレームの数には上限があり、それを超える書き込みはエラーになる。
+
+use core::{cmp, ptr, slice};
+
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    string::{String, ToString as _},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+
+use crate::{
+    error::{Code, Result},
+    fat::Attribute,
+    file::Stat,
+    make_error,
+    memory_manager::{FrameId, BYTES_PER_FRAME, MEMORY_MANAGER},
+    sync::Mutex,
+    vfs::{File, FileSystem},
+};
+
+/// ファイルやディレクトリの名前の最大バイト数
+const NAME_MAX: usize = 255;
+
+pub struct TmpFileSystem {
+    /// 先頭と末尾の `/` を除いたパスから、そこにあるノードへの対応。ルートディレクトリは含まない
+    nodes: Mutex<BTreeMap<String, Node>>,
+    usage: Arc<Usage>,
+}
+
+impl TmpFileSystem {
+    /// 最大 `size` バイトまでファイルを置けるファイルシステムを作る。
+    pub fn new(size: usize) -> Self {
+        Self {
+            nodes: Mutex::new(BTreeMap::new()),
+            usage: Arc::new(Usage {
+                limit: size / BYTES_PER_FRAME,
+                used: Mutex::new(0),
+            }),
+        }
+    }
+
+    /// `path` にあるノードを削除する。`dir` が true なら空のディレクトリを、false ならファイルを削除する。
+    fn remove_node(&self, path: &str, dir: bool) -> Result<()> {
+        let post_slash = path.ends_with('/');
+        let path = normalize(path)?;
+        let mut nodes = self.nodes.lock_wait();
+        match nodes.get(path) {
+            Some(Node::Dir) if !dir => return Err(make_error!(Code::IsDirectory)),
+            Some(Node::Dir) if has_children(&nodes, path) => {
+                return Err(make_error!(Code::DirectoryNotEmpty))
+            }
+            Some(Node::File(_)) if dir => return Err(make_error!(Code::NotDirectory)),
+            Some(Node::File(_)) if post_slash => return Err(make_error!(Code::NoSuchEntry)),
+            Some(_) => {}
+            None => return Err(make_error!(Code::NoSuchEntry)),
+        }
+        // 開いているファイルがなければ、ここでフレームが解放される
+        nodes.remove(path);
+        Ok(())
+    }
+}
+
+impl FileSystem for TmpFileSystem {
+    fn name(&self) -> &str {
+        "tmp"
+    }
+
+    fn lookup(&self, path: &str) -> Result<Box<dyn File>> {
+        let post_slash = path.ends_with('/');
+        let path = normalize(path)?;
+        let nodes = self.nodes.lock_wait();
+        if path.is_empty() {
+            return Ok(Box::new(TmpDir::new(&nodes, "")));
+        }
+
+        match nodes.get(path) {
+            Some(Node::Dir) => Ok(Box::new(TmpDir::new(&nodes, path))),
+            Some(Node::File(_)) if post_slash => Err(make_error!(Code::NotDirectory)),
+            Some(Node::File(data)) => Ok(Box::new(TmpFile::new(data.clone()))),
+            None => {
+                check_parent(&nodes, path)?;
+                Err(make_error!(Code::NoSuchEntry))
+            }
+        }
+    }
+
+    fn create(&self, path: &str) -> Result<Box<dyn File>> {
+        if path.ends_with('/') {
+            return Err(make_error!(Code::IsDirectory));
+        }
+        let path = normalize(path)?;
+        let mut nodes = self.nodes.lock_wait();
+        check_new_entry(&nodes, path)?;
+
+        let data = Arc::new(Mutex::new(TmpData {
+            frames: Vec::new(),
+            size: 0,
+            usage: self.usage.clone(),
+        }));
+        nodes.insert(path.to_string(), Node::File(data.clone()));
+        Ok(Box::new(TmpFile::new(data)))
+    }
+
+    fn remove(&self, path: &str) -> Result<()> {
+        self.remove_node(path, false)
+    }
+
+    fn create_dir(&self, path: &str) -> Result<()> {
+        let path = normalize(path)?;
+        let mut nodes = self.nodes.lock_wait();
+        check_new_entry(&nodes, path)?;
+        nodes.insert(path.to_string(), Node::Dir);
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &str) -> Result<()> {
+        self.remove_node(path, true)
+    }
+
+    fn rename(&self, old_path: &str, new_path: &str) -> Result<()> {
+        let old_path = normalize(old_path)?;
+        let new_path = normalize(new_path)?;
+        let mut nodes = self.nodes.lock_wait();
+
+        let Some(node) = nodes.get(old_path) else {
+            return Err(make_error!(Code::NoSuchEntry));
+        };
+        let is_dir = matches!(node, Node::Dir);
+        check_new_entry(&nodes, new_path)?;
+        // ディレクトリを自身の中に移動することはできない
+        if is_dir && is_descendant(new_path, old_path) {
+            return Err(make_error!(Code::InvalidFile));
+        }
+
+        let node = nodes.remove(old_path).unwrap();
+        nodes.insert(new_path.to_string(), node);
+        if is_dir {
+            // ディレクトリの中身もパスを付け替える
+            let children: Vec<_> = nodes
+                .keys()
+                .filter(|p| is_descendant(p, old_path))
+                .cloned()
+                .collect();
+            for child in children {
+                let node = nodes.remove(&child).unwrap();
+                nodes.insert(format_path(new_path, &child[old_path.len() + 1..]), node);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+enum Node {
+    File(Arc<Mutex<TmpData>>),
+    Dir,
+}
+
+/// ファイルシステム全体で確保しているフレームの数。
+struct Usage {
+    /// 確保できるフレームの数の上限
+    limit: usize,
+    used: Mutex<usize>,
+}
+
+impl Usage {
+    /// 0 で埋めたフレームを 1 つ確保する。
+    fn allocate(&self) -> Result<FrameId> {
+        let mut used = self.used.lock_wait();
+        if *used >= self.limit {
+            return Err(make_error!(Code::NoEnoughMemory));
+        }
+        let frame = MEMORY_MANAGER.allocate(1)?;
+        unsafe { ptr::write_bytes(frame.frame(), 0, BYTES_PER_FRAME) };
+        *used += 1;
+        Ok(frame)
+    }
+
+    fn free(&self, frame: FrameId) {
+        MEMORY_MANAGER.free(frame, 1);
+        *self.used.lock_wait() -= 1;
+    }
+}
+
+/// ファイルの内容。
+struct TmpData {
+    /// ファイル先頭から順に内容を保持するフレーム
+    frames: Vec<FrameId>,
+    size: usize,
+    usage: Arc<Usage>,
+}
+
+impl TmpData {
+    /// `offset` バイト目から `buf` に読み込み、読み込んだバイト数を返す。
+    fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let len = cmp::min(buf.len(), self.size.saturating_sub(offset));
+        let mut total = 0;
+        while total < len {
+            let pos = offset + total;
+            let page_off = pos % BYTES_PER_FRAME;
+            let n = cmp::min(len - total, BYTES_PER_FRAME - page_off);
+            let page = self.page(pos / BYTES_PER_FRAME);
+            buf[total..total + n].copy_from_slice(&page[page_off..page_off + n]);
+            total += n;
+        }
+        total
+    }
+
+    /// `offset` バイト目から `buf` を書き込み、書き込んだバイト数を返す。
+    /// フレームが足りなくなった場合は、書き込めたところまでのバイト数を返す。
+    fn write(&mut self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let end = offset + buf.len();
+        while self.frames.len() * BYTES_PER_FRAME < end {
+            match self.usage.allocate() {
+                Ok(frame) => self.frames.push(frame),
+                Err(e) if self.frames.len() * BYTES_PER_FRAME <= offset => return Err(e),
+                Err(_) => break,
+            }
+        }
+
+        let len = cmp::min(buf.len(), self.frames.len() * BYTES_PER_FRAME - offset);
+        let mut total = 0;
+        while total < len {
+            let pos = offset + total;
+            let page_off = pos % BYTES_PER_FRAME;
+            let n = cmp::min(len - total, BYTES_PER_FRAME - page_off);
+            let page = self.page(pos / BYTES_PER_FRAME);
+            page[page_off..page_off + n].copy_from_slice(&buf[total..total + n]);
+            total += n;
+        }
+        self.size = cmp::max(self.size, offset + len);
+        Ok(len)
+    }
+
+    /// `size` バイトに切り詰め、要らなくなったフレームを解放する。
+    fn truncate(&mut self, size: usize) -> Result<()> {
+        if size > self.size {
+            return Err(make_error!(Code::IndexOutOfRange));
+        }
+        for frame in self.frames.drain(size.div_ceil(BYTES_PER_FRAME)..) {
+            self.usage.free(frame);
+        }
+        // 後で広げたときに、切り詰めた部分の内容が見えないようにする
+        if !size.is_multiple_of(BYTES_PER_FRAME) {
+            self.page(size / BYTES_PER_FRAME)[size % BYTES_PER_FRAME..].fill(0);
+        }
+        self.size = size;
+        Ok(())
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn page(&self, index: usize) -> &mut [u8] {
+        // Safety: フレームはアイデンティティマップされていて、このファイルだけが使っている
+        unsafe { slice::from_raw_parts_mut(self.frames[index].frame(), BYTES_PER_FRAME) }
+    }
+}
+
+impl Drop for TmpData {
+    fn drop(&mut self) {
+        for &frame in &self.frames {
+            self.usage.free(frame);
+        }
+    }
+}
+
+/// 開かれた一時ファイル。
+struct TmpFile {
+    data: Arc<Mutex<TmpData>>,
+    /// 読み書きする位置のファイル先頭からのオフセット
+    off: usize,
+}
+
+impl TmpFile {
+    fn new(data: Arc<Mutex<TmpData>>) -> Self {
+        Self { data, off: 0 }
+    }
+}
+
+impl File for TmpFile {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let n = self.data.lock_wait().read(self.off, buf);
+        self.off += n;
+        n
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.data.lock_wait().write(self.off, buf)?;
+        self.off += n;
+        Ok(n)
+    }
+
+    fn stat(&self) -> Stat {
+        file_stat(&self.data.lock_wait())
+    }
+
+    fn seek(&mut self, offset: usize) -> Result<()> {
+        if offset > self.data.lock_wait().size {
+            return Err(make_error!(Code::IndexOutOfRange));
+        }
+        self.off = offset;
+        Ok(())
+    }
+
+    fn truncate(&mut self, size: usize) -> Result<()> {
+        self.data.lock_wait().truncate(size)?;
+        self.off = cmp::min(self.off, size);
+        Ok(())
+    }
+
+    fn position(&self) -> usize {
+        self.off
+    }
+}
+
+/// 開いた時点のエントリの一覧を保持するディレクトリ。
+struct TmpDir {
+    entries: vec::IntoIter<(Stat, String)>,
+}
+
+impl TmpDir {
+    /// `nodes` の中から、`dir` の直下にあるエントリを集める。
+    fn new(nodes: &BTreeMap<String, Node>, dir: &str) -> Self {
+        let entries: Vec<_> = nodes
+            .iter()
+            .filter_map(|(path, node)| {
+                let name = if dir.is_empty() {
+                    path.as_str()
+                } else {
+                    path.strip_prefix(dir)?.strip_prefix('/')?
+                };
+                if name.contains('/') {
+                    return None;
+                }
+                let stat = match node {
+                    Node::File(data) => file_stat(&data.lock_wait()),
+                    Node::Dir => dir_stat(),
+                };
+                Some((stat, name.to_string()))
+            })
+            .collect();
+        Self {
+            entries: entries.into_iter(),
+        }
+    }
+}
+
+impl File for TmpDir {
+    fn read(&mut self, _buf: &mut [u8]) -> usize {
+        0
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+        Err(make_error!(Code::IsDirectory))
+    }
+
+    fn stat(&self) -> Stat {
+        dir_stat()
+    }
+
+    fn read_dir(&mut self) -> Result<Option<(Stat, String)>> {
+        Ok(self.entries.next())
+    }
+}
+
+fn file_stat(data: &TmpData) -> Stat {
+    Stat {
+        size: data.size as _,
+        attr: Attribute::Archive as _,
+        ..Default::default()
+    }
+}
+
+fn dir_stat() -> Stat {
+    Stat {
+        attr: Attribute::Directory as _,
+        ..Default::default()
+    }
+}
+
+/// 先頭と末尾の `/` を除いたパスを返す。
+/// 空の要素や長すぎる名前、`.` と `..` を含む場合はエラーを返す。
+fn normalize(path: &str) -> Result<&str> {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        return Ok(path);
+    }
+    for name in path.split('/') {
+        if name.is_empty() {
+            return Err(make_error!(Code::NoSuchEntry));
+        }
+        // `.` と `..` のエントリは持たないので、普通の名前として扱わせない
+        if name == "." || name == ".." {
+            return Err(make_error!(Code::InvalidFile));
+        }
+        if name.len() > NAME_MAX {
+            return Err(make_error!(Code::NameTooLong));
+        }
+    }
+    Ok(path)
+}
+
+/// `path` の親ディレクトリが存在することを確かめる。
+fn check_parent(nodes: &BTreeMap<String, Node>, path: &str) -> Result<()> {
+    let Some((parent, _)) = path.rsplit_once('/') else {
+        return Ok(());
+    };
+    match nodes.get(parent) {
+        Some(Node::Dir) => Ok(()),
+        Some(Node::File(_)) => Err(make_error!(Code::NotDirectory)),
+        None => Err(make_error!(Code::NoSuchEntry)),
+    }
+}
+
+/// `path` に新しいエントリを作れることを確かめる。
+fn check_new_entry(nodes: &BTreeMap<String, Node>, path: &str) -> Result<()> {
+    if path.is_empty() || nodes.contains_key(path) {
+        return Err(make_error!(Code::FileExists));
+    }
+    check_parent(nodes, path)
+}
+
+/// `dir` の中にエントリがあるかを返す。
+fn has_children(nodes: &BTreeMap<String, Node>, dir: &str) -> bool {
+    nodes.keys().any(|path| is_descendant(path, dir))
+}
+
+/// `path` が `dir` の中にあるかを返す。
+fn is_descendant(path: &str, dir: &str) -> bool {
+    path.strip_prefix(dir)
+        .is_some_and(|rest| rest.starts_with('/'))
+}
+
+fn format_path(dir: &str, name: &str) -> String {
+    let mut path = String::from(dir);
+    path.push('/');
+    path.push_str(name);
+    path
+}
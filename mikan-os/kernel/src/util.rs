@@ -35,6 +35,11 @@ impl<T> OnceStatic<T> {
         }
     }
 
+    /// 初期化済みかを返す。
+    pub fn is_initialized(&self) -> bool {
+        self.is_initialized.load(Acquire)
+    }
+
     /// 初期化する。
     /// 1度しか呼び出さないこと。
     /// 2度目以降は `panic` を起こす。
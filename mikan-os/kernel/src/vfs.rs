@@ -0,0 +1,240 @@
+//! 仮想ファイルシステム。
+//!
+//! ファイルシステムをパスにマウントしておき、パスを受け取る操作はマウントテーブルから
+//! そのパスを担当するファイルシステムを探して、マウントポイントからの相対パスで処理を任せる。
+
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    error::{Code, Result},
+    file::Stat,
+    make_error,
+    memory_manager::FrameId,
+    sync::Mutex,
+    terminal::TerminalRef,
+};
+
+/// マウントされているファイルシステムの一覧。
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+struct Mount {
+    /// 先頭と末尾の `/` を除いたマウントポイント。ルートは空文字列
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+/// ファイルシステム全体の容量。
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FsStat {
+    /// 全体のバイト数
+    pub total_bytes: u64,
+    /// 空いているバイト数
+    pub free_bytes: u64,
+}
+
+/// パスで指定されたファイルを扱うファイルシステム。
+/// パスはマウントポイントからの相対パスで渡される。
+pub trait FileSystem: Send + Sync {
+    /// ファイルシステムの種類の名前を返す。
+    fn name(&self) -> &str;
+
+    /// `path` にあるファイルもしくはディレクトリを開く。
+    fn lookup(&self, path: &str) -> Result<Box<dyn File>>;
+
+    /// `path` に空のファイルを作って開く。
+    fn create(&self, _path: &str) -> Result<Box<dyn File>> {
+        Err(make_error!(Code::NotImplemented))
+    }
+
+    /// `path` にあるファイルもしくはディレクトリのメタデータを返す。
+    fn stat(&self, path: &str) -> Result<Stat> {
+        Ok(self.lookup(path)?.stat())
+    }
+
+    /// `path` にあるファイルを削除する。
+    fn remove(&self, _path: &str) -> Result<()> {
+        Err(make_error!(Code::NotImplemented))
+    }
+
+    /// `path` にディレクトリを作る。
+    fn create_dir(&self, _path: &str) -> Result<()> {
+        Err(make_error!(Code::NotImplemented))
+    }
+
+    /// `path` にある空のディレクトリを削除する。
+    fn remove_dir(&self, _path: &str) -> Result<()> {
+        Err(make_error!(Code::NotImplemented))
+    }
+
+    /// `old_path` にあるファイルもしくはディレクトリを `new_path` に移動する。
+    fn rename(&self, _old_path: &str, _new_path: &str) -> Result<()> {
+        Err(make_error!(Code::NotImplemented))
+    }
+
+    /// 変更をデバイスに書き戻す。
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// ファイルシステム全体の容量を返す。
+    fn stat_fs(&self) -> Result<FsStat> {
+        Err(make_error!(Code::NotImplemented))
+    }
+}
+
+/// 開かれたファイル。ファイルディスクリプタはこれを通して読み書きする。
+pub trait File: Send {
+    /// `buf` に読み込み、読み込んだバイト数を返す。0 を返した場合は終端に達している。
+    fn read(&mut self, buf: &mut [u8]) -> usize;
+
+    /// `buf` を書き込み、書き込んだバイト数を返す。
+    fn write(&mut self, buf: &[u8]) -> Result<usize>;
+
+    /// メタデータを返す。
+    fn stat(&self) -> Stat;
+
+    /// ディレクトリ内の次のエントリのメタデータと名前を返す。`.` と `..` は返さない。
+    /// ディレクトリでない場合はエラーを返す。
+    fn read_dir(&mut self) -> Result<Option<(Stat, String)>> {
+        Err(make_error!(Code::NotDirectory))
+    }
+
+    /// 読み書きする位置を、ファイル先頭から `offset` バイトの位置に移動する。
+    fn seek(&mut self, _offset: usize) -> Result<()> {
+        Err(make_error!(Code::InvalidFile))
+    }
+
+    /// ファイルを `size` バイトに切り詰める。`size` は今のファイルサイズ以下でなければならない。
+    /// 内容を保持しないファイル（デバイスやパイプなど）では何もしない。
+    fn truncate(&mut self, _size: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// 読み書きする位置のファイル先頭からのオフセットを返す。
+    fn position(&self) -> usize {
+        0
+    }
+
+    /// ファイルの `offset` バイト目からの 1 ページを保持するフレームを、マップするために返す。
+    fn map_page(&self, _offset: usize) -> Result<FrameId> {
+        Err(make_error!(Code::InvalidFile))
+    }
+
+    /// [File::map_page] で返すフレームがデバイスのメモリで、書き込みをコピーせずにそのまま反映する場合は true を返す。
+    /// そのフレームはマップを外しても解放しない。
+    fn map_shared(&self) -> bool {
+        false
+    }
+
+    /// 端末に結びついたファイルの場合は、出力先の端末を `term` にする。
+    fn set_terminal(&mut self, _term: TerminalRef) {}
+
+    /// 書き込みが終わったことを読み込み側に伝える。
+    fn finish_write(&self) {}
+}
+
+/// `fs` を `path` にマウントする。
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<()> {
+    let path = path.trim_matches('/');
+    let mut mounts = MOUNTS.lock_wait();
+    if mounts.iter().any(|m| m.path == path) {
+        return Err(make_error!(Code::FileExists));
+    }
+    mounts.push(Mount {
+        path: String::from(path),
+        fs,
+    });
+    Ok(())
+}
+
+/// マウントポイントとファイルシステムの一覧を、マウントした順に返す。
+/// マウントポイントは `/` から始まる。
+pub fn mounts() -> Vec<(String, Arc<dyn FileSystem>)> {
+    MOUNTS
+        .lock_wait()
+        .iter()
+        .map(|m| (format!("/{}", m.path), m.fs.clone()))
+        .collect()
+}
+
+/// `path` を担当するファイルシステムと、そのマウントポイントからの相対パスを返す。
+fn resolve(path: &str) -> Result<(Arc<dyn FileSystem>, &str)> {
+    let path = path.trim_start_matches('/');
+    let mounts = MOUNTS.lock_wait();
+    // 最も長く一致するマウントポイントを選ぶ
+    let (mount, rest) = mounts
+        .iter()
+        .filter_map(|m| {
+            let rest = path.strip_prefix(m.path.as_str())?;
+            if m.path.is_empty() || rest.is_empty() || rest.starts_with('/') {
+                Some((m, rest.trim_start_matches('/')))
+            } else {
+                None
+            }
+        })
+        .max_by_key(|(m, _)| m.path.len())
+        .ok_or(make_error!(Code::NoSuchEntry))?;
+    Ok((mount.fs.clone(), rest))
+}
+
+/// `path` にあるファイルもしくはディレクトリを開く。
+pub fn lookup(path: &str) -> Result<Box<dyn File>> {
+    let (fs, path) = resolve(path)?;
+    fs.lookup(path)
+}
+
+/// `path` に空のファイルを作って開く。
+pub fn create(path: &str) -> Result<Box<dyn File>> {
+    let (fs, path) = resolve(path)?;
+    fs.create(path)
+}
+
+/// `path` にあるファイルもしくはディレクトリのメタデータを返す。
+pub fn stat(path: &str) -> Result<Stat> {
+    let (fs, path) = resolve(path)?;
+    fs.stat(path)
+}
+
+/// `path` にあるファイルを削除する。
+pub fn remove(path: &str) -> Result<()> {
+    let (fs, path) = resolve(path)?;
+    fs.remove(path)
+}
+
+/// `path` にディレクトリを作る。
+pub fn create_dir(path: &str) -> Result<()> {
+    let (fs, path) = resolve(path)?;
+    fs.create_dir(path)
+}
+
+/// `path` にある空のディレクトリを削除する。
+pub fn remove_dir(path: &str) -> Result<()> {
+    let (fs, path) = resolve(path)?;
+    fs.remove_dir(path)
+}
+
+/// `old_path` にあるファイルもしくはディレクトリを `new_path` に移動する。
+/// 異なるファイルシステムの間では移動できない。
+pub fn rename(old_path: &str, new_path: &str) -> Result<()> {
+    let (old_fs, old_path) = resolve(old_path)?;
+    let (new_fs, new_path) = resolve(new_path)?;
+    if !Arc::ptr_eq(&old_fs, &new_fs) {
+        return Err(make_error!(Code::CrossDevice));
+    }
+    old_fs.rename(old_path, new_path)
+}
+
+/// `path` を含むファイルシステム全体の容量を返す。
+pub fn stat_fs(path: &str) -> Result<FsStat> {
+    let (fs, _) = resolve(path)?;
+    fs.stat_fs()
+}
+
+/// 全てのファイルシステムの変更をデバイスに書き戻す。
+pub fn sync() -> Result<()> {
+    let filesystems: Vec<_> = MOUNTS.lock_wait().iter().map(|m| m.fs.clone()).collect();
+    for fs in filesystems {
+        fs.sync()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,323 @@
+//! virtio-blk のデバイスドライバ。
+//!
+//! レガシーインターフェース（I/O ポート経由のレジスタ）にのみ対応している。
+//! リクエストは 1 つずつ発行し、完了はポーリングで待つ。
+//! 完了を待つ時間には上限を設け、デバイスが応答しない場合は [Code::TransferFailed] を返す。
+
+use core::{
+    hint::spin_loop,
+    mem, ptr,
+    sync::atomic::{fence, Ordering},
+};
+
+use alloc::boxed::Box;
+
+use crate::{
+    acpi::Deadline,
+    asmfunc::{io_in_16, io_in_32, io_out_16, io_out_32, io_out_8},
+    bitfield::BitField as _,
+    block::{self, BlockDevice},
+    error::{Code, Result},
+    log,
+    logger::LogLevel,
+    make_error,
+    memory_manager::{BYTES_PER_FRAME, MEMORY_MANAGER},
+    pci,
+};
+
+/// virtio-blk が扱うセクタのサイズ。
+const SECTOR_SIZE: usize = 512;
+
+const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+/// レガシーインターフェースを持つ virtio-blk のデバイス ID
+const VIRTIO_BLK_LEGACY_DEVICE_ID: u16 = 0x1001;
+
+// レガシーインターフェースのレジスタの I/O ポートオフセット
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_ADDRESS: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0c;
+const REG_QUEUE_SELECT: u16 = 0x0e;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_DEVICE_STATUS: u16 = 0x12;
+/// デバイス固有の設定領域のうち、容量（セクタ数）を表すレジスタ
+const REG_CAPACITY: u16 = 0x14;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FAILED: u8 = 128;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// デバイスが書き込むバッファであることを示す
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+
+/// レガシーインターフェースの virtqueue のアラインメント
+const VIRTQ_ALIGN: usize = 4096;
+
+/// リクエストの完了を待つ最大のミリ秒数
+const REQUEST_TIMEOUT_MSEC: u64 = 5000;
+
+pub fn init() {
+    let dev = pci::DEVICES.read().iter().copied().find(|dev| {
+        dev.read_vendor_id() == VIRTIO_VENDOR_ID
+            && dev.read_device_id() == VIRTIO_BLK_LEGACY_DEVICE_ID
+    });
+    let Some(dev) = dev else {
+        log!(LogLevel::Info, "virtio-blk is not found");
+        return;
+    };
+    log!(
+        LogLevel::Info,
+        "virtio-blk has been found: {}.{}.{}",
+        dev.bus(),
+        dev.device(),
+        dev.function()
+    );
+
+    // I/O 空間へのアクセスとバスマスタを有効にする
+    let command = dev.read_conf_reg(0x04);
+    dev.write_conf_reg(0x04, command | 0b101);
+
+    let bar = match dev.read_bar(0) {
+        Ok(bar) => bar,
+        Err(e) => {
+            log!(LogLevel::Error, "failed to read BAR0 of virtio-blk: {}", e);
+            return;
+        }
+    };
+    if !bar.get_bit(0) {
+        log!(LogLevel::Error, "BAR0 of virtio-blk is not an I/O space");
+        return;
+    }
+    let io_base = (bar & !0x3) as u16;
+
+    match VirtioBlk::new(io_base) {
+        Ok(blk) => {
+            log!(
+                LogLevel::Info,
+                "virtio-blk: capacity = {} sectors",
+                blk.capacity()
+            );
+            block::register(Box::new(blk));
+        }
+        Err(e) => log!(LogLevel::Error, "failed to initialize virtio-blk: {}", e),
+    }
+}
+
+#[repr(C)]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct VirtqUsedElem {
+    id: u32,
+    len: u32,
+}
+
+#[repr(C)]
+struct BlkRequestHeader {
+    ty: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+pub struct VirtioBlk {
+    io_base: u16,
+    queue_size: u16,
+    /// ディスクリプタテーブル
+    desc: *mut VirtqDesc,
+    /// Available リングの先頭（flags, idx, ring[queue_size] の順に並ぶ）
+    avail: *mut u16,
+    /// Used リングの先頭（flags, idx, ring[queue_size] の順に並ぶ）
+    used: *mut u16,
+    /// 最後に処理を確認した Used リングのインデックス
+    last_used_idx: u16,
+    capacity: u64,
+    header: Box<BlkRequestHeader>,
+    status: Box<u8>,
+    /// リクエストがタイムアウトした場合は true。
+    /// デバイスをリセットして止めてあるので、以降は使わない
+    broken: bool,
+}
+
+// Safety: 生ポインタはこの構造体が確保した virtqueue のみを指している
+unsafe impl Send for VirtioBlk {}
+
+impl VirtioBlk {
+    fn new(io_base: u16) -> Result<Self> {
+        // デバイスをリセットしてから、ドライバが認識したことを伝える
+        io_out_8(io_base + REG_DEVICE_STATUS, 0);
+        io_out_8(io_base + REG_DEVICE_STATUS, STATUS_ACKNOWLEDGE);
+        io_out_8(
+            io_base + REG_DEVICE_STATUS,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER,
+        );
+
+        // 追加の機能は使わない
+        let _features = io_in_32(io_base + REG_DEVICE_FEATURES);
+        io_out_32(io_base + REG_GUEST_FEATURES, 0);
+
+        io_out_16(io_base + REG_QUEUE_SELECT, 0);
+        let queue_size = io_in_16(io_base + REG_QUEUE_SIZE);
+        if queue_size < 3 {
+            io_out_8(io_base + REG_DEVICE_STATUS, STATUS_FAILED);
+            return Err(make_error!(Code::InvalidDescriptor));
+        }
+
+        let n = queue_size as usize;
+        let avail_offset = n * mem::size_of::<VirtqDesc>();
+        let used_offset = (avail_offset + 2 * (3 + n)).next_multiple_of(VIRTQ_ALIGN);
+        let queue_bytes = used_offset + 2 * 3 + n * mem::size_of::<VirtqUsedElem>();
+        let num_frames = queue_bytes.div_ceil(BYTES_PER_FRAME);
+
+        let frame = match MEMORY_MANAGER.allocate(num_frames) {
+            Ok(frame) => frame,
+            Err(e) => {
+                io_out_8(io_base + REG_DEVICE_STATUS, STATUS_FAILED);
+                return Err(e);
+            }
+        };
+        let queue = frame.frame();
+        unsafe { ptr::write_bytes(queue, 0, num_frames * BYTES_PER_FRAME) };
+
+        // カーネルの領域はアイデンティティマッピングされているので、
+        // 仮想アドレスをそのまま物理アドレスとして渡せる
+        io_out_32(
+            io_base + REG_QUEUE_ADDRESS,
+            (queue as usize / VIRTQ_ALIGN) as u32,
+        );
+        io_out_8(
+            io_base + REG_DEVICE_STATUS,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK,
+        );
+
+        let capacity = io_in_32(io_base + REG_CAPACITY) as u64
+            | (io_in_32(io_base + REG_CAPACITY + 4) as u64) << 32;
+
+        Ok(Self {
+            io_base,
+            queue_size,
+            desc: queue as _,
+            avail: unsafe { queue.add(avail_offset) } as _,
+            used: unsafe { queue.add(used_offset) } as _,
+            last_used_idx: 0,
+            capacity,
+            header: Box::new(BlkRequestHeader {
+                ty: 0,
+                reserved: 0,
+                sector: 0,
+            }),
+            status: Box::new(0),
+            broken: false,
+        })
+    }
+
+    fn request(&mut self, ty: u32, sector: u64, buf: *mut u8, len: usize) -> Result<()> {
+        if self.broken {
+            return Err(make_error!(Code::TransferFailed));
+        }
+        if !len.is_multiple_of(SECTOR_SIZE) {
+            return Err(make_error!(Code::InvalidFormat));
+        }
+        if sector + (len / SECTOR_SIZE) as u64 > self.capacity {
+            return Err(make_error!(Code::IndexOutOfRange));
+        }
+
+        *self.header = BlkRequestHeader {
+            ty,
+            reserved: 0,
+            sector,
+        };
+        *self.status = 0xff;
+
+        // ヘッダ、データ、ステータスの 3 つのディスクリプタをつなげて 1 つのリクエストとする
+        let data_flags = if ty == VIRTIO_BLK_T_IN {
+            VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE
+        } else {
+            VIRTQ_DESC_F_NEXT
+        };
+        let descs = [
+            VirtqDesc {
+                addr: &*self.header as *const _ as u64,
+                len: mem::size_of::<BlkRequestHeader>() as _,
+                flags: VIRTQ_DESC_F_NEXT,
+                next: 1,
+            },
+            VirtqDesc {
+                addr: buf as u64,
+                len: len as _,
+                flags: data_flags,
+                next: 2,
+            },
+            VirtqDesc {
+                addr: &*self.status as *const _ as u64,
+                len: 1,
+                flags: VIRTQ_DESC_F_WRITE,
+                next: 0,
+            },
+        ];
+
+        unsafe {
+            for (i, desc) in descs.into_iter().enumerate() {
+                ptr::write_volatile(self.desc.add(i), desc);
+            }
+
+            let avail_idx = ptr::read_volatile(self.avail.add(1));
+            let ring_index = 2 + (avail_idx % self.queue_size) as usize;
+            ptr::write_volatile(self.avail.add(ring_index), 0);
+            fence(Ordering::SeqCst);
+            ptr::write_volatile(self.avail.add(1), avail_idx.wrapping_add(1));
+            fence(Ordering::SeqCst);
+        }
+        io_out_16(self.io_base + REG_QUEUE_NOTIFY, 0);
+
+        let mut deadline = Deadline::after_milli_seconds(REQUEST_TIMEOUT_MSEC);
+        while unsafe { ptr::read_volatile(self.used.add(1)) } == self.last_used_idx {
+            if deadline.expired() {
+                log!(LogLevel::Error, "virtio-blk: request {} timed out", ty);
+                // 後からデバイスが `buf` に書き込まないように、リセットして止める
+                io_out_8(self.io_base + REG_DEVICE_STATUS, 0);
+                self.broken = true;
+                return Err(make_error!(Code::TransferFailed));
+            }
+            spin_loop();
+        }
+        fence(Ordering::SeqCst);
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+
+        match unsafe { ptr::read_volatile(&*self.status) } {
+            0 => Ok(()),
+            _ => Err(make_error!(Code::TransferFailed)),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        "virtio-blk"
+    }
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        self.request(VIRTIO_BLK_T_IN, sector, buf.as_mut_ptr(), buf.len())
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<()> {
+        self.request(VIRTIO_BLK_T_OUT, sector, buf.as_ptr() as _, buf.len())
+    }
+}
@@ -4,6 +4,7 @@
 mod chars;
 mod elf;
 mod graphics;
+mod volume;
 
 use crate::chars::*;
 use crate::elf::Elf64Ehdr;
@@ -24,6 +25,7 @@ use uefi::{
     prelude::*,
     proto::{
         console::gop::{GraphicsOutput, PixelFormat},
+        device_path::{DevicePath, DevicePathNodeEnum},
         loaded_image::LoadedImage,
         media::{
             block::BlockIO,
@@ -40,6 +42,7 @@ use uefi::{
     },
     CStr16, Error, Guid, Result,
 };
+use volume::BootVolume;
 
 /// メモリマップを渡されたファイルに保存する。
 fn save_memory_map(
@@ -302,12 +305,10 @@ fn read_file(
     Ok(buffer)
 }
 
-fn open_block_io_protocol_for_loaded_image(
-    system_table: &SystemTable<Boot>,
-    image_handle: Handle,
-) -> Result<ScopedProtocol<'_, BlockIO>> {
-    unsafe {
-        let loaded_image = match system_table
+/// このローダーが読み込まれたデバイス（パーティション）のハンドルを返す。
+fn loaded_image_device(system_table: &SystemTable<Boot>, image_handle: Handle) -> Result<Handle> {
+    let loaded_image = unsafe {
+        system_table
             .boot_services()
             .open_protocol::<LoadedImage>(
                 OpenProtocolParams {
@@ -318,14 +319,19 @@ fn open_block_io_protocol_for_loaded_image(
                 OpenProtocolAttributes::GetProtocol,
             )?
             .device()
-        {
-            Some(image) => image,
-            None => return Err(uefi::Error::new(Status::ABORTED, ())),
-        };
+    };
+    loaded_image.ok_or_else(|| uefi::Error::new(Status::ABORTED, ()))
+}
 
+fn open_block_io_protocol_for_loaded_image(
+    system_table: &SystemTable<Boot>,
+    image_handle: Handle,
+) -> Result<ScopedProtocol<'_, BlockIO>> {
+    let device = loaded_image_device(system_table, image_handle)?;
+    unsafe {
         system_table.boot_services().open_protocol::<BlockIO>(
             OpenProtocolParams {
-                handle: loaded_image,
+                handle: device,
                 agent: image_handle,
                 controller: None,
             },
@@ -334,23 +340,110 @@ fn open_block_io_protocol_for_loaded_image(
     }
 }
 
-fn read_block(
+/// このローダーが読み込まれたパーティションの、ディスク上の開始ブロックを返す。
+/// パーティションを持たないディスクの場合は 0 を返す。
+fn partition_start(system_table: &SystemTable<Boot>, image_handle: Handle) -> Result<u64> {
+    let device = loaded_image_device(system_table, image_handle)?;
+    let device_path = unsafe {
+        system_table.boot_services().open_protocol::<DevicePath>(
+            OpenProtocolParams {
+                handle: device,
+                agent: image_handle,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )?
+    };
+    let start = device_path
+        .node_iter()
+        .find_map(|node| match node.as_enum() {
+            Ok(DevicePathNodeEnum::MediaHardDrive(hd)) => Some(hd.partition_start()),
+            _ => None,
+        })
+        .unwrap_or(0);
+    Ok(start)
+}
+
+/// このローダーが USB 接続のデバイスから読み込まれたかどうかを返す。
+fn booted_from_usb(system_table: &SystemTable<Boot>, image_handle: Handle) -> Result<bool> {
+    let device = loaded_image_device(system_table, image_handle)?;
+    let device_path = unsafe {
+        system_table.boot_services().open_protocol::<DevicePath>(
+            OpenProtocolParams {
+                handle: device,
+                agent: image_handle,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )?
+    };
+    let is_usb = device_path.node_iter().any(|node| {
+        matches!(
+            node.as_enum(),
+            Ok(DevicePathNodeEnum::MessagingUsb(_)
+                | DevicePathNodeEnum::MessagingUsbWwid(_)
+                | DevicePathNodeEnum::MessagingUsbClass(_))
+        )
+    });
+    Ok(is_usb)
+}
+
+/// このローダーが置かれているボリュームを、先頭から最大 32 MiB までメモリに読み込む。
+fn read_boot_volume_image(
     system_table: &SystemTable<Boot>,
-    block_io: &mut BlockIO,
-    media_id: u32,
-    read_bytes: u64,
+    image_handle: Handle,
 ) -> Result<&'static mut [u8]> {
-    let read_bytes = read_bytes as usize;
+    let block_io = open_block_io_protocol_for_loaded_image(system_table, image_handle)?;
+    let media = block_io.media();
+    let media_id = media.media_id();
+    let volume_bytes = media.block_size() as u64 * (media.last_block() + 1);
+    let volume_bytes = volume_bytes.min(32 * 1024 * 1024) as usize;
+    info!(
+        "Reading {} bytes (BlockSize {}, LastBlock {})",
+        volume_bytes,
+        media.block_size(),
+        media.last_block(),
+    );
 
     let buffer_addr = system_table
         .boot_services()
-        .allocate_pool(MemoryType::LOADER_DATA, read_bytes)?;
-
-    let buffer = unsafe { slice::from_raw_parts_mut(buffer_addr, read_bytes) };
+        .allocate_pool(MemoryType::LOADER_DATA, volume_bytes)?;
+    let buffer = unsafe { slice::from_raw_parts_mut(buffer_addr, volume_bytes) };
     block_io.read_blocks(media_id, 0, buffer)?;
     Ok(buffer)
 }
 
+/// ディスク上にあるブートボリュームの情報を集める。
+/// ボリュームの中身はカーネルが必要になったときに読み込むので、ここでは先頭セクタだけを読む。
+fn read_boot_volume_info(
+    system_table: &SystemTable<Boot>,
+    image_handle: Handle,
+) -> Result<BootVolume> {
+    let block_io = open_block_io_protocol_for_loaded_image(system_table, image_handle)?;
+    let media = block_io.media();
+    let media_id = media.media_id();
+    let block_size = media.block_size();
+
+    let buffer_addr = system_table
+        .boot_services()
+        .allocate_pool(MemoryType::LOADER_DATA, block_size as usize)?;
+    let buffer = unsafe { slice::from_raw_parts_mut(buffer_addr, block_size as usize) };
+    block_io.read_blocks(media_id, 0, buffer)?;
+
+    let mut boot_sector = [0; 512];
+    boot_sector.copy_from_slice(&buffer[..512]);
+    unsafe { system_table.boot_services().free_pool(buffer_addr)? };
+
+    Ok(BootVolume {
+        image: core::ptr::null_mut(),
+        image_size: 0,
+        media_id,
+        block_size,
+        lba_offset: partition_start(system_table, image_handle)?,
+        boot_sector,
+    })
+}
+
 #[entry]
 fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     // 恐らく log を使えるようにしているのではないか
@@ -535,59 +628,54 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
         }
     }
 
-    // "\fat_disk" もしくは外部接続の IO デバイスを読み込む
-    let volume_image = match root_dir.open(
+    // "\fat_disk" があればそれをボリュームとしてメモリに読み込み、
+    // なければこのローダーが置かれているボリュームの情報だけをカーネルに渡す
+    let boot_volume = match root_dir.open(
         cstr16!("\\fat_disk"),
         FileMode::Read,
         FileAttribute::empty(),
     ) {
         Ok(file) => match read_file(&mut system_table, file) {
-            Ok(buf) => buf,
+            Ok(buf) => match BootVolume::from_image(buf) {
+                Some(volume) => volume,
+                None => {
+                    error!("volume file is too small");
+                    halt();
+                }
+            },
             Err(e) => {
                 error!("failed to read volume file: {}", e);
                 halt();
             }
         },
-        Err(_) => {
-            // "\fat_disk" が見つからなかった場合
-            let block_io =
-                match open_block_io_protocol_for_loaded_image(&system_table, image_handle) {
-                    Ok(prot) => prot,
-                    Err(e) => {
-                        error!("failed to read blocks: {}", e);
-                        halt();
-                    }
-                };
-
-            let media = block_io.media();
-            let volume_bytes = media.block_size() as u64 * (media.last_block() + 1);
-            let volume_bytes = if volume_bytes > 32 * 1024 * 1024 {
-                32 * 1024 * 1024
-            } else {
-                volume_bytes
-            };
-
-            info!(
-                "Reading {} bytes (Present {}, BockSize {}, LastBlock {})",
-                volume_bytes,
-                media.is_media_present(),
-                media.block_size(),
-                media.last_block(),
-            );
-
-            match read_block(
-                &system_table,
-                block_io.get_mut().unwrap(),
-                media.media_id(),
-                volume_bytes,
-            ) {
-                Ok(buf) => buf,
+        // カーネルには USB のマスストレージのドライバがないので、
+        // USB メモリから起動した場合はボリュームをメモリに読み込んで渡す
+        Err(_) if matches!(booted_from_usb(&system_table, image_handle), Ok(true)) => {
+            match read_boot_volume_image(&system_table, image_handle).map(BootVolume::from_image) {
+                Ok(Some(volume)) => volume,
+                Ok(None) => {
+                    error!("boot volume is too small");
+                    halt();
+                }
                 Err(e) => {
                     error!("failed to read blocks: {}", e);
                     halt();
                 }
             }
         }
+        Err(_) => match read_boot_volume_info(&system_table, image_handle) {
+            Ok(volume) => {
+                info!(
+                    "Boot volume: MediaId {}, BlockSize {}, LBA offset {}",
+                    volume.media_id, volume.block_size, volume.lba_offset
+                );
+                volume
+            }
+            Err(e) => {
+                error!("failed to get the boot volume: {}", e);
+                halt();
+            }
+        },
     };
 
     // UEFI のブートサービスを終了する
@@ -640,7 +728,7 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
         usize,
         usize,
         *const c_void,
-        *mut c_void,
+        &BootVolume,
     ) = unsafe { transmute(kernel_ehdr.entry) };
     entry_point(
         &config,
@@ -648,7 +736,7 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
         kernel_first_addr,
         kernel_last_addr - kernel_first_addr,
         acpi_table,
-        volume_image.as_mut_ptr() as *mut c_void,
+        &boot_volume,
     );
 
     halt()
@@ -0,0 +1,34 @@
+/// カーネルに渡すブートボリュームの情報。
+/// カーネル側の `fat::BootVolume` と同じレイアウトにすること。
+#[repr(C)]
+pub struct BootVolume {
+    /// メモリに読み込んだボリュームイメージ。ディスク上のボリュームを使う場合は null
+    pub image: *mut u8,
+    /// `image` のバイト数
+    pub image_size: u64,
+    /// ボリュームがある BlockIO のメディア ID
+    pub media_id: u32,
+    /// BlockIO のブロックサイズ
+    pub block_size: u32,
+    /// ディスクの先頭からボリュームの先頭までのブロック数
+    pub lba_offset: u64,
+    /// ボリュームの先頭セクタ。カーネルがボリュームのあるディスクを特定するのに使う
+    pub boot_sector: [u8; 512],
+}
+
+impl BootVolume {
+    /// メモリに読み込んだボリュームイメージ `image` を渡す。
+    /// 先頭セクタ分の長さがない場合は `None` を返す。
+    pub fn from_image(image: &'static mut [u8]) -> Option<Self> {
+        let mut boot_sector = [0; 512];
+        boot_sector.copy_from_slice(image.get(..512)?);
+        Some(Self {
+            image: image.as_mut_ptr(),
+            image_size: image.len() as u64,
+            media_id: 0,
+            block_size: 512,
+            lba_offset: 0,
+            boot_sector,
+        })
+    }
+}